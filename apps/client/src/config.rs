@@ -0,0 +1,297 @@
+//! Client settings, loaded from a TOML file given with `--config <path>`.
+//!
+//! ```toml
+//! servers = ["sg1.example.com", "jp1.example.com:4433", "[2001:db8::7]:4433"]
+//! server_name = "vpn.example.com"
+//! psk_file = "/etc/xeonvpn/psk"
+//! token_file = "/var/lib/xeonvpn/token"
+//! cert_file = "/etc/xeonvpn/client.pem"
+//! key_file = "/etc/xeonvpn/client.key"
+//! client_id = "alice-laptop"
+//!
+//! [trust]
+//! ca_file = "/etc/xeonvpn/ca.pem"
+//! pins = ["sha256:3b0c...e1"]
+//! tofu = true
+//!
+//! [transport]
+//! congestion = "bbr"
+//! initial_rtt_ms = 150
+//! ```
+//!
+//! Without a `client_id` the client generates one on first run and keeps it
+//! in `client_id_file`, which defaults to `client_id` next to the config
+//! file.
+
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use tracing::info;
+use xeonvpn_quic::endpoint::{ServerAddr, DEFAULT_PORT};
+use xeonvpn_quic::transport::TransportSettings;
+
+/// File a generated client id is kept in, next to the config file.
+const CLIENT_ID_FILE: &str = "client_id";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// Servers to connect to, as `host[:port]`. Every address of every
+    /// server is raced and the first to answer is used.
+    pub servers: Vec<String>,
+    /// Name checked against the server certificate and sent as SNI.
+    /// Defaults to the host of the first server.
+    pub server_name: Option<String>,
+    /// Pre-shared key for servers that require PSK authentication.
+    pub psk: Option<String>,
+    /// File holding the pre-shared key; takes precedence over `psk`.
+    pub psk_file: Option<PathBuf>,
+    /// Signed session token from the provisioning backend; sent instead of
+    /// the pre-shared key when both are set.
+    pub token: Option<String>,
+    /// File holding the token, read on every connect so the backend can
+    /// refresh it; takes precedence over `token`.
+    pub token_file: Option<PathBuf>,
+    /// Client certificate chain (PEM or DER) for servers requiring mutual TLS.
+    pub cert_file: Option<PathBuf>,
+    /// Private key for `cert_file`.
+    pub key_file: Option<PathBuf>,
+    /// Stable identity sent in the session hello, so the server hands out
+    /// the same tunnel address on every reconnect. Generated when unset.
+    pub client_id: Option<String>,
+    /// Where a generated client id is kept; defaults to `client_id` next to
+    /// the config file, or next to the known-servers file without one.
+    pub client_id_file: Option<PathBuf>,
+    pub trust: TrustSettings,
+    /// QUIC transport tuning.
+    pub transport: TransportSettings,
+    /// Directory of the config file these settings were loaded from.
+    #[serde(skip)]
+    config_dir: Option<PathBuf>,
+}
+
+/// How the server certificate is verified; see the `trust` module. Every
+/// configured check must pass.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrustSettings {
+    /// CA certificates (PEM or DER) the server chain must verify against.
+    pub ca_file: Option<PathBuf>,
+    /// Also accept chains issued by the operating system's trusted CAs.
+    pub system_roots: bool,
+    /// Accepted SHA-256 hashes of the server's public key, as
+    /// `sha256:<hex>`. List the next key alongside the current one to rotate.
+    pub pins: Vec<String>,
+    /// Record the server key on first connect and refuse a different one
+    /// later.
+    pub tofu: bool,
+    /// Where trust-on-first-use keys are kept; defaults to `known_servers`.
+    pub known_servers_file: Option<PathBuf>,
+}
+
+impl ClientSettings {
+    /// Load settings from `--config <path>`, or defaults when not given.
+    /// `--server <host[:port]>` (repeatable), `--server-name <name>` and
+    /// `--client-id <id>` override the file.
+    pub fn from_args(args: &[String]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut settings = match args.iter().position(|a| a == "--config") {
+            Some(i) => {
+                let path = args.get(i + 1).ok_or("--config requires a path")?;
+                Self::load(Path::new(path))?
+            }
+            None => Self::default(),
+        };
+        let mut servers = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            match arg.as_str() {
+                "--server" => {
+                    servers.push(args.get(i + 1).ok_or("--server requires a host")?.clone())
+                }
+                "--server-name" => {
+                    settings.server_name = Some(
+                        args.get(i + 1)
+                            .ok_or("--server-name requires a name")?
+                            .clone(),
+                    );
+                }
+                "--client-id" => {
+                    settings.client_id = Some(
+                        args.get(i + 1)
+                            .ok_or("--client-id requires a value")?
+                            .clone(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        if !servers.is_empty() {
+            settings.servers = servers;
+        }
+        Ok(settings)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let text = std::fs::read_to_string(path)?;
+        let mut settings: Self = toml::from_str(&text)?;
+        settings.config_dir = path.parent().map(Path::to_path_buf);
+        Ok(settings)
+    }
+
+    /// Parsed server list. Without any configured server the client targets
+    /// a local test server.
+    pub fn servers(&self) -> Result<Vec<ServerAddr>, Box<dyn std::error::Error + Send + Sync>> {
+        if self.servers.is_empty() {
+            return Ok(vec![ServerAddr {
+                host: "127.0.0.1".into(),
+                port: DEFAULT_PORT,
+            }]);
+        }
+        self.servers.iter().map(|s| s.parse()).collect()
+    }
+
+    /// TLS server name for `servers`.
+    pub fn server_name(&self, servers: &[ServerAddr]) -> String {
+        match (&self.server_name, servers.first()) {
+            (Some(name), _) => name.clone(),
+            // The local test server's ephemeral certificate only names localhost
+            (None, _) if self.servers.is_empty() => "localhost".into(),
+            (None, Some(server)) => server.host.clone(),
+            (None, None) => "localhost".into(),
+        }
+    }
+
+    /// The configured pre-shared key, if any.
+    pub fn load_psk(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let raw = match (&self.psk_file, &self.psk) {
+            (Some(path), _) => std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read PSK file {}: {e}", path.display()))?,
+            (None, Some(psk)) => psk.clone(),
+            (None, None) => return Ok(None),
+        };
+        xeonvpn_quic::auth::parse_psk(&raw).map(Some)
+    }
+
+    /// The configured session token, if any.
+    pub fn load_token(&self) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let token = match (&self.token_file, &self.token) {
+            (Some(path), _) => std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read token file {}: {e}", path.display()))?,
+            (None, Some(token)) => token.clone(),
+            (None, None) => return Ok(None),
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            return Err("session token is empty".into());
+        }
+        Ok(Some(token.to_string()))
+    }
+    /// Where a generated client id is kept.
+    pub fn client_id_path(&self) -> PathBuf {
+        if let Some(path) = &self.client_id_file {
+            return path.clone();
+        }
+        let dir = match &self.config_dir {
+            Some(dir) => dir.as_path(),
+            None => self
+                .trust
+                .known_servers_file
+                .as_deref()
+                .and_then(Path::parent)
+                .unwrap_or(Path::new("")),
+        };
+        dir.join(CLIENT_ID_FILE)
+    }
+
+    /// The configured client id, else the one generated on an earlier run,
+    /// else a new one saved to [`ClientSettings::client_id_path`].
+    pub fn load_client_id(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(id) = &self.client_id {
+            let id = id.trim();
+            if id.is_empty() {
+                return Err("client_id is empty".into());
+            }
+            return Ok(id.to_string());
+        }
+        let path = self.client_id_path();
+        match std::fs::read_to_string(&path) {
+            Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_string()),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(format!("failed to read client id file {}: {e}", path.display()).into())
+            }
+        }
+        let host = std::fs::read_to_string("/etc/hostname").unwrap_or_default();
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let id = format!("{}-{:016x}", host.trim(), hasher.finish());
+        std::fs::write(&path, &id)
+            .map_err(|e| format!("failed to save client id to {}: {e}", path.display()))?;
+        info!("generated client id {id}, saved to {}", path.display());
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "xeonvpn-client-config-test-{}-{name}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn generated_client_id_is_kept_next_to_the_config() {
+        let dir = temp_dir("generated");
+        let config = dir.join("client.toml");
+        std::fs::write(&config, "servers = [\"vpn.example.com\"]\n").unwrap();
+        let settings = ClientSettings::load(&config).unwrap();
+        assert_eq!(settings.client_id_path(), dir.join("client_id"));
+        let id = settings.load_client_id().unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("client_id")).unwrap(), id);
+        // Reused on the next run
+        let settings = ClientSettings::load(&config).unwrap();
+        assert_eq!(settings.load_client_id().unwrap(), id);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn configured_client_id_wins() {
+        let dir = temp_dir("configured");
+        let config = dir.join("client.toml");
+        std::fs::write(&config, "client_id = \"alice-laptop\"\n").unwrap();
+        let args = |extra: &[&str]| {
+            ["xeonvpn-client", "--config", config.to_str().unwrap()]
+                .iter()
+                .chain(extra)
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+        };
+        let settings = ClientSettings::from_args(&args(&[])).unwrap();
+        assert_eq!(settings.load_client_id().unwrap(), "alice-laptop");
+        let settings = ClientSettings::from_args(&args(&["--client-id", "bob"])).unwrap();
+        assert_eq!(settings.load_client_id().unwrap(), "bob");
+        // Nothing is generated or written
+        assert!(!dir.join("client_id").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_id_file_defaults() {
+        let mut settings = ClientSettings::default();
+        assert_eq!(settings.client_id_path(), Path::new("client_id"));
+        settings.trust.known_servers_file = Some("/var/lib/xeonvpn/known_servers".into());
+        assert_eq!(
+            settings.client_id_path(),
+            Path::new("/var/lib/xeonvpn/client_id")
+        );
+        settings.client_id_file = Some("/run/xeonvpn/id".into());
+        assert_eq!(settings.client_id_path(), Path::new("/run/xeonvpn/id"));
+    }
+}
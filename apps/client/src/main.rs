@@ -1,27 +1,148 @@
-use std::{fs, sync::Arc};
+mod config;
+mod trust;
+
+use config::ClientSettings;
+use ipnet::IpNet;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 use tracing::info;
 use tracing_subscriber::{fmt, EnvFilter};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use trust::ServerTrust;
+use xeonvpn_core::batch;
+use xeonvpn_core::buffer::PacketPool;
+use xeonvpn_core::close::{CloseCode, SessionClose};
+use xeonvpn_core::compress::Decompressor;
+use xeonvpn_core::control::{Capabilities, ClientHello, Credentials};
+use xeonvpn_core::frame::PROTOCOL_VERSION;
+use xeonvpn_core::frame::{Frame, FrameRead, FrameWrite};
+#[cfg(target_os = "linux")]
+use xeonvpn_net::{vnet::VNET_HDR_LEN, TunOptions};
+use xeonvpn_quic::auth::psk_proof;
+use xeonvpn_quic::close::{close_connection, connection_closed, frame_closed, write_closed};
+use xeonvpn_quic::endpoint;
+use xeonvpn_quic::handshake::{client_handshake, serve_control, HandshakeError};
+use xeonvpn_quic::pmtu;
+use xeonvpn_quic::tls::{load_certs, load_private_key};
+use xeonvpn_quic::tunnel::{DataPlane, DatagramRecvError, StreamBatcher};
+
+/// Name of the client TUN device.
+const TUN_NAME: &str = "xeonvpn0";
+
+/// Largest packet read from the client TUN.
+const TUN_READ_LEN: usize = 2000;
 
 fn build_client_config(
-    cert_der: &[u8],
+    settings: &ClientSettings,
 ) -> Result<quinn::ClientConfig, Box<dyn std::error::Error + Send + Sync>> {
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&rustls::Certificate(cert_der.to_vec()))?;
-
-    let mut crypto = rustls::ClientConfig::builder()
+    let trust = ServerTrust::from_settings(&settings.trust)?;
+    let builder = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(Arc::new(trust));
+    // Present a client certificate to servers that require mutual TLS
+    let mut crypto = match (&settings.cert_file, &settings.key_file) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("cert_file and key_file must be set together".into()),
+    };
 
     crypto.alpn_protocols = vec![b"hq-29".to_vec(), b"h3".to_vec()];
 
-    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(settings.transport.transport_config()?));
+    Ok(config)
+}
+
+/// Connect to the configured servers, racing their addresses.
+async fn connect(
+    settings: &ClientSettings,
+) -> Result<quinn::Connection, Box<dyn std::error::Error + Send + Sync>> {
+    let servers = settings.servers()?;
+    let server_name = settings.server_name(&servers);
+    let addrs = endpoint::resolve(&servers).await;
+    if addrs.is_empty() {
+        return Err("none of the configured servers resolved to an address".into());
+    }
+    let mut endpoint = endpoint::client_endpoint(settings.transport.endpoint_config()?)?;
+    endpoint.set_default_client_config(build_client_config(settings)?);
+    endpoint::connect_any(&endpoint, &addrs, &server_name).await
+}
+
+/// Write packets the server sends as QUIC datagrams into the client TUN.
+#[cfg(target_os = "linux")]
+async fn datagram_downlink(plane: DataPlane, dev: xeonvpn_net::TunWriter) {
+    let mut decompressor = Decompressor::new();
+    loop {
+        match plane.recv_frame().await {
+            Ok(Frame::Data(payload)) => {
+                if let Err(e) = dev.send(&payload).await {
+                    eprintln!("[client] write to TUN failed: {e}");
+                    break;
+                }
+            }
+            Ok(Frame::Offload(payload)) => {
+                if let Err(e) = dev.send_offload(&payload).await {
+                    eprintln!("[client] write to TUN failed: {e}");
+                    break;
+                }
+            }
+            Ok(Frame::Compressed(payload)) => match plane.decompress(&mut decompressor, &payload) {
+                Ok(pkt) => {
+                    if let Err(e) = dev.send(&pkt).await {
+                        eprintln!("[client] write to TUN failed: {e}");
+                        break;
+                    }
+                }
+                Err(e) => eprintln!("[client] dropping compressed datagram: {e}"),
+            },
+            Ok(other) => eprintln!("[client] ignoring non-data datagram frame: {other:?}"),
+            Err(DatagramRecvError::Protocol(e)) => eprintln!("[client] dropping datagram: {e}"),
+            Err(DatagramRecvError::Connection(e)) => {
+                eprintln!("[client] datagram receive ended: {e}");
+                break;
+            }
+        }
+    }
+}
+
+/// Report a failed write to the client TUN and close the session over it.
+#[cfg(target_os = "linux")]
+fn tun_write_failed(e: std::io::Error) -> SessionClose {
+    eprintln!("[client] write to TUN failed: {e}");
+    SessionClose::new(CloseCode::Internal, "client failed to deliver a packet")
 }
 
+/// Exit status when the session ended in a way that reconnecting may fix
+/// (`EX_TEMPFAIL`); other failures exit with 1.
+const EXIT_RECONNECT: u8 = 75;
+
+/// How long to wait for the server's close once the tunnel stream ends.
+const CLOSE_WAIT: Duration = Duration::from_secs(1);
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn main() -> ExitCode {
+    let Err(e) = run().await else {
+        return ExitCode::SUCCESS;
+    };
+    eprintln!("Error: {e}");
+    let close = match e.downcast_ref::<HandshakeError>() {
+        Some(e) => {
+            let close = e.session_close();
+            eprintln!("[client] session closed: {close}");
+            Some(close)
+        }
+        None => e.downcast_ref::<SessionClose>().cloned(),
+    };
+    match close {
+        Some(close) if close.should_reconnect() => ExitCode::from(EXIT_RECONNECT),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let _ = if std::env::var("RUST_LOG").is_err() {
         fmt().with_env_filter(EnvFilter::new("info")).try_init()
     } else {
@@ -34,46 +155,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // Optional: Linux TUN POC mode
     let args: Vec<String> = std::env::args().collect();
+    let settings = ClientSettings::from_args(&args)?;
     if args.iter().any(|a| a == "--tun") {
         #[cfg(target_os = "linux")]
         {
-            // Load server cert
-            let cert_der =
-                fs::read("server_cert.der").or_else(|_| fs::read("../server_cert.der"))?;
-            let client_config = build_client_config(&cert_der)?;
-
-            // Create endpoint and connect
-            let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse()?)?;
-            endpoint.set_default_client_config(client_config);
-            let connect = endpoint.connect("127.0.0.1:4433".parse()?, "localhost")?;
-            let connection = connect.await?;
+            let connection = connect(&settings).await?;
             let addr = connection.remote_address();
             info!("connected: {addr}");
             println!("[client] connected to {addr}");
 
-            // Read one packet from TUN and send over QUIC with a simple header
+            // Read one packet from TUN and send it over QUIC as a data frame
             let pkt = xeonvpn_net::read_one_packet().await?;
-            let mut header = Vec::with_capacity(4 + 4 + pkt.len());
-            header.extend_from_slice(b"TUN ");
-            header.extend_from_slice(&(pkt.len() as u32).to_be_bytes());
-            header.extend_from_slice(&pkt);
-
+            let sent = pkt.len();
             let (mut send, mut recv) = connection.open_bi().await?;
-            send.write_all(&header).await?;
+            send.write_frame(&Frame::Data(pkt.into())).await?;
             send.finish().await?;
 
-            // Expect echo; verify header and length, then print sizes
+            // Expect echo; decode the frame, then print sizes
             let resp = recv.read_to_end(256 * 1024).await?;
-            if resp.len() >= 8 && &resp[0..4] == b"TUN " {
-                let len = u32::from_be_bytes([resp[4], resp[5], resp[6], resp[7]]) as usize;
-                println!(
-                    "[client] TUN sent {} bytes, echoed header len {}, payload echo {} bytes",
-                    pkt.len(),
-                    len,
-                    resp.len().saturating_sub(8)
-                );
-            } else {
-                println!("[client] unexpected echo size {} bytes", resp.len());
+            match Frame::decode(&resp) {
+                Ok(Some((Frame::Data(echo), _))) => println!(
+                    "[client] TUN sent {} bytes, payload echo {} bytes",
+                    sent,
+                    echo.len()
+                ),
+                Ok(_) => println!("[client] unexpected echo of {} bytes", resp.len()),
+                Err(e) => println!("[client] invalid echo frame: {e}"),
             }
             return Ok(());
         }
@@ -88,84 +195,314 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if args.iter().any(|a| a == "--tun-loop") {
         #[cfg(target_os = "linux")]
         {
-            // Load server cert
-            let cert_der = fs::read("server_cert.der").or_else(|_| fs::read("../server_cert.der"))?;
-            let client_config = build_client_config(&cert_der)?;
-
-            // Create endpoint and connect
-            let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse()?)?;
-            endpoint.set_default_client_config(client_config);
-            let connect = endpoint.connect("127.0.0.1:4433".parse()?, "localhost")?;
-            let connection = connect.await?;
+            let connection = connect(&settings).await?;
             let addr = connection.remote_address();
             info!("connected: {addr}");
             println!("[client] connected to {addr}");
 
-            // Keep TUN open during the whole loop (shared)
-            let dev = Arc::new(Mutex::new(xeonvpn_net::open_tun().await?));
-            // Open one long-lived bidirectional stream
+            // Session handshake on the control stream. `--datagrams` asks the server
+            // to carry packets in QUIC DATAGRAM frames; packets still use the stream
+            // whenever a datagram cannot be sent. `--offload` opens the TUN with
+            // segmentation offloads and exchanges unsegmented TCP packets with a
+            // server that does too.
+            let plane = DataPlane::new(connection.clone());
+            let want_datagrams = args.iter().any(|a| a == "--datagrams");
+            let offload = args.iter().any(|a| a == "--offload");
+            if want_datagrams && !plane.datagrams_available() {
+                eprintln!("[client] path cannot carry datagrams, using the stream");
+            }
+            let hello = ClientHello {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities {
+                    datagrams: want_datagrams && plane.datagrams_available(),
+                    offload,
+                    batching: true,
+                    fragments: true,
+                    probes: true,
+                },
+                client_id: Some(settings.load_client_id()?),
+                credentials: match (settings.load_token()?, settings.load_psk()?) {
+                    (Some(token), _) => Some(Credentials::Token { token }),
+                    (None, Some(psk)) => Some(Credentials::Psk {
+                        proof: psk_proof(&connection, &psk)?,
+                    }),
+                    (None, None) => None,
+                },
+                compression: settings.transport.compression.clone(),
+            };
+            let (params, ctrl) = client_handshake(&connection, hello).await?;
+            let session_id = params.session_id;
+            info!("session {session_id} established: {params:?}");
+
+            // Configure the TUN from the session parameters and keep it open
+            // during the whole loop; the uplink owns the reader and the
+            // downlinks share the writer
+            let tun_addr = params
+                .addresses
+                .iter()
+                .find_map(|a| match a {
+                    IpNet::V4(v4) => Some(*v4),
+                    IpNet::V6(_) => None,
+                })
+                .ok_or("server did not assign an IPv4 tunnel address")?;
+            // With `auto_mtu` the TUN starts at what fits in one datagram on
+            // the path so far and follows path MTU discovery from there
+            let mut path_mtu = settings
+                .transport
+                .auto_mtu
+                .then(|| pmtu::watch_path_mtu(plane.clone(), params.mtu));
+            let mtu = match &mut path_mtu {
+                Some(path_mtu) => *path_mtu.borrow_and_update(),
+                None => params.mtu,
+            };
+            // Without negotiated offloads the TUN only ever carries MTU-sized
+            // packets, and reads need no room for a segment batch
+            let options = TunOptions {
+                queues: 1,
+                offload: params.capabilities.offload,
+            };
+            let (mut tun_reader, dev) =
+                xeonvpn_net::open_tun_with(TUN_NAME, tun_addr, mtu, &options)
+                    .await?
+                    .remove(0)
+                    .split();
+            println!("[client] tunnel address {tun_addr}, mtu {mtu}");
+            plane.set_mtu(mtu);
+            if settings.transport.clamp_mss {
+                plane.enable_mss_clamp();
+            }
+            if let Some(mut path_mtu) = path_mtu {
+                let plane = plane.clone();
+                tokio::spawn(async move {
+                    while path_mtu.changed().await.is_ok() {
+                        let mtu = *path_mtu.borrow_and_update();
+                        plane.set_mtu(mtu);
+                        match xeonvpn_net::set_link_mtu(TUN_NAME, mtu).await {
+                            Ok(()) => info!("tunnel MTU {mtu}"),
+                            Err(e) => eprintln!("[client] cannot set tunnel MTU {mtu}: {e}"),
+                        }
+                    }
+                });
+            }
+            xeonvpn_net::apply_routes(TUN_NAME, &params.routes).await?;
+            xeonvpn_net::apply_dns(TUN_NAME, &params.dns).await;
+            let control = tokio::spawn(serve_control(ctrl));
+
+            // Open one long-lived bidirectional data stream. The server only
+            // sees a stream once something is written to it, and with
+            // datagrams no packet may ever need the stream, so announce it
+            // with a ping right away
             let (mut send, mut recv) = connection.open_bi().await?;
+            send.write_frame(&Frame::Ping(0)).await?;
+            // Frames the downlink must answer (pongs) are sent by the uplink task
+            let (reply_tx, mut reply_rx) = mpsc::channel::<Frame>(16);
+            if params.capabilities.offload {
+                info!("offloads enabled");
+                plane.enable_offload();
+            }
+            if let Some(algorithm) = params.compression {
+                let transport = &settings.transport;
+                plane.enable_compression(
+                    algorithm,
+                    transport.compression_level,
+                    transport.compress_min_size,
+                )?;
+                info!("{algorithm} compression enabled");
+            }
+            let datagrams = params.capabilities.datagrams && plane.enable_datagrams();
+            if datagrams {
+                info!("datagram data plane enabled");
+                plane.enable_fragments(&settings.transport, params.capabilities.fragments);
+                if plane.fragments_enabled() {
+                    info!("fragmentation enabled");
+                }
+                if params.capabilities.probes {
+                    plane.enable_probes();
+                }
+                tokio::spawn(datagram_downlink(plane.clone(), dev.clone()));
+            }
 
             // Uplink task: read from TUN and send to server
-            let dev_u = dev.clone();
+            let plane_u = plane.clone();
+            let conn_u = connection.clone();
+            let icmp_dev = dev.clone();
+            let vnet = tun_reader.offload();
+            let read_len = if vnet {
+                VNET_HDR_LEN + usize::from(u16::MAX)
+            } else {
+                TUN_READ_LEN
+            };
+            let mut batcher = StreamBatcher::new(&settings.transport, params.capabilities.batching);
             let uplink = tokio::spawn(async move {
-                loop {
-                    let mut buf = vec![0u8; 2000];
-                    let n = {
-                        let mut d = dev_u.lock().await;
-                        match d.read(&mut buf).await {
-                            Ok(n) => n,
+                let mut buffers = PacketPool::new(read_len);
+                let mut buf = buffers.take();
+                // Frames for the stream; a GSO packet may turn into several
+                let mut pending = Vec::new();
+                let close = loop {
+                    let read = tun_reader.recv(buf.space_mut());
+                    tokio::select! {
+                        biased;
+                        reply = reply_rx.recv() => match reply {
+                            Some(frame) => match frame.to_bytes() {
+                                Ok(wire) => pending.push(wire),
+                                Err(e) => {
+                                    eprintln!("[client] cannot encode frame: {e}");
+                                    continue;
+                                }
+                            },
+                            None => break None,
+                        },
+                        e = conn_u.closed() => break Some(connection_closed(&e)),
+                        res = read => match res {
+                            Ok(0) => continue,
+                            Ok(n) => {
+                                let mut pkt = std::mem::replace(&mut buf, buffers.take());
+                                pkt.truncate(n);
+                                match plane_u.send_tun_packet(pkt, vnet, &mut pending) {
+                                    Ok(None) => {}
+                                    Ok(Some(icmp)) => {
+                                        if let Err(e) = icmp_dev.send(&icmp).await {
+                                            eprintln!("[client] write to TUN failed: {e}");
+                                        }
+                                    }
+                                    Err(e) => {
+                                        eprintln!("[client] datagram send error: {e}");
+                                        break Some(connection_closed(&e));
+                                    }
+                                }
+                            }
                             Err(e) => {
                                 eprintln!("[client] TUN read error: {e}");
-                                break;
+                                break Some(SessionClose::new(CloseCode::Internal, "client TUN read failed"));
                             }
-                        }
-                    };
-                    if n == 0 {
+                        },
+                        () = batcher.deadline() => {}
+                    }
+                    if pending.is_empty() || batcher.hold(&pending) {
                         continue;
                     }
-                    let pkt = &buf[..n];
-                    let mut frame = Vec::with_capacity(8 + pkt.len());
-                    frame.extend_from_slice(b"TUN ");
-                    frame.extend_from_slice(&(pkt.len() as u32).to_be_bytes());
-                    frame.extend_from_slice(pkt);
-                    if let Err(e) = send.write_all(&frame).await {
+                    batcher.flush(&mut pending);
+                    if let Err(e) = send.write_all_chunks(&mut pending).await {
                         eprintln!("[client] QUIC send error: {e}");
-                        break;
+                        break write_closed(&e);
                     }
+                    pending.clear();
+                };
+                if batcher.enabled() {
+                    info!("uplink batching: {}", batcher.stats());
+                }
+                if let Some(algorithm) = params.compression {
+                    info!("{algorithm} compression: {}", plane_u.compression_stats());
                 }
+                if datagrams {
+                    info!("fragmentation: {}", plane_u.fragment_stats());
+                }
+                close
             });
 
             // Downlink task: read from server and write to TUN
-            let dev_d = dev.clone();
+            let plane_d = plane.clone();
             let downlink = tokio::spawn(async move {
-                loop {
-                    let mut hdr = [0u8; 8];
-                    if let Err(e) = recv.read_exact(&mut hdr).await {
-                        eprintln!("[client] QUIC recv header error: {e}");
-                        break;
-                    }
-                    if &hdr[0..4] != b"TUN " {
-                        eprintln!("[client] bad magic in reply");
-                        continue;
-                    }
-                    let len = u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]) as usize;
-                    let mut payload = vec![0u8; len.min(65535)];
-                    if let Err(e) = recv.read_exact(&mut payload).await {
-                        eprintln!("[client] QUIC recv payload error: {e}");
-                        break;
-                    }
-                    if let Err(e) = {
-                        let mut d = dev_d.lock().await;
-                        d.write_all(&payload).await
-                    } {
-                        eprintln!("[client] write to TUN failed: {e}");
-                        break;
+                let mut buffers = PacketPool::new(TUN_READ_LEN);
+                let mut decompressor = Decompressor::new();
+                'frames: loop {
+                    let frame = match recv.read_frame_in(&mut buffers).await {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => {
+                            eprintln!("[client] server closed the tunnel stream");
+                            break None;
+                        }
+                        Err(e) => {
+                            // A framing error leaves the stream misaligned; never resync by guessing
+                            eprintln!("[client] QUIC recv error: {e}");
+                            break frame_closed(&e);
+                        }
+                    };
+                    match frame {
+                        Frame::Data(payload) => {
+                            if let Err(e) = dev.send(&payload).await {
+                                break Some(tun_write_failed(e));
+                            }
+                        }
+                        Frame::Offload(payload) => {
+                            if let Err(e) = dev.send_offload(&payload).await {
+                                break Some(tun_write_failed(e));
+                            }
+                        }
+                        Frame::Batch(payload) => {
+                            for pkt in batch::unpack(payload) {
+                                let pkt = match pkt {
+                                    Ok(pkt) => pkt,
+                                    Err(e) => {
+                                        eprintln!("[client] dropping rest of batch: {e}");
+                                        break;
+                                    }
+                                };
+                                if let Err(e) = dev.send(&pkt).await {
+                                    break 'frames Some(tun_write_failed(e));
+                                }
+                            }
+                        }
+                        Frame::Compressed(payload) => {
+                            let pkt = match plane_d.decompress(&mut decompressor, &payload) {
+                                Ok(pkt) => pkt,
+                                Err(e) => {
+                                    eprintln!("[client] dropping compressed packet: {e}");
+                                    continue;
+                                }
+                            };
+                            if let Err(e) = dev.send(&pkt).await {
+                                break Some(tun_write_failed(e));
+                            }
+                        }
+                        Frame::Ping(nonce) => {
+                            if reply_tx.send(Frame::Pong(nonce)).await.is_err() {
+                                break None;
+                            }
+                        }
+                        // Fragments and probes only travel as datagrams
+                        Frame::Pong(_)
+                        | Frame::Control(_)
+                        | Frame::Fragment(_)
+                        | Frame::Probe { .. } => {}
+                        Frame::Close { code, reason } => {
+                            break Some(SessionClose::new(code.into(), reason));
+                        }
                     }
                 }
             });
 
-            let _ = tokio::join!(uplink, downlink);
+            // The session is over once either direction is. The server
+            // closes the connection with the authoritative code right after
+            // ending the stream; failures seen here are closed with ours
+            let (mut uplink, mut downlink) = (uplink, downlink);
+            let (seen, uplink_done) = tokio::select! {
+                close = &mut uplink => (close, true),
+                close = &mut downlink => (close, false),
+            };
+            downlink.abort();
+            let seen = seen.ok().flatten();
+            let close = match tokio::time::timeout(CLOSE_WAIT, connection.closed()).await {
+                Ok(e) => connection_closed(&e),
+                Err(_) => {
+                    let close = seen.unwrap_or_else(|| {
+                        SessionClose::new(CloseCode::Normal, "tunnel stream ended")
+                    });
+                    close_connection(&connection, &close);
+                    close
+                }
+            };
+            // The uplink stops once the connection is closed; let it log
+            if !uplink_done {
+                let _ = tokio::time::timeout(CLOSE_WAIT, &mut uplink).await;
+                uplink.abort();
+            }
+            eprintln!("[client] session closed: {close}");
+            if !close.should_reconnect() {
+                eprintln!("[client] not reconnecting until this is resolved");
+            }
+            control.abort();
+            return Err(close.into());
         }
         #[cfg(not(target_os = "linux"))]
         {
@@ -177,17 +514,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Handle DoH POC: `--doh <domain>`
     if let Some(i) = args.iter().position(|a| a == "--doh") {
         let domain = args.get(i + 1).map(String::as_str).unwrap_or("example.com");
-        // Load server cert
-        let cert_der = fs::read("server_cert.der").or_else(|_| fs::read("../server_cert.der"))?;
-        let client_config = build_client_config(&cert_der)?;
-
-        // Create endpoint
-        let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse()?)?;
-        endpoint.set_default_client_config(client_config);
-
-        // Connect
-        let connect = endpoint.connect("127.0.0.1:4433".parse()?, "localhost")?;
-        let connection = connect.await?;
+        let connection = connect(&settings).await?;
         let addr = connection.remote_address();
         info!("connected: {addr}");
         println!("[client] connected to {addr}");
@@ -204,17 +531,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         return Ok(());
     }
 
-    // Load server cert
-    let cert_der = fs::read("server_cert.der").or_else(|_| fs::read("../server_cert.der"))?;
-    let client_config = build_client_config(&cert_der)?;
-
-    // Create endpoint
-    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse()?)?;
-    endpoint.set_default_client_config(client_config);
-
-    // Connect to server
-    let connect = endpoint.connect("127.0.0.1:4433".parse()?, "localhost")?;
-    let connection = connect.await?;
+    let connection = connect(&settings).await?;
     let addr = connection.remote_address();
     info!("connected: {addr}");
     println!("[client] connected to {addr}");
@@ -0,0 +1,199 @@
+//! Server certificate verification.
+//!
+//! Three independent checks can be configured, and every configured one must
+//! pass:
+//!
+//! - CA roots: the chain must verify against a custom CA file and/or the
+//!   system trust store, for the name the client connects to.
+//! - Pins: the SHA-256 hash of the server's public key (SPKI) must match one
+//!   of the pins. Several pins allow rolling over to a new key.
+//! - Trust on first use: the first key seen for a server is recorded in a
+//!   known_servers file and every later connection must present the same key,
+//!   as SSH does with `known_hosts`.
+//!
+//! With none configured, the client falls back to trusting the certificate in
+//! `server_cert.der`, as written by a server running with `--ephemeral-cert`.
+
+use crate::config::TrustSettings;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, CertificateError, RootCertStore, ServerName};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use tracing::{error, info, warn};
+use xeonvpn_quic::tls::{load_certs, spki_fingerprint};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default location of the trust-on-first-use database.
+const KNOWN_SERVERS_FILE: &str = "known_servers";
+
+/// Normalize a pin to lowercase hex, accepting an optional `sha256:` prefix
+/// and `:` separators.
+fn parse_pin(pin: &str) -> Result<String, BoxError> {
+    let hex = pin.trim();
+    let hex = hex.strip_prefix("sha256:").unwrap_or(hex);
+    let hex = hex.replace(':', "").to_ascii_lowercase();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid pin {pin:?}: expected sha256:<64 hex digits>").into());
+    }
+    Ok(hex)
+}
+
+fn server_key(name: &ServerName) -> String {
+    match name {
+        ServerName::DnsName(dns) => dns.as_ref().to_string(),
+        ServerName::IpAddress(ip) => ip.to_string(),
+        _ => format!("{name:?}"),
+    }
+}
+
+/// Verifies server certificates against the configured CA roots, pins and
+/// known_servers file.
+pub struct ServerTrust {
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<String>,
+    known_servers: Option<Mutex<PathBuf>>,
+}
+
+impl ServerTrust {
+    pub fn from_settings(settings: &TrustSettings) -> Result<Self, BoxError> {
+        let mut roots = RootCertStore::empty();
+        if let Some(path) = &settings.ca_file {
+            for ca in load_certs(path)? {
+                roots.add(&ca)?;
+            }
+        }
+        if settings.system_roots {
+            let native = rustls_native_certs::load_native_certs()?;
+            let native: Vec<Vec<u8>> = native.into_iter().map(|c| c.0).collect();
+            let (added, skipped) = roots.add_parsable_certificates(&native);
+            info!("loaded {added} system CA roots ({skipped} unusable)");
+        }
+        let pins = settings
+            .pins
+            .iter()
+            .map(|p| parse_pin(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let known_servers = settings.tofu.then(|| {
+            let path = settings
+                .known_servers_file
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(KNOWN_SERVERS_FILE));
+            Mutex::new(path)
+        });
+
+        if roots.is_empty() && pins.is_empty() && known_servers.is_none() {
+            // Nothing configured: trust the certificate a local test server wrote
+            let legacy = ["server_cert.der", "../server_cert.der"]
+                .into_iter()
+                .map(Path::new)
+                .find(|p| p.exists())
+                .ok_or(
+                    "no server trust configured: set trust.ca_file, trust.system_roots, \
+                     trust.pins or trust.tofu (or provide server_cert.der)",
+                )?;
+            for cert in load_certs(legacy)? {
+                roots.add(&cert)?;
+            }
+        }
+
+        Ok(Self {
+            webpki: (!roots.is_empty()).then(|| WebPkiVerifier::new(roots, None)),
+            pins,
+            known_servers,
+        })
+    }
+
+    /// Compare `spki` with the key recorded for `server`, recording it if the
+    /// server is new.
+    fn check_known(&self, path: &Mutex<PathBuf>, server: &str, spki: &str) -> Result<(), BoxError> {
+        // Held across read and append so concurrent handshakes record once
+        let path = path.lock().unwrap();
+        let text = match std::fs::read_to_string(&*path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+        };
+        let mut recorded = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            if let (Some(name), Some(pin)) = (fields.next(), fields.next()) {
+                if name == server {
+                    recorded.push((n + 1, parse_pin(pin)?));
+                }
+            }
+        }
+        if recorded.iter().any(|(_, pin)| pin == spki) {
+            return Ok(());
+        }
+        if let Some((line, old)) = recorded.first() {
+            let msg = format!(
+                "\n@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+                 @    WARNING: SERVER IDENTIFICATION HAS CHANGED!          @\n\
+                 @@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n\
+                 Someone could be intercepting your connection, or the server\n\
+                 key was replaced. The key presented by {server} is\n  sha256:{spki}\n\
+                 but {}:{line} records\n  sha256:{old}\n\
+                 Refusing to connect. If the change is expected, remove that line.",
+                path.display()
+            );
+            eprintln!("{msg}");
+            return Err(format!("server key for {server} changed").into());
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*path)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        writeln!(file, "{server} sha256:{spki}")?;
+        warn!(
+            "permanently added {server} (sha256:{spki}) to {}",
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for ServerTrust {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        let spki = spki_fingerprint(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let server = server_key(server_name);
+        if !self.pins.is_empty() && !self.pins.contains(&spki) {
+            error!("server key sha256:{spki} of {server} matches none of the configured pins");
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        if let Some(path) = &self.known_servers {
+            self.check_known(path, &server, &spki).map_err(|e| {
+                error!("trust on first use check failed: {e}");
+                rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+            })?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,171 @@
+//! `xeonvpn-server cert` (alias `keygen`): create a private CA, issue the
+//! server and client certificates signed by it, and print public key pins.
+
+use std::path::{Path, PathBuf};
+use tracing::info;
+use xeonvpn_quic::pki::{self, Issued, LeafKind};
+
+const USAGE: &str = "\
+usage:
+  xeonvpn-server cert ca [--name <cn>] [--out <dir>] [--days <n>]
+  xeonvpn-server cert server --san <host-or-ip>... [--name <cn>] [--ca <dir>] [--out <dir>] [--days <n>]
+  xeonvpn-server cert client --name <user> [--ca <dir>] [--out <dir>] [--days <n>]
+  xeonvpn-server cert pin <cert-file>
+
+Files are written to --out (default: current directory). The CA is read from
+ca.pem and ca.key in --ca (default: --out). Existing files are kept unless
+--force is given.";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+struct Options {
+    names: Vec<String>,
+    sans: Vec<String>,
+    out: PathBuf,
+    ca: Option<PathBuf>,
+    days: Option<u32>,
+    force: bool,
+}
+
+fn parse(args: &[String]) -> Result<Options, BoxError> {
+    let mut opts = Options {
+        names: Vec::new(),
+        sans: Vec::new(),
+        out: PathBuf::from("."),
+        ca: None,
+        days: None,
+        force: false,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .cloned()
+                .ok_or_else(|| format!("{arg} requires a value"))
+        };
+        match arg.as_str() {
+            "--name" => opts.names.push(value()?),
+            "--san" => opts.sans.push(value()?),
+            "--out" => opts.out = value()?.into(),
+            "--ca" => opts.ca = Some(value()?.into()),
+            "--days" => opts.days = Some(value()?.parse()?),
+            "--force" => opts.force = true,
+            other => return Err(format!("unknown option {other}\n{USAGE}").into()),
+        }
+    }
+    Ok(opts)
+}
+
+/// Run the `cert` subcommand with the arguments following it.
+pub fn run(args: &[String]) -> Result<(), BoxError> {
+    let Some((kind, rest)) = args.split_first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    if kind == "--help" || kind == "-h" {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    if kind == "pin" {
+        // Print the public key pin clients put in `trust.pins`
+        let path = rest.first().ok_or("cert pin requires a certificate file")?;
+        let chain = xeonvpn_quic::tls::load_certs(Path::new(path))?;
+        println!("sha256:{}", xeonvpn_quic::tls::spki_fingerprint(&chain[0])?);
+        return Ok(());
+    }
+    let opts = parse(rest)?;
+    std::fs::create_dir_all(&opts.out)?;
+    match kind.as_str() {
+        "ca" => {
+            let name = opts.names.first().map_or("XeonVPN CA", String::as_str);
+            let der = opts.out.join("ca.der");
+            if der.exists() && !opts.force {
+                return Err(format!("{} exists; pass --force to replace it", der.display()).into());
+            }
+            let ca = pki::generate_ca(name, opts.days.unwrap_or(3650))?;
+            write_pair(&opts, "ca", &ca)?;
+            write_file(&der, &ca.cert_der, false, opts.force)?;
+        }
+        "server" => {
+            // Without an explicit common name, use the first SAN
+            let name = opts.names.first().or(opts.sans.first());
+            let name = name.ok_or("server certificates need at least one --san")?;
+            let kind = LeafKind::Server {
+                sans: opts.sans.clone(),
+            };
+            let issued = issue_leaf(&opts, name, &kind)?;
+            write_pair(&opts, "server", &issued)?;
+        }
+        "client" => {
+            let [name] = opts.names.as_slice() else {
+                return Err("client certificates need exactly one --name".into());
+            };
+            if name.contains(['/', '\\']) || name.starts_with('.') {
+                return Err(format!("{name:?} cannot be used as a file name").into());
+            }
+            let issued = issue_leaf(&opts, name, &LeafKind::Client)?;
+            write_pair(&opts, name, &issued)?;
+        }
+        other => return Err(format!("unknown certificate kind {other}\n{USAGE}").into()),
+    }
+    Ok(())
+}
+
+fn issue_leaf(opts: &Options, name: &str, kind: &LeafKind) -> Result<Issued, BoxError> {
+    let ca_dir = opts.ca.as_deref().unwrap_or(&opts.out);
+    let read = |file: &str| {
+        let path = ca_dir.join(file);
+        std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))
+    };
+    pki::issue(
+        &read("ca.pem")?,
+        &read("ca.key")?,
+        name,
+        kind,
+        opts.days.unwrap_or(825),
+    )
+}
+
+fn write_pair(opts: &Options, stem: &str, issued: &Issued) -> Result<(), BoxError> {
+    let cert = opts.out.join(format!("{stem}.pem"));
+    let key = opts.out.join(format!("{stem}.key"));
+    // Check both first so a refused overwrite never leaves a mismatched pair
+    if !opts.force {
+        for path in [&cert, &key] {
+            if path.exists() {
+                return Err(
+                    format!("{} exists; pass --force to replace it", path.display()).into(),
+                );
+            }
+        }
+    }
+    write_file(&cert, issued.cert_pem.as_bytes(), false, opts.force)?;
+    write_file(&key, issued.key_pem.as_bytes(), true, opts.force)?;
+    Ok(())
+}
+
+/// Write `data` to `path`, readable by the owner only if `secret`. Existing
+/// files are kept unless `force`.
+pub(crate) fn write_file(
+    path: &Path,
+    data: &[u8],
+    secret: bool,
+    force: bool,
+) -> Result<(), BoxError> {
+    if path.exists() && !force {
+        return Err(format!("{} exists; pass --force to replace it", path.display()).into());
+    }
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        file.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    std::io::Write::write_all(&mut file.open(path)?, data)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    info!("wrote {}", path.display());
+    Ok(())
+}
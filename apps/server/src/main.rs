@@ -1,3 +1,7 @@
+mod cert;
+mod token;
+
+use std::time::Duration;
 use tracing::info;
 use tracing_subscriber::{fmt, EnvFilter};
 
@@ -12,41 +16,91 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .with_env_filter(EnvFilter::from_default_env())
             .try_init()
     };
+    let args: Vec<String> = std::env::args().collect();
+    if let Some("cert" | "keygen") = args.get(1).map(String::as_str) {
+        return cert::run(&args[2..]);
+    }
+    if let Some("token") = args.get(1).map(String::as_str) {
+        return token::run(&args[2..]);
+    }
+
     let banner = xeonvpn_core::banner("Server");
     info!("{banner}");
 
-    let addr = "0.0.0.0:4433";
-    info!("starting QUIC server on {addr}");
-
-    let args: Vec<String> = std::env::args().collect();
     let use_tun = args.iter().any(|a| a == "--tun-server");
+    // Optional settings file: `--config <path>`
+    let mut settings = match args.iter().position(|a| a == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).ok_or("--config requires a path")?;
+            xeonvpn_quic::config::TunServerSettings::load(std::path::Path::new(path))?
+        }
+        None => xeonvpn_quic::config::TunServerSettings::default(),
+    };
+    if args.iter().any(|a| a == "--ephemeral-cert") {
+        settings.tls.ephemeral = true;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--listen") {
+        settings.listen = args
+            .get(i + 1)
+            .ok_or("--listen requires an address")?
+            .parse()?;
+    }
+    let addr = settings.listen.to_string();
+    info!("starting QUIC server on {addr}");
 
-    let server_task = tokio::spawn(async move {
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let mut server_task = tokio::spawn(async move {
         if use_tun {
             #[cfg(target_os = "linux")]
             {
-                if let Err(e) = xeonvpn_quic::serve_quic_tun(addr).await {
+                let stop = async {
+                    let _ = stop_rx.await;
+                };
+                if let Err(e) = xeonvpn_quic::serve_quic_tun(&addr, settings, stop).await {
                     eprintln!("server error: {e}");
                 }
             }
             #[cfg(not(target_os = "linux"))]
             {
+                drop(stop_rx);
                 eprintln!("--tun-server is only supported on Linux for now");
-                if let Err(e) = xeonvpn_quic::serve_quic(addr).await {
+                if let Err(e) =
+                    xeonvpn_quic::serve_quic(&addr, &settings.tls, &settings.transport).await
+                {
                     eprintln!("server error: {e}");
                 }
             }
-        } else if let Err(e) = xeonvpn_quic::serve_quic(addr).await {
+        } else if let Err(e) =
+            xeonvpn_quic::serve_quic(&addr, &settings.tls, &settings.transport).await
+        {
             eprintln!("server error: {e}");
         }
     });
 
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c().await?;
+    // Wait for Ctrl+C or SIGTERM
+    shutdown_signal().await?;
     info!("shutdown signal received, exiting");
 
-    // Stop background task
+    // Let the TUN server tell its clients, then stop whatever is left
+    let _ = stop_tx.send(());
+    if use_tun {
+        let _ = tokio::time::timeout(Duration::from_secs(5), &mut server_task).await;
+    }
     server_task.abort();
 
     Ok(())
 }
+
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,126 @@
+//! `xeonvpn-server token`: create token signing keys and issue session
+//! tokens, for testing and for deployments without a provisioning backend.
+
+use crate::cert::write_file;
+use std::path::{Path, PathBuf};
+use xeonvpn_quic::token::{self, TokenAlgorithm, TokenClaims, TokenSigner};
+
+const USAGE: &str = "\
+usage:
+  xeonvpn-server token keygen --key-id <id> [--algorithm <alg>] [--out <dir>] [--force]
+  xeonvpn-server token issue --key <file> --key-id <id> --user <user> [--algorithm <alg>]
+                             [--ttl <secs>] [--region <region>]... [--bandwidth-class <class>]
+
+<alg> is ed25519 (default) or hmac-sha256. keygen writes the signing key to
+<id>.key in --out (default: current directory) and, for ed25519, the public
+key servers verify with to <id>.pub. issue prints a token signed with --key,
+valid for --ttl seconds (default 3600) in every region unless --region is
+given.";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+struct Options {
+    key_id: Option<String>,
+    algorithm: TokenAlgorithm,
+    out: PathBuf,
+    force: bool,
+    key: Option<PathBuf>,
+    user: Option<String>,
+    ttl: u64,
+    regions: Vec<String>,
+    bandwidth_class: Option<String>,
+}
+
+fn parse(args: &[String]) -> Result<Options, BoxError> {
+    let mut opts = Options {
+        key_id: None,
+        algorithm: TokenAlgorithm::Ed25519,
+        out: PathBuf::from("."),
+        force: false,
+        key: None,
+        user: None,
+        ttl: 3600,
+        regions: Vec::new(),
+        bandwidth_class: None,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .cloned()
+                .ok_or_else(|| format!("{arg} requires a value"))
+        };
+        match arg.as_str() {
+            "--key-id" => opts.key_id = Some(value()?),
+            "--algorithm" => opts.algorithm = value()?.parse()?,
+            "--out" => opts.out = value()?.into(),
+            "--force" => opts.force = true,
+            "--key" => opts.key = Some(value()?.into()),
+            "--user" => opts.user = Some(value()?),
+            "--ttl" => opts.ttl = value()?.parse()?,
+            "--region" => opts.regions.push(value()?),
+            "--bandwidth-class" => opts.bandwidth_class = Some(value()?),
+            other => return Err(format!("unknown option {other}\n{USAGE}").into()),
+        }
+    }
+    Ok(opts)
+}
+
+/// Run the `token` subcommand with the arguments following it.
+pub fn run(args: &[String]) -> Result<(), BoxError> {
+    let Some((command, rest)) = args.split_first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    if command == "--help" || command == "-h" {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    let opts = parse(rest)?;
+    let key_id = opts.key_id.as_deref().ok_or("--key-id is required")?;
+    match command.as_str() {
+        "keygen" => {
+            if key_id.contains(['/', '\\']) || key_id.starts_with('.') {
+                return Err(format!("{key_id:?} cannot be used as a file name").into());
+            }
+            let key = token::generate_key(opts.algorithm)?;
+            std::fs::create_dir_all(&opts.out)?;
+            let secret = opts.out.join(format!("{key_id}.key"));
+            let public = opts.out.join(format!("{key_id}.pub"));
+            // Check both first so a refused overwrite never leaves a mismatched pair
+            if !opts.force {
+                for path in [&secret, &public] {
+                    if path.exists() {
+                        return Err(format!(
+                            "{} exists; pass --force to replace it",
+                            path.display()
+                        )
+                        .into());
+                    }
+                }
+            }
+            write_file(&secret, key.secret.as_bytes(), true, opts.force)?;
+            if let Some(pem) = &key.public {
+                write_file(&public, pem.as_bytes(), false, opts.force)?;
+            }
+        }
+        "issue" => {
+            let key = opts.key.as_deref().ok_or("--key is required")?;
+            let user = opts.user.clone().ok_or("--user is required")?;
+            let signer = TokenSigner::load(opts.algorithm, Path::new(key))?;
+            let expires = token::unix_now()
+                .checked_add(opts.ttl)
+                .ok_or_else(|| format!("--ttl {} is out of range", opts.ttl))?;
+            let claims = TokenClaims {
+                key_id: key_id.to_string(),
+                user,
+                expires,
+                regions: opts.regions,
+                bandwidth_class: opts.bandwidth_class,
+            };
+            println!("{}", signer.sign(&claims));
+        }
+        other => return Err(format!("unknown token command {other}\n{USAGE}").into()),
+    }
+    Ok(())
+}
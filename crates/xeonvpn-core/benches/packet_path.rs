@@ -0,0 +1,110 @@
+//! Allocations and throughput of the per-packet data path.
+//!
+//! Run with `cargo bench -p xeonvpn-core`. Compares the original path, where
+//! every packet is read into a fresh `Vec` and copied again to encode its
+//! frame, with the pooled path, where packets are read into [`PacketPool`]
+//! buffers and framed in place. A counting global allocator reports the heap
+//! allocations per packet of each.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use xeonvpn_core::buffer::PacketPool;
+use xeonvpn_core::frame::{Frame, FrameRead};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const PACKETS: usize = 200_000;
+const PACKET_LEN: usize = 1400;
+const BUF_LEN: usize = 2000;
+
+/// Run `f` once per packet and print allocations per packet and packet rate.
+fn measure(name: &str, mut f: impl FnMut()) {
+    // Warm up so one-off allocations (arenas, runtimes) are not counted
+    for _ in 0..1000 {
+        f();
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{name:<28} {:>6.2} allocs/packet {:>8.0} ns/packet",
+        allocs as f64 / PACKETS as f64,
+        elapsed.as_nanos() as f64 / PACKETS as f64,
+    );
+}
+
+fn main() {
+    let packet = vec![0x45u8; PACKET_LEN];
+
+    // TUN read followed by framing for the wire
+    measure("encode: Vec", || {
+        let mut buf = vec![0u8; BUF_LEN];
+        buf[..PACKET_LEN].copy_from_slice(&packet);
+        buf.truncate(PACKET_LEN);
+        let mut wire = Vec::new();
+        Frame::Data(buf.into()).encode(&mut wire).unwrap();
+        black_box(wire);
+    });
+    let mut pool = PacketPool::new(BUF_LEN);
+    measure("encode: pooled", || {
+        let mut buf = pool.take();
+        buf.space_mut()[..PACKET_LEN].copy_from_slice(&packet);
+        buf.truncate(PACKET_LEN);
+        black_box(buf.into_data_frame());
+    });
+
+    // Datagram received from the peer
+    let datagram = Frame::Data(packet.clone().into()).to_bytes().unwrap();
+    measure("decode datagram: copy", || {
+        black_box(Frame::decode(&datagram).unwrap());
+    });
+    measure("decode datagram: zero-copy", || {
+        black_box(Frame::decode_bytes(&datagram).unwrap());
+    });
+
+    // Frames read off a stream
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let stream = datagram.repeat(64);
+    measure("stream read: Vec", || {
+        rt.block_on(async {
+            let mut reader = &stream[..];
+            black_box(reader.read_frame().await.unwrap());
+        })
+    });
+    let mut pool = PacketPool::new(BUF_LEN);
+    measure("stream read: pooled", || {
+        rt.block_on(async {
+            let mut reader = &stream[..];
+            black_box(reader.read_frame_in(&mut pool).await.unwrap());
+        })
+    });
+}
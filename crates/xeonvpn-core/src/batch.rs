@@ -0,0 +1,110 @@
+//! Batch frames: several IP packets packed into one [`Frame::Batch`].
+//!
+//! A small packet costs about as much to frame, write and read as a large
+//! one, so a sender with several packets queued for the stream packs them
+//! into one frame. The payload is a sequence of packets, each prefixed with
+//! its length:
+//!
+//! ```text
+//! +------------------+--------+------------------+--------+-----
+//! | length (u16, BE) | packet | length (u16, BE) | packet | ...
+//! +------------------+--------+------------------+--------+-----
+//! ```
+//!
+//! [`Frame::Batch`]: crate::frame::Frame::Batch
+
+use crate::frame::{write_header, ProtocolError, HEADER_LEN, MAX_PAYLOAD, TYPE_BATCH, TYPE_DATA};
+use bytes::{BufMut, Bytes, BytesMut};
+
+const LEN_PREFIX: usize = 2;
+
+/// Pack runs of encoded data frames in `frames` into batch frames of at
+/// most `max_packets` packets each. Other frames, and the order of all
+/// packets, are kept as they are; a lone data frame is not repacked.
+pub fn coalesce(frames: &mut Vec<Bytes>, max_packets: usize) {
+    if max_packets < 2 || frames.len() < 2 {
+        return;
+    }
+    let mut out = Vec::with_capacity(frames.len());
+    let mut run = Run::default();
+    for frame in frames.drain(..) {
+        let Some(len) = data_len(&frame) else {
+            run.finish(&mut out);
+            out.push(frame);
+            continue;
+        };
+        if run.frames.len() == max_packets || run.payload + LEN_PREFIX + len > MAX_PAYLOAD {
+            run.finish(&mut out);
+        }
+        run.payload += LEN_PREFIX + len;
+        run.frames.push(frame);
+    }
+    run.finish(&mut out);
+    *frames = out;
+}
+
+/// Payload length of an encoded data frame, or `None` for other frames.
+fn data_len(frame: &Bytes) -> Option<usize> {
+    (frame.len() >= HEADER_LEN && frame[3] == TYPE_DATA).then(|| frame.len() - HEADER_LEN)
+}
+
+/// Consecutive data frames waiting to be packed.
+#[derive(Default)]
+struct Run {
+    frames: Vec<Bytes>,
+    payload: usize,
+}
+
+impl Run {
+    fn finish(&mut self, out: &mut Vec<Bytes>) {
+        if self.frames.len() < 2 {
+            out.append(&mut self.frames);
+        } else {
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + self.payload);
+            buf.resize(HEADER_LEN, 0);
+            write_header(&mut buf, TYPE_BATCH, self.payload);
+            for frame in self.frames.drain(..) {
+                let pkt = &frame[HEADER_LEN..];
+                buf.put_u16(pkt.len() as u16);
+                buf.put_slice(pkt);
+            }
+            out.push(buf.freeze());
+        }
+        self.payload = 0;
+    }
+}
+
+/// The packets in the payload of a [`Frame::Batch`], as slices of it.
+///
+/// [`Frame::Batch`]: crate::frame::Frame::Batch
+pub fn unpack(payload: Bytes) -> Unpack {
+    Unpack { payload, at: 0 }
+}
+
+/// Iterator returned by [`unpack`]. Stops after the first malformed entry.
+pub struct Unpack {
+    payload: Bytes,
+    at: usize,
+}
+
+impl Iterator for Unpack {
+    type Item = Result<Bytes, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.payload.get(self.at..)?;
+        if rest.is_empty() {
+            return None;
+        }
+        let len = match rest {
+            [hi, lo, ..] => usize::from(u16::from_be_bytes([*hi, *lo])),
+            _ => 0,
+        };
+        if len == 0 || rest.len() < LEN_PREFIX + len {
+            self.at = self.payload.len();
+            return Some(Err(ProtocolError::Malformed("truncated packet in batch")));
+        }
+        let start = self.at + LEN_PREFIX;
+        self.at = start + len;
+        Some(Ok(self.payload.slice(start..self.at)))
+    }
+}
@@ -0,0 +1,148 @@
+//! Pooled packet buffers for the data path.
+//!
+//! A [`PacketPool`] carves buffers out of one large [`BytesMut`] arena. Every
+//! buffer starts with [`HEADER_LEN`] bytes of headroom, so a packet read from
+//! the TUN can be turned into a wire frame by writing the header in front of
+//! it instead of copying it into a new allocation. Frozen buffers are
+//! reference-counted views into the arena; once all of them are dropped the
+//! arena's memory is reused for the next packets, so the steady state of a
+//! tunnel does not allocate per packet. An arena is zeroed once, when it is
+//! allocated; buffers handed out later keep whatever the arena held before.
+
+use crate::frame::{write_header, HEADER_LEN, MAX_PAYLOAD, TYPE_DATA, TYPE_OFFLOAD};
+use bytes::{Buf, Bytes, BytesMut};
+use std::ops::{Deref, DerefMut};
+
+/// Size an arena aims for. Large buffers get fewer per arena, so a few of
+/// them still queued do not pin megabytes of memory.
+const ARENA_BYTES: usize = 256 * 1024;
+
+/// Most buffers carved from one arena allocation.
+const ARENA_BUFFERS: usize = 64;
+
+/// Source of [`PacketBuf`]s with room for `buf_len` payload bytes each.
+///
+/// A pool is owned by one task; it is cheap to create one per reader.
+pub struct PacketPool {
+    arena: BytesMut,
+    buf_len: usize,
+}
+
+impl PacketPool {
+    /// Create a pool handing out buffers with room for `buf_len` bytes.
+    pub fn new(buf_len: usize) -> Self {
+        Self {
+            arena: BytesMut::new(),
+            buf_len,
+        }
+    }
+
+    /// Payload capacity of the buffers from this pool.
+    pub fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// Take an empty buffer whose [`PacketBuf::space_mut`] is `buf_len`
+    /// bytes long.
+    pub fn take(&mut self) -> PacketBuf {
+        let need = HEADER_LEN + self.buf_len;
+        if self.arena.len() < need {
+            self.refill(need);
+        }
+        PacketBuf {
+            buf: self.arena.split_to(need),
+        }
+    }
+
+    /// Replace the used-up arena. It is reused in place once every buffer
+    /// carved from it has been dropped, and a fresh one is allocated
+    /// otherwise.
+    fn refill(&mut self, need: usize) {
+        let size = need * (ARENA_BYTES / need).clamp(1, ARENA_BUFFERS);
+        self.arena.clear();
+        if self.arena.try_reclaim(size) {
+            // SAFETY: reclaiming hands back part of the allocation the arena
+            // was created with, and all of that was zeroed below
+            unsafe { self.arena.set_len(self.arena.capacity()) };
+        } else {
+            self.arena = BytesMut::zeroed(size);
+            let capacity = self.arena.capacity();
+            self.arena.resize(capacity, 0);
+        }
+    }
+}
+
+/// A packet with [`HEADER_LEN`] bytes of headroom in front of it.
+///
+/// Dereferences to the packet bytes, without the headroom.
+pub struct PacketBuf {
+    buf: BytesMut,
+}
+
+impl PacketBuf {
+    /// The writable packet area after the headroom.
+    pub fn space_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[HEADER_LEN..]
+    }
+
+    /// Shorten the packet to `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(HEADER_LEN + len);
+    }
+
+    /// Drop the first `n` bytes of the packet, keeping the headroom in front
+    /// of what is left.
+    pub fn advance(&mut self, n: usize) {
+        assert!(n <= self.len(), "advance past the end of the packet");
+        self.buf.advance(n);
+    }
+
+    /// Write a [`Frame::Data`](crate::frame::Frame::Data) header into the
+    /// headroom and return the encoded frame, without copying the packet.
+    ///
+    /// Panics if the packet is longer than [`MAX_PAYLOAD`].
+    pub fn into_data_frame(self) -> Bytes {
+        self.into_frame(TYPE_DATA)
+    }
+
+    /// Like [`PacketBuf::into_data_frame`], for a packet that starts with a
+    /// virtio-net header ([`Frame::Offload`](crate::frame::Frame::Offload)).
+    pub fn into_offload_frame(self) -> Bytes {
+        self.into_frame(TYPE_OFFLOAD)
+    }
+
+    fn into_frame(mut self, ty: u8) -> Bytes {
+        let len = self.len();
+        assert!(len <= MAX_PAYLOAD, "{len} byte packet does not fit a frame");
+        write_header(&mut self.buf[..HEADER_LEN], ty, len);
+        self.buf.freeze()
+    }
+
+    /// The packet bytes, dropping the headroom.
+    pub fn freeze(mut self) -> Bytes {
+        self.buf.advance(HEADER_LEN);
+        self.buf.freeze()
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[HEADER_LEN..]
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[HEADER_LEN..]
+    }
+}
+
+impl std::fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketBuf")
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,170 @@
+//! Why a session ended.
+//!
+//! The same [`CloseCode`]s are used as QUIC application close codes, as
+//! stream stop and reset codes, in [`Frame::Close`] and in session
+//! rejections, each with a human-readable reason. The client turns whatever
+//! ended its session into a [`SessionClose`] and uses
+//! [`SessionClose::should_reconnect`] to decide whether to try again.
+//!
+//! [`Frame::Close`]: crate::frame::Frame::Close
+
+use crate::frame::{Frame, ProtocolError};
+use std::fmt;
+
+/// Application close code of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// Orderly shutdown; nothing went wrong.
+    Normal,
+    /// The peer broke the protocol: a misaligned stream, or a message that
+    /// is not valid at that point.
+    ProtocolViolation,
+    /// The client failed authentication.
+    AuthFailed,
+    /// A frame that could not be decoded or carried invalid content.
+    BadFrame,
+    /// The server is shutting down or restarting.
+    ShuttingDown,
+    /// Nothing was heard from the peer for too long.
+    IdleTimeout,
+    /// The client used up its allowance.
+    QuotaExceeded,
+    /// The peers speak different protocol versions.
+    VersionMismatch,
+    /// The server cannot take the session right now, e.g. it is out of
+    /// tunnel addresses.
+    Unavailable,
+    /// A peer failed on its side, e.g. writing to its TUN.
+    Internal,
+    /// A code this build does not know.
+    Other(u16),
+}
+
+impl CloseCode {
+    /// Whether reconnecting would end the same way until something changes
+    /// on either side (credentials, software or configuration).
+    pub fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            CloseCode::ProtocolViolation
+                | CloseCode::AuthFailed
+                | CloseCode::BadFrame
+                | CloseCode::QuotaExceeded
+                | CloseCode::VersionMismatch
+        )
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            0 => CloseCode::Normal,
+            1 => CloseCode::ProtocolViolation,
+            2 => CloseCode::AuthFailed,
+            3 => CloseCode::BadFrame,
+            4 => CloseCode::ShuttingDown,
+            5 => CloseCode::IdleTimeout,
+            6 => CloseCode::QuotaExceeded,
+            7 => CloseCode::VersionMismatch,
+            8 => CloseCode::Unavailable,
+            9 => CloseCode::Internal,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Normal => 0,
+            CloseCode::ProtocolViolation => 1,
+            CloseCode::AuthFailed => 2,
+            CloseCode::BadFrame => 3,
+            CloseCode::ShuttingDown => 4,
+            CloseCode::IdleTimeout => 5,
+            CloseCode::QuotaExceeded => 6,
+            CloseCode::VersionMismatch => 7,
+            CloseCode::Unavailable => 8,
+            CloseCode::Internal => 9,
+            CloseCode::Other(other) => other,
+        }
+    }
+}
+
+impl From<&ProtocolError> for CloseCode {
+    fn from(e: &ProtocolError) -> Self {
+        match e {
+            ProtocolError::UnsupportedVersion(_) => CloseCode::VersionMismatch,
+            ProtocolError::UnknownFrameType(_)
+            | ProtocolError::FrameTooLarge(_)
+            | ProtocolError::Malformed(_) => CloseCode::BadFrame,
+            ProtocolError::BadMagic(_) => CloseCode::ProtocolViolation,
+            ProtocolError::Io(_) => CloseCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseCode::Normal => f.write_str("closed"),
+            CloseCode::ProtocolViolation => f.write_str("protocol violation"),
+            CloseCode::AuthFailed => f.write_str("authentication failed"),
+            CloseCode::BadFrame => f.write_str("bad frame"),
+            CloseCode::ShuttingDown => f.write_str("server shutting down"),
+            CloseCode::IdleTimeout => f.write_str("idle timeout"),
+            CloseCode::QuotaExceeded => f.write_str("quota exceeded"),
+            CloseCode::VersionMismatch => f.write_str("version mismatch"),
+            CloseCode::Unavailable => f.write_str("unavailable"),
+            CloseCode::Internal => f.write_str("internal error"),
+            CloseCode::Other(code) => write!(f, "close code {code}"),
+        }
+    }
+}
+
+/// A session's end: its close code and the reason given with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionClose {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl SessionClose {
+    pub fn new(code: CloseCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// The close to send for a frame error on a stream.
+    pub fn protocol(e: &ProtocolError) -> Self {
+        Self::new(e.into(), e.to_string())
+    }
+
+    /// Whether the client should connect again, after a backoff.
+    pub fn should_reconnect(&self) -> bool {
+        !self.code.is_permanent()
+    }
+
+    /// The [`Frame::Close`] announcing this close on a stream.
+    pub fn to_frame(&self) -> Frame {
+        Frame::Close {
+            code: self.code.into(),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+impl fmt::Display for SessionClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.code, u16::from(self.code))?;
+        // Many reasons only repeat the code's description
+        if !self.reason.is_empty() && self.reason != self.code.to_string() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SessionClose {}
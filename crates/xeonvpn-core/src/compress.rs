@@ -0,0 +1,163 @@
+//! Optional per-packet compression of tunnel payloads.
+//!
+//! The client lists the algorithms it accepts in its hello and the server
+//! picks one for the session (see [`crate::control`]). Each side then sends
+//! packets that shrink as [`Frame::Compressed`], whose payload names the
+//! algorithm and the original length in front of the compressed bytes:
+//!
+//! ```text
+//! +-----------+-------------------------+-----------------+
+//! | algorithm | original length (u16)   | compressed data |
+//! +-----------+-------------------------+-----------------+
+//! ```
+//!
+//! Packets that do not shrink by at least 1/32 are sent as they are.
+//!
+//! [`Frame::Compressed`]: crate::frame::Frame::Compressed
+
+use crate::frame::{write_header, ProtocolError, HEADER_LEN, TYPE_COMPRESSED};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+const PREFIX_LEN: usize = 3;
+
+/// A compression algorithm for tunnel packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Fast, with modest ratios.
+    Lz4,
+    /// Better ratios for more CPU.
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// The algorithm for a session: the first the client offers that the
+/// server accepts too.
+pub fn negotiate(offered: &[Compression], accepted: &[Compression]) -> Option<Compression> {
+    offered.iter().copied().find(|c| accepted.contains(c))
+}
+
+/// Compresses packets into encoded [`Frame::Compressed`] frames. Keeps its
+/// scratch buffer and zstd context between packets.
+///
+/// [`Frame::Compressed`]: crate::frame::Frame::Compressed
+pub struct Compressor {
+    algorithm: Compression,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    scratch: Vec<u8>,
+}
+
+impl Compressor {
+    /// A compressor for `algorithm`; `level` only applies to zstd.
+    pub fn new(algorithm: Compression, level: i32) -> io::Result<Self> {
+        let zstd = match algorithm {
+            Compression::Zstd => Some(zstd::bulk::Compressor::new(level)?),
+            Compression::Lz4 => None,
+        };
+        Ok(Self {
+            algorithm,
+            zstd,
+            scratch: Vec::new(),
+        })
+    }
+
+    pub fn algorithm(&self) -> Compression {
+        self.algorithm
+    }
+
+    /// Compress `pkt` into an encoded frame, or return `None` when it does
+    /// not shrink enough to be worth it.
+    pub fn compress(&mut self, pkt: &[u8]) -> Option<Bytes> {
+        let original = u16::try_from(pkt.len()).ok()?;
+        let bound = match self.algorithm {
+            Compression::Lz4 => lz4_flex::block::get_maximum_output_size(pkt.len()),
+            Compression::Zstd => zstd::zstd_safe::compress_bound(pkt.len()),
+        };
+        let start = HEADER_LEN + PREFIX_LEN;
+        self.scratch.resize(start + bound, 0);
+        let out = &mut self.scratch[start..];
+        let len = match &mut self.zstd {
+            Some(zstd) => zstd.compress_to_buffer(pkt, out).ok()?,
+            None => lz4_flex::block::compress_into(pkt, out).ok()?,
+        };
+        let payload = PREFIX_LEN + len;
+        if payload > pkt.len() - pkt.len() / 32 {
+            return None;
+        }
+        write_header(&mut self.scratch, TYPE_COMPRESSED, payload);
+        self.scratch[HEADER_LEN] = self.algorithm.id();
+        self.scratch[HEADER_LEN + 1..start].copy_from_slice(&original.to_be_bytes());
+        Some(Bytes::copy_from_slice(&self.scratch[..start + len]))
+    }
+}
+
+/// Restores packets from [`Frame::Compressed`] payloads of any algorithm.
+/// Keeps its zstd context between packets.
+///
+/// [`Frame::Compressed`]: crate::frame::Frame::Compressed
+#[derive(Default)]
+pub struct Decompressor {
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decompress the payload of a compressed frame into the original
+    /// packet.
+    pub fn decompress(&mut self, payload: &[u8]) -> Result<Bytes, ProtocolError> {
+        let [id, hi, lo, data @ ..] = payload else {
+            return Err(ProtocolError::Malformed("compressed payload too short"));
+        };
+        let original = usize::from(u16::from_be_bytes([*hi, *lo]));
+        let mut out = vec![0u8; original];
+        let len = match Compression::from_id(*id) {
+            Some(Compression::Lz4) => lz4_flex::block::decompress_into(data, &mut out)
+                .map_err(|_| ProtocolError::Malformed("invalid lz4 data"))?,
+            Some(Compression::Zstd) => {
+                let zstd = match &mut self.zstd {
+                    Some(zstd) => zstd,
+                    None => self.zstd.insert(zstd::bulk::Decompressor::new()?),
+                };
+                zstd.decompress_to_buffer(data, out.as_mut_slice())
+                    .map_err(|_| ProtocolError::Malformed("invalid zstd data"))?
+            }
+            None => return Err(ProtocolError::Malformed("unknown compression algorithm")),
+        };
+        if len != original {
+            return Err(ProtocolError::Malformed(
+                "compressed packet length mismatch",
+            ));
+        }
+        Ok(out.into())
+    }
+}
@@ -0,0 +1,168 @@
+//! Session control messages exchanged on the control stream.
+//!
+//! Each message travels JSON-encoded inside a [`Frame::Control`]. The client
+//! opens the control stream and sends [`ControlMessage::Hello`]; the server
+//! answers with [`ControlMessage::Welcome`] carrying the session parameters,
+//! or [`ControlMessage::Reject`] when it will not serve the client.
+
+use crate::close::CloseCode;
+use crate::compress::Compression;
+use crate::frame::{Frame, ProtocolError};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Optional protocol features a peer supports.
+///
+/// Unknown fields are ignored and missing ones default to off, so either side
+/// can add capabilities without breaking older peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Packets may be carried in QUIC DATAGRAM frames.
+    pub datagrams: bool,
+    /// The peer's TUN takes packets with virtio-net headers, so
+    /// [`Frame::Offload`] frames may be sent to it.
+    pub offload: bool,
+    /// The peer unpacks [`Frame::Batch`] frames.
+    pub batching: bool,
+    /// The peer reassembles [`Frame::Fragment`] datagrams.
+    pub fragments: bool,
+    /// The peer answers [`Frame::Probe`] datagrams.
+    pub probes: bool,
+}
+
+impl Capabilities {
+    /// Features both peers support.
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            datagrams: self.datagrams && other.datagrams,
+            offload: self.offload && other.offload,
+            batching: self.batching && other.batching,
+            fragments: self.fragments && other.fragments,
+            probes: self.probes && other.probes,
+        }
+    }
+}
+
+/// First message on the control stream, sent by the client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// Wire protocol version the client speaks.
+    pub version: u8,
+    pub capabilities: Capabilities,
+    /// Stable identifier the client keeps across connections, used to give a
+    /// reconnecting client its previous tunnel address.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Proof of identity; required when the server enforces authentication.
+    #[serde(default)]
+    pub credentials: Option<Credentials>,
+    /// Compression algorithms the client accepts, preferred first.
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+}
+
+/// Client credentials presented in the [`ClientHello`].
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Credentials {
+    /// HMAC over TLS exporter keying material, keyed with the pre-shared key.
+    Psk {
+        #[serde(with = "hex_bytes")]
+        proof: Vec<u8>,
+    },
+    /// A signed, expiring token from the provisioning backend.
+    Token { token: String },
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep proofs out of logs
+        match self {
+            Credentials::Psk { .. } => f.write_str("Psk { .. }"),
+            Credentials::Token { .. } => f.write_str("Token { .. }"),
+        }
+    }
+}
+
+/// Parameters the server assigns to a session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionParams {
+    pub session_id: u64,
+    /// Addresses to configure on the client TUN, with their prefix length.
+    pub addresses: Vec<IpNet>,
+    /// MTU to set on the client TUN.
+    pub mtu: u16,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// Destinations the client should route into the tunnel.
+    #[serde(default)]
+    pub routes: Vec<IpNet>,
+    /// Features enabled for this session (the intersection of both peers').
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Algorithm both peers compress packets with, if any.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Hello(ClientHello),
+    Welcome(SessionParams),
+    Reject {
+        reason: String,
+        /// A [`CloseCode`], as on the wire.
+        #[serde(default = "unavailable")]
+        code: u16,
+    },
+}
+
+impl ControlMessage {
+    /// Wrap this message in a control frame.
+    pub fn to_frame(&self) -> Frame {
+        // Serializing these plain data types cannot fail.
+        Frame::Control(serde_json::to_vec(self).expect("control message serializes"))
+    }
+
+    /// Parse a control frame payload.
+    pub fn from_payload(payload: &[u8]) -> Result<ControlMessage, ProtocolError> {
+        serde_json::from_slice(payload)
+            .map_err(|_| ProtocolError::Malformed("invalid control message"))
+    }
+}
+
+/// Close code of a rejection from a server that did not send one.
+fn unavailable() -> u16 {
+    CloseCode::Unavailable.into()
+}
+
+/// Serialize byte strings as lowercase hex.
+mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::fmt::Write;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        let mut out = String::with_capacity(bytes.len() * 2);
+        for b in bytes {
+            let _ = write!(out, "{b:02x}");
+        }
+        s.serialize_str(&out)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(d)?;
+        if text.len() % 2 != 0 {
+            return Err(D::Error::custom("odd-length hex string"));
+        }
+        text.as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair).map_err(D::Error::custom)?;
+                u8::from_str_radix(pair, 16).map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,377 @@
+//! Fragments: frames too big for one QUIC datagram, split over several.
+//!
+//! The sender cuts the encoded frame into numbered pieces, each sent as a
+//! [`Frame::Fragment`] whose payload starts with the frame's id, the piece's
+//! index and the number of pieces:
+//!
+//! ```text
+//! +-----------+--------------+--------------+------------------+
+//! | id (u32)  | index (u8)   | count (u8)   | piece of a frame |
+//! +-----------+--------------+--------------+------------------+
+//! ```
+//!
+//! The receiver collects the pieces in a [`Reassembler`] and decodes the
+//! frame once all have arrived. Datagrams may be lost, so incomplete frames
+//! are dropped after a timeout, and both the number of incomplete frames and
+//! the memory they hold are capped.
+//!
+//! [`Frame::Fragment`]: crate::frame::Frame::Fragment
+
+use crate::frame::{write_header, ProtocolError, HEADER_LEN, TYPE_FRAGMENT};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Bytes in front of each piece of a frame.
+pub const FRAGMENT_PREFIX_LEN: usize = 6;
+
+/// Most pieces a frame is cut into.
+const MAX_FRAGMENTS: usize = u8::MAX as usize;
+
+/// Most incomplete frames a [`Reassembler`] holds at once.
+pub const MAX_PENDING: usize = 1024;
+
+/// Bytes charged against the memory cap for the bookkeeping of one
+/// incomplete frame, on top of its slots and pieces.
+const PARTIAL_OVERHEAD: usize = 64;
+
+/// Memory cap charge of an incomplete frame of `count` pieces, before any
+/// piece arrived.
+fn partial_cost(count: usize) -> usize {
+    PARTIAL_OVERHEAD + count * std::mem::size_of::<Option<Bytes>>()
+}
+
+/// Cut the encoded frame `frame` into fragment frames of at most
+/// `max_size` bytes each. Returns `None` when that takes more than 255
+/// pieces, or `max_size` leaves no room for data.
+pub fn split(frame: &[u8], id: u32, max_size: usize) -> Option<Vec<Bytes>> {
+    let room = max_size.checked_sub(HEADER_LEN + FRAGMENT_PREFIX_LEN)?;
+    if room == 0 {
+        return None;
+    }
+    let count = frame.len().div_ceil(room);
+    if count > MAX_FRAGMENTS {
+        return None;
+    }
+    let pieces = frame
+        .chunks(room)
+        .enumerate()
+        .map(|(index, piece)| {
+            let payload = FRAGMENT_PREFIX_LEN + piece.len();
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + payload);
+            buf.resize(HEADER_LEN, 0);
+            write_header(&mut buf, TYPE_FRAGMENT, payload);
+            buf.put_u32(id);
+            buf.put_u8(index as u8);
+            buf.put_u8(count as u8);
+            buf.put_slice(piece);
+            buf.freeze()
+        })
+        .collect();
+    Some(pieces)
+}
+
+/// Puts frames back together from their fragments.
+pub struct Reassembler {
+    pending: HashMap<u32, Partial>,
+    /// Bytes charged for `pending`: pieces and bookkeeping.
+    held: usize,
+    max_held: usize,
+    timeout: Duration,
+    next_sweep: Instant,
+    stats: ReassemblyStats,
+}
+
+/// The pieces of one frame received so far.
+struct Partial {
+    started: Instant,
+    pieces: Vec<Option<Bytes>>,
+    received: usize,
+    /// Bytes of the pieces received.
+    len: usize,
+    /// Bytes charged against the memory cap.
+    cost: usize,
+}
+
+impl Reassembler {
+    /// A reassembler that gives up on a frame `timeout` after its first
+    /// piece arrived, and holds at most `max_held` bytes of incomplete
+    /// frames.
+    pub fn new(timeout: Duration, max_held: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            held: 0,
+            max_held,
+            timeout,
+            next_sweep: Instant::now() + timeout,
+            stats: ReassemblyStats::default(),
+        }
+    }
+
+    /// Add the payload of a fragment frame. Returns the encoded frame once
+    /// its last piece is in.
+    ///
+    /// Fragments that do not fit the caps, are empty, repeat a piece or
+    /// disagree with earlier pieces about the count are dropped and counted.
+    pub fn push(&mut self, payload: Bytes) -> Result<Option<Bytes>, ProtocolError> {
+        let now = Instant::now();
+        if now >= self.next_sweep {
+            self.expire(now);
+        }
+        self.stats.received += 1;
+        let [a, b, c, d, index, count, ..] = payload[..] else {
+            self.stats.dropped_invalid += 1;
+            return Err(ProtocolError::Malformed("fragment too short"));
+        };
+        let (id, index, count) = (u32::from_be_bytes([a, b, c, d]), index, count);
+        if count == 0 || index >= count {
+            self.stats.dropped_invalid += 1;
+            return Err(ProtocolError::Malformed("fragment index out of range"));
+        }
+        if payload.len() == FRAGMENT_PREFIX_LEN {
+            self.stats.dropped_invalid += 1;
+            return Err(ProtocolError::Malformed("empty fragment"));
+        }
+        // Copied so a held piece does not keep its whole datagram alive
+        let piece = Bytes::copy_from_slice(&payload[FRAGMENT_PREFIX_LEN..]);
+        let new = !self.pending.contains_key(&id);
+        let cost = piece.len()
+            + if new {
+                partial_cost(usize::from(count))
+            } else {
+                0
+            };
+        let over = |r: &Self| r.held + cost > r.max_held || (new && r.pending.len() >= MAX_PENDING);
+        if over(self) {
+            self.expire(now);
+            if over(self) {
+                self.stats.dropped_memory += 1;
+                return Ok(None);
+            }
+        }
+        let partial = self.pending.entry(id).or_insert_with(|| Partial {
+            started: now,
+            pieces: vec![None; usize::from(count)],
+            received: 0,
+            len: 0,
+            cost: 0,
+        });
+        let slot = usize::from(index);
+        if partial.pieces.len() != usize::from(count) || partial.pieces[slot].is_some() {
+            self.stats.dropped_invalid += 1;
+            return Err(ProtocolError::Malformed("conflicting fragment"));
+        }
+        partial.received += 1;
+        partial.len += piece.len();
+        partial.cost += cost;
+        self.held += cost;
+        partial.pieces[slot] = Some(piece);
+        if partial.received < partial.pieces.len() {
+            return Ok(None);
+        }
+        let partial = self.pending.remove(&id).expect("partial frame is pending");
+        self.held -= partial.cost;
+        let mut frame = BytesMut::with_capacity(partial.len);
+        for piece in partial.pieces.into_iter().flatten() {
+            frame.put_slice(&piece);
+        }
+        self.stats.reassembled += 1;
+        Ok(Some(frame.freeze()))
+    }
+
+    /// Drop frames whose first piece arrived more than the timeout ago.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let (held, stats) = (&mut self.held, &mut self.stats);
+        self.pending.retain(|_, partial| {
+            if now.duration_since(partial.started) < timeout {
+                return true;
+            }
+            *held -= partial.cost;
+            stats.dropped_timeout += partial.received as u64;
+            false
+        });
+        self.next_sweep = now + timeout / 2;
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+}
+
+/// Counters of a [`Reassembler`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReassemblyStats {
+    /// Fragments received.
+    pub received: u64,
+    /// Frames put back together.
+    pub reassembled: u64,
+    /// Fragments dropped because the rest of their frame did not arrive in
+    /// time.
+    pub dropped_timeout: u64,
+    /// Fragments dropped because incomplete frames held too much memory, or
+    /// too many frames were incomplete.
+    pub dropped_memory: u64,
+    /// Fragments dropped as malformed or conflicting.
+    pub dropped_invalid: u64,
+}
+
+impl ReassemblyStats {
+    /// All fragments dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped_timeout + self.dropped_memory + self.dropped_invalid
+    }
+}
+
+impl fmt::Display for ReassemblyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} fragments received, {} frames reassembled, dropped {} timed out, \
+             {} over the memory cap, {} invalid",
+            self.received,
+            self.reassembled,
+            self.dropped_timeout,
+            self.dropped_memory,
+            self.dropped_invalid
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Fragment payloads of `frame`, as the receiver sees them.
+    fn payloads(frame: &[u8], id: u32, max_size: usize) -> Vec<Bytes> {
+        split(frame, id, max_size)
+            .expect("frame fits in fragments")
+            .into_iter()
+            .map(|wire| wire.slice(HEADER_LEN..))
+            .collect()
+    }
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let frame = frame(1000);
+        let pieces = payloads(&frame, 7, 300);
+        assert_eq!(pieces.len(), 4);
+        let mut reassembler = Reassembler::new(TIMEOUT, 1 << 20);
+        for piece in pieces[1..].iter().rev() {
+            assert_eq!(reassembler.push(piece.clone()).unwrap(), None);
+        }
+        let out = reassembler.push(pieces[0].clone()).unwrap();
+        assert_eq!(out.as_deref(), Some(&frame[..]));
+        assert_eq!(reassembler.stats().reassembled, 1);
+        assert_eq!(reassembler.held, 0);
+    }
+
+    #[test]
+    fn interleaved_frames_stay_apart() {
+        let (a, b) = (frame(700), vec![0xab; 500]);
+        let (pa, pb) = (payloads(&a, 1, 300), payloads(&b, 2, 300));
+        let mut reassembler = Reassembler::new(TIMEOUT, 1 << 20);
+        assert_eq!(reassembler.push(pa[0].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(pb[1].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(pa[2].clone()).unwrap(), None);
+        assert_eq!(
+            reassembler.push(pb[0].clone()).unwrap().as_deref(),
+            Some(&b[..])
+        );
+        assert_eq!(
+            reassembler.push(pa[1].clone()).unwrap().as_deref(),
+            Some(&a[..])
+        );
+    }
+
+    #[test]
+    fn duplicate_piece_is_dropped() {
+        let frame = frame(600);
+        let pieces = payloads(&frame, 3, 300);
+        let mut reassembler = Reassembler::new(TIMEOUT, 1 << 20);
+        assert_eq!(reassembler.push(pieces[0].clone()).unwrap(), None);
+        assert!(reassembler.push(pieces[0].clone()).is_err());
+        assert_eq!(reassembler.stats().dropped_invalid, 1);
+        for piece in &pieces[1..pieces.len() - 1] {
+            assert_eq!(reassembler.push(piece.clone()).unwrap(), None);
+        }
+        let out = reassembler.push(pieces[pieces.len() - 1].clone()).unwrap();
+        assert_eq!(out.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn conflicting_count_is_dropped() {
+        let mut reassembler = Reassembler::new(TIMEOUT, 1 << 20);
+        let first = payloads(&frame(600), 9, 300);
+        let other = payloads(&frame(1000), 9, 300);
+        assert_ne!(first.len(), other.len());
+        assert_eq!(reassembler.push(first[0].clone()).unwrap(), None);
+        assert!(reassembler.push(other[1].clone()).is_err());
+        assert_eq!(reassembler.stats().dropped_invalid, 1);
+    }
+
+    #[test]
+    fn malformed_fragments_are_rejected() {
+        let mut reassembler = Reassembler::new(TIMEOUT, 1 << 20);
+        // Too short, index past the count, no count, and no data
+        for payload in [
+            &[0, 0, 0, 1, 0][..],
+            &[0, 0, 0, 1, 2, 2, 0xff],
+            &[0, 0, 0, 1, 0, 0, 0xff],
+            &[0, 0, 0, 1, 0, 1],
+        ] {
+            assert!(reassembler.push(Bytes::copy_from_slice(payload)).is_err());
+        }
+        assert_eq!(reassembler.stats().dropped_invalid, 4);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn memory_cap_drops_pieces() {
+        let pieces = payloads(&frame(1000), 1, 300);
+        let piece_len = pieces[0].len() - FRAGMENT_PREFIX_LEN;
+        // Room for the bookkeeping of one frame and one of its pieces
+        let max_held = partial_cost(pieces.len()) + piece_len;
+        let mut reassembler = Reassembler::new(TIMEOUT, max_held);
+        assert_eq!(reassembler.push(pieces[0].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(pieces[1].clone()).unwrap(), None);
+        let other = payloads(&frame(100), 2, 300);
+        assert_eq!(reassembler.push(other[0].clone()).unwrap(), None);
+        let stats = reassembler.stats();
+        assert_eq!(stats.dropped_memory, 2);
+        assert_eq!(stats.reassembled, 0);
+        assert_eq!(reassembler.held, max_held);
+    }
+
+    #[test]
+    fn pending_frames_are_capped() {
+        let mut reassembler = Reassembler::new(TIMEOUT, usize::MAX);
+        for id in 0..=MAX_PENDING as u32 {
+            let pieces = payloads(&frame(600), id, 300);
+            assert_eq!(reassembler.push(pieces[0].clone()).unwrap(), None);
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+        assert_eq!(reassembler.stats().dropped_memory, 1);
+    }
+
+    #[test]
+    fn incomplete_frames_expire() {
+        let timeout = Duration::from_millis(20);
+        let mut reassembler = Reassembler::new(timeout, 1 << 20);
+        let stale = payloads(&frame(600), 1, 300);
+        assert_eq!(reassembler.push(stale[0].clone()).unwrap(), None);
+        std::thread::sleep(timeout * 2);
+        let fresh = payloads(&frame(100), 2, 300);
+        assert!(reassembler.push(fresh[0].clone()).unwrap().is_some());
+        assert_eq!(reassembler.stats().dropped_timeout, 1);
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.held, 0);
+    }
+}
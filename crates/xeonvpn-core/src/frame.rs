@@ -0,0 +1,604 @@
+//! Tunnel frame codec shared by the client, the server and any tooling that
+//! speaks the XeonVPN wire protocol.
+//!
+//! Every frame starts with an 8-byte header:
+//!
+//! ```text
+//! +--------+--------+---------+--------+------------------+
+//! | 'X'    | 'V'    | version | type   | length (u32, BE) |
+//! +--------+--------+---------+--------+------------------+
+//! ```
+//!
+//! followed by `length` bytes of type-specific payload.
+
+use crate::buffer::PacketPool;
+use bytes::Bytes;
+use std::{fmt, future::Future, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Two-byte magic at the start of every frame.
+pub const MAGIC: [u8; 2] = *b"XV";
+/// Wire protocol version emitted by this build.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Size of the fixed frame header in bytes.
+pub const HEADER_LEN: usize = 8;
+/// Largest payload accepted in a single frame.
+pub const MAX_PAYLOAD: usize = 65_535;
+
+pub(crate) const TYPE_DATA: u8 = 0x00;
+const TYPE_PING: u8 = 0x01;
+const TYPE_PONG: u8 = 0x02;
+const TYPE_CONTROL: u8 = 0x03;
+const TYPE_CLOSE: u8 = 0x04;
+pub(crate) const TYPE_OFFLOAD: u8 = 0x05;
+pub(crate) const TYPE_BATCH: u8 = 0x06;
+pub(crate) const TYPE_COMPRESSED: u8 = 0x07;
+pub(crate) const TYPE_FRAGMENT: u8 = 0x08;
+const TYPE_PROBE: u8 = 0x09;
+
+/// A single typed tunnel frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// One raw IP packet.
+    Data(Bytes),
+    /// Liveness probe carrying an opaque nonce.
+    Ping(u64),
+    /// Reply to a [`Frame::Ping`] or [`Frame::Probe`] echoing its nonce.
+    Pong(u64),
+    /// Control-plane message; the payload is interpreted by the session layer.
+    Control(Vec<u8>),
+    /// Shutdown of the tunnel with a [`CloseCode`] and reason.
+    ///
+    /// [`CloseCode`]: crate::close::CloseCode
+    Close { code: u16, reason: String },
+    /// An IP packet preceded by its 10-byte virtio-net header, which may be
+    /// a TCP super-segment still to be segmented. Only sent to peers that
+    /// negotiated offloads.
+    Offload(Bytes),
+    /// Several IP packets, each prefixed with its length; see
+    /// [`crate::batch`]. Only sent to peers that negotiated batching.
+    Batch(Bytes),
+    /// One IP packet compressed with the session's algorithm; see
+    /// [`crate::compress`]. Only sent to peers that negotiated compression.
+    Compressed(Bytes),
+    /// A piece of a frame too big for one datagram; see [`crate::fragment`].
+    /// Only sent as a datagram, to peers that negotiated fragmentation.
+    Fragment(Bytes),
+    /// Path MTU probe: a nonce followed by `padding` zero bytes, so the
+    /// datagram carrying it has the size being tested. Only sent as a
+    /// datagram, to peers that negotiated probes; they answer with a
+    /// [`Frame::Pong`].
+    Probe { nonce: u64, padding: usize },
+}
+
+impl Frame {
+    fn type_byte(&self) -> u8 {
+        match self {
+            Frame::Data(_) => TYPE_DATA,
+            Frame::Ping(_) => TYPE_PING,
+            Frame::Pong(_) => TYPE_PONG,
+            Frame::Control(_) => TYPE_CONTROL,
+            Frame::Close { .. } => TYPE_CLOSE,
+            Frame::Offload(_) => TYPE_OFFLOAD,
+            Frame::Batch(_) => TYPE_BATCH,
+            Frame::Compressed(_) => TYPE_COMPRESSED,
+            Frame::Fragment(_) => TYPE_FRAGMENT,
+            Frame::Probe { .. } => TYPE_PROBE,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Frame::Data(p)
+            | Frame::Offload(p)
+            | Frame::Batch(p)
+            | Frame::Compressed(p)
+            | Frame::Fragment(p) => p.len(),
+            Frame::Control(p) => p.len(),
+            Frame::Ping(_) | Frame::Pong(_) => 8,
+            Frame::Close { reason, .. } => 2 + reason.len(),
+            Frame::Probe { padding, .. } => 8 + padding,
+        }
+    }
+
+    /// Number of bytes this frame occupies on the wire.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload_len()
+    }
+
+    /// Append the wire encoding of this frame to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), ProtocolError> {
+        let len = self.payload_len();
+        if len > MAX_PAYLOAD {
+            return Err(ProtocolError::FrameTooLarge(len));
+        }
+        buf.reserve(HEADER_LEN + len);
+        let mut header = [0u8; HEADER_LEN];
+        write_header(&mut header, self.type_byte(), len);
+        buf.extend_from_slice(&header);
+        match self {
+            Frame::Data(p)
+            | Frame::Offload(p)
+            | Frame::Batch(p)
+            | Frame::Compressed(p)
+            | Frame::Fragment(p) => buf.extend_from_slice(p),
+            Frame::Control(p) => buf.extend_from_slice(p),
+            Frame::Ping(n) | Frame::Pong(n) => buf.extend_from_slice(&n.to_be_bytes()),
+            Frame::Close { code, reason } => {
+                buf.extend_from_slice(&code.to_be_bytes());
+                buf.extend_from_slice(reason.as_bytes());
+            }
+            Frame::Probe { nonce, padding } => {
+                buf.extend_from_slice(&nonce.to_be_bytes());
+                buf.resize(buf.len() + padding, 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode this frame into a standalone buffer.
+    pub fn to_bytes(&self) -> Result<Bytes, ProtocolError> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut buf)?;
+        Ok(buf.into())
+    }
+
+    /// Decode one frame from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` does not yet hold a complete frame, otherwise
+    /// the frame and the number of bytes it consumed.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>, ProtocolError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = Header::parse(buf[..HEADER_LEN].try_into().unwrap())?;
+        let end = HEADER_LEN + header.len;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let payload = Bytes::copy_from_slice(&buf[HEADER_LEN..end]);
+        let frame = Frame::from_parts(header.ty, payload)?;
+        Ok(Some((frame, end)))
+    }
+
+    /// Like [`Frame::decode`], but a data frame's payload shares `buf`'s
+    /// memory instead of being copied.
+    pub fn decode_bytes(buf: &Bytes) -> Result<Option<(Frame, usize)>, ProtocolError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = Header::parse(buf[..HEADER_LEN].try_into().unwrap())?;
+        let end = HEADER_LEN + header.len;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let frame = Frame::from_parts(header.ty, buf.slice(HEADER_LEN..end))?;
+        Ok(Some((frame, end)))
+    }
+
+    fn from_parts(ty: u8, payload: Bytes) -> Result<Frame, ProtocolError> {
+        match ty {
+            TYPE_DATA => Ok(Frame::Data(payload)),
+            TYPE_OFFLOAD => Ok(Frame::Offload(payload)),
+            TYPE_BATCH => Ok(Frame::Batch(payload)),
+            TYPE_COMPRESSED => Ok(Frame::Compressed(payload)),
+            TYPE_FRAGMENT => Ok(Frame::Fragment(payload)),
+            TYPE_CONTROL => Ok(Frame::Control(payload.into())),
+            TYPE_PING | TYPE_PONG => {
+                let nonce: [u8; 8] = payload
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| ProtocolError::Malformed("ping/pong payload must be 8 bytes"))?;
+                let nonce = u64::from_be_bytes(nonce);
+                Ok(if ty == TYPE_PING {
+                    Frame::Ping(nonce)
+                } else {
+                    Frame::Pong(nonce)
+                })
+            }
+            TYPE_CLOSE => {
+                if payload.len() < 2 {
+                    return Err(ProtocolError::Malformed("close payload shorter than code"));
+                }
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = String::from_utf8(payload[2..].to_vec())
+                    .map_err(|_| ProtocolError::Malformed("close reason is not UTF-8"))?;
+                Ok(Frame::Close { code, reason })
+            }
+            TYPE_PROBE => {
+                let Some((nonce, padding)) = payload.split_first_chunk::<8>() else {
+                    return Err(ProtocolError::Malformed("probe payload shorter than nonce"));
+                };
+                Ok(Frame::Probe {
+                    nonce: u64::from_be_bytes(*nonce),
+                    padding: padding.len(),
+                })
+            }
+            other => Err(ProtocolError::UnknownFrameType(other)),
+        }
+    }
+}
+
+/// Write a frame header for a payload of `len` bytes into `out[..HEADER_LEN]`.
+pub(crate) fn write_header(out: &mut [u8], ty: u8, len: usize) {
+    out[0..2].copy_from_slice(&MAGIC);
+    out[2] = PROTOCOL_VERSION;
+    out[3] = ty;
+    out[4..8].copy_from_slice(&(len as u32).to_be_bytes());
+}
+
+struct Header {
+    ty: u8,
+    len: usize,
+}
+
+impl Header {
+    fn parse(hdr: [u8; HEADER_LEN]) -> Result<Header, ProtocolError> {
+        if hdr[0..2] != MAGIC {
+            return Err(ProtocolError::BadMagic([hdr[0], hdr[1]]));
+        }
+        if hdr[2] != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(hdr[2]));
+        }
+        let len = u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(ProtocolError::FrameTooLarge(len));
+        }
+        Ok(Header { ty: hdr[3], len })
+    }
+}
+
+/// Errors raised while encoding or decoding frames.
+///
+/// Any error other than [`ProtocolError::Io`] means the byte stream can no
+/// longer be trusted to be aligned on a frame boundary, so the stream must be
+/// torn down rather than resumed.
+#[derive(Debug)]
+pub enum ProtocolError {
+    BadMagic([u8; 2]),
+    UnsupportedVersion(u8),
+    UnknownFrameType(u8),
+    FrameTooLarge(usize),
+    Malformed(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic(m) => write!(f, "bad frame magic {m:02x?}"),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            ProtocolError::UnknownFrameType(t) => write!(f, "unknown frame type 0x{t:02x}"),
+            ProtocolError::FrameTooLarge(n) => {
+                write!(
+                    f,
+                    "frame payload of {n} bytes exceeds limit of {MAX_PAYLOAD}"
+                )
+            }
+            ProtocolError::Malformed(what) => write!(f, "malformed frame: {what}"),
+            ProtocolError::Io(e) => write!(f, "frame I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+/// Read typed frames from any async byte stream.
+pub trait FrameRead {
+    /// Read the next frame. Returns `Ok(None)` on a clean end of stream at a
+    /// frame boundary.
+    fn read_frame(&mut self) -> impl Future<Output = Result<Option<Frame>, ProtocolError>> + Send;
+
+    /// Like [`FrameRead::read_frame`], but packet payloads (data and offload
+    /// frames) that fit are read into buffers from `pool` instead of a fresh
+    /// allocation.
+    fn read_frame_in(
+        &mut self,
+        pool: &mut PacketPool,
+    ) -> impl Future<Output = Result<Option<Frame>, ProtocolError>> + Send;
+}
+
+impl<R: AsyncRead + Unpin + Send + ?Sized> FrameRead for R {
+    async fn read_frame(&mut self) -> Result<Option<Frame>, ProtocolError> {
+        let Some(header) = read_header(self).await? else {
+            return Ok(None);
+        };
+        let mut payload = vec![0u8; header.len];
+        self.read_exact(&mut payload).await?;
+        Frame::from_parts(header.ty, payload.into()).map(Some)
+    }
+
+    async fn read_frame_in(
+        &mut self,
+        pool: &mut PacketPool,
+    ) -> Result<Option<Frame>, ProtocolError> {
+        let Some(header) = read_header(self).await? else {
+            return Ok(None);
+        };
+        if !matches!(header.ty, TYPE_DATA | TYPE_OFFLOAD) || header.len > pool.buf_len() {
+            let mut payload = vec![0u8; header.len];
+            self.read_exact(&mut payload).await?;
+            return Frame::from_parts(header.ty, payload.into()).map(Some);
+        }
+        let mut buf = pool.take();
+        self.read_exact(&mut buf.space_mut()[..header.len]).await?;
+        buf.truncate(header.len);
+        Frame::from_parts(header.ty, buf.freeze()).map(Some)
+    }
+}
+
+async fn read_header<R: AsyncRead + Unpin + Send + ?Sized>(
+    reader: &mut R,
+) -> Result<Option<Header>, ProtocolError> {
+    let mut hdr = [0u8; HEADER_LEN];
+    let first = reader.read(&mut hdr).await?;
+    if first == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut hdr[first..]).await?;
+    Header::parse(hdr).map(Some)
+}
+
+/// Write typed frames to any async byte stream.
+pub trait FrameWrite {
+    /// Encode and write one frame in a single write call.
+    fn write_frame(
+        &mut self,
+        frame: &Frame,
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send;
+}
+
+impl<W: AsyncWrite + Unpin + Send + ?Sized> FrameWrite for W {
+    async fn write_frame(&mut self, frame: &Frame) -> Result<(), ProtocolError> {
+        let mut buf = Vec::with_capacity(frame.encoded_len());
+        frame.encode(&mut buf)?;
+        self.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn every_variant() -> Vec<Frame> {
+        vec![
+            Frame::Data(Bytes::from_static(&[0x45, 0, 0, 20, 1, 2, 3])),
+            Frame::Data(Bytes::new()),
+            Frame::Ping(0x0102_0304_0506_0708),
+            Frame::Pong(u64::MAX),
+            Frame::Control(br#"{"type":"hello"}"#.to_vec()),
+            Frame::Close {
+                code: 2,
+                reason: "authentication failed ✗".into(),
+            },
+            Frame::Close {
+                code: 0,
+                reason: String::new(),
+            },
+            Frame::Offload(Bytes::from(vec![7u8; 1000])),
+            Frame::Batch(Bytes::from_static(&[0, 2, 0x45, 0])),
+            Frame::Compressed(Bytes::from_static(b"\x04\x22\x4d\x18")),
+            Frame::Fragment(Bytes::from_static(&[0, 0, 0, 1, 0, 2, 9, 9])),
+            Frame::Probe {
+                nonce: 3,
+                padding: 0,
+            },
+            Frame::Probe {
+                nonce: 4,
+                padding: 1400,
+            },
+            Frame::Data(Bytes::from(vec![0xab; MAX_PAYLOAD])),
+        ]
+    }
+
+    /// A frame header with the given fields.
+    fn header(magic: [u8; 2], version: u8, ty: u8, len: u32) -> Vec<u8> {
+        let mut hdr = magic.to_vec();
+        hdr.extend_from_slice(&[version, ty]);
+        hdr.extend_from_slice(&len.to_be_bytes());
+        hdr
+    }
+
+    #[test]
+    fn encodes_the_documented_layout() {
+        let wire = Frame::Ping(0x0102_0304_0506_0708).to_bytes().unwrap();
+        assert_eq!(
+            &wire[..],
+            b"XV\x01\x01\x00\x00\x00\x08\x01\x02\x03\x04\x05\x06\x07\x08"
+        );
+        let wire = Frame::Close {
+            code: 0x0203,
+            reason: "bye".into(),
+        }
+        .to_bytes()
+        .unwrap();
+        assert_eq!(&wire[..], b"XV\x01\x04\x00\x00\x00\x05\x02\x03bye");
+        let wire = Frame::Probe {
+            nonce: 1,
+            padding: 2,
+        }
+        .to_bytes()
+        .unwrap();
+        assert_eq!(
+            &wire[..],
+            b"XV\x01\x09\x00\x00\x00\x0a\0\0\0\0\0\0\0\x01\0\0"
+        );
+    }
+
+    #[test]
+    fn round_trips_every_variant() {
+        for frame in every_variant() {
+            let wire = frame.to_bytes().unwrap();
+            assert_eq!(wire.len(), frame.encoded_len());
+            assert_eq!(
+                Frame::decode(&wire).unwrap(),
+                Some((frame.clone(), wire.len()))
+            );
+            assert_eq!(
+                Frame::decode_bytes(&wire).unwrap(),
+                Some((frame.clone(), wire.len()))
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_over_a_stream() {
+        let frames = every_variant();
+        let mut wire = Vec::new();
+        block_on(async {
+            for frame in &frames {
+                wire.write_frame(frame).await.unwrap();
+            }
+        });
+
+        let mut reader = &wire[..];
+        let read = block_on(async {
+            let mut read = Vec::new();
+            while let Some(frame) = reader.read_frame().await.unwrap() {
+                read.push(frame);
+            }
+            read
+        });
+        assert_eq!(read, frames);
+
+        // Into pooled buffers, also for payloads too big for them
+        let mut reader = &wire[..];
+        let mut pool = PacketPool::new(1500);
+        let read = block_on(async {
+            let mut read = Vec::new();
+            while let Some(frame) = reader.read_frame_in(&mut pool).await.unwrap() {
+                read.push(frame);
+            }
+            read
+        });
+        assert_eq!(read, frames);
+    }
+
+    #[test]
+    fn decodes_consecutive_frames() {
+        let mut wire = Vec::new();
+        Frame::Ping(1).encode(&mut wire).unwrap();
+        Frame::Data(Bytes::from_static(b"abc"))
+            .encode(&mut wire)
+            .unwrap();
+        let (first, used) = Frame::decode(&wire).unwrap().unwrap();
+        assert_eq!((first, used), (Frame::Ping(1), 16));
+        let (second, rest) = Frame::decode(&wire[used..]).unwrap().unwrap();
+        assert_eq!(second, Frame::Data(Bytes::from_static(b"abc")));
+        assert_eq!(used + rest, wire.len());
+    }
+
+    #[test]
+    fn waits_for_incomplete_frames() {
+        let wire = Frame::Control(b"{}".to_vec()).to_bytes().unwrap();
+        for end in 0..wire.len() {
+            assert_eq!(Frame::decode(&wire[..end]).unwrap(), None, "{end} bytes");
+            assert_eq!(Frame::decode_bytes(&wire.slice(..end)).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let bad_magic = header(*b"XW", PROTOCOL_VERSION, TYPE_DATA, 0);
+        assert!(matches!(
+            Frame::decode(&bad_magic),
+            Err(ProtocolError::BadMagic([b'X', b'W']))
+        ));
+        let bad_version = header(MAGIC, 2, TYPE_DATA, 0);
+        assert!(matches!(
+            Frame::decode(&bad_version),
+            Err(ProtocolError::UnsupportedVersion(2))
+        ));
+        let unknown = header(MAGIC, PROTOCOL_VERSION, 0x7f, 0);
+        assert!(matches!(
+            Frame::decode(&unknown),
+            Err(ProtocolError::UnknownFrameType(0x7f))
+        ));
+        // Refused from the header alone, before the payload arrives
+        let too_large = header(MAGIC, PROTOCOL_VERSION, TYPE_DATA, MAX_PAYLOAD as u32 + 1);
+        assert!(matches!(
+            Frame::decode(&too_large),
+            Err(ProtocolError::FrameTooLarge(n)) if n == MAX_PAYLOAD + 1
+        ));
+        let huge = header(MAGIC, PROTOCOL_VERSION, TYPE_DATA, u32::MAX);
+        assert!(matches!(
+            Frame::decode_bytes(&huge.into()),
+            Err(ProtocolError::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let with_payload = |ty, payload: &[u8]| {
+            let mut wire = header(MAGIC, PROTOCOL_VERSION, ty, payload.len() as u32);
+            wire.extend_from_slice(payload);
+            Frame::decode(&wire)
+        };
+        for (ty, payload) in [
+            (TYPE_PING, &[0u8; 7][..]),
+            (TYPE_PONG, &[0u8; 9][..]),
+            (TYPE_CLOSE, &[0u8][..]),
+            (TYPE_CLOSE, &[0, 1, 0xff, 0xfe][..]),
+            (TYPE_PROBE, &[0u8; 7][..]),
+        ] {
+            assert!(
+                matches!(with_payload(ty, payload), Err(ProtocolError::Malformed(_))),
+                "type {ty} with {payload:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_oversized_payloads() {
+        let frame = Frame::Data(Bytes::from(vec![0; MAX_PAYLOAD + 1]));
+        assert!(matches!(
+            frame.to_bytes(),
+            Err(ProtocolError::FrameTooLarge(n)) if n == MAX_PAYLOAD + 1
+        ));
+        let mut buf = Vec::new();
+        assert!(block_on(buf.write_frame(&frame)).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn stream_errors() {
+        // A clean end only between frames
+        assert!(block_on((&[][..]).read_frame()).unwrap().is_none());
+        let wire = Frame::Ping(5).to_bytes().unwrap();
+        for end in [3, HEADER_LEN, wire.len() - 1] {
+            let mut reader = &wire[..end];
+            match block_on(reader.read_frame()) {
+                Err(ProtocolError::Io(e)) => {
+                    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "{end} bytes")
+                }
+                other => panic!("{end} bytes: {other:?}"),
+            }
+        }
+        let mut reader = &header(MAGIC, 9, TYPE_PING, 8)[..];
+        assert!(matches!(
+            block_on(reader.read_frame()),
+            Err(ProtocolError::UnsupportedVersion(9))
+        ));
+    }
+}
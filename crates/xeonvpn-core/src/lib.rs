@@ -1,3 +1,12 @@
+pub mod batch;
+pub mod buffer;
+pub mod close;
+pub mod compress;
+pub mod control;
+pub mod fragment;
+pub mod frame;
+pub mod ratelimit;
+
 pub const VERSION: &str = "0.1.0";
 
 pub fn banner(component: &str) -> String {
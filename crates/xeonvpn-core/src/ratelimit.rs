@@ -0,0 +1,87 @@
+//! Per-session bandwidth limits.
+//!
+//! A [`RateLimiter`] is a token bucket that polices a packet stream: packets
+//! that find enough tokens pass, the rest are dropped and counted. Dropping
+//! rather than delaying keeps the forwarding paths free of timers and lets
+//! TCP inside the tunnel back off as it would on a slow link.
+
+use std::fmt;
+use std::time::Instant;
+
+/// Shortest burst the bucket holds, so a few full-size packets (or one
+/// offload segment batch) can always pass back to back.
+const MIN_BURST: u64 = 64 * 1024;
+
+/// Token bucket over bytes.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes added per second.
+    rate: u64,
+    /// Most bytes the bucket holds.
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    /// A limiter passing `bytes_per_sec` on average, with bursts of a
+    /// quarter second's worth.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let burst = (bytes_per_sec / 4).max(MIN_BURST);
+        Self {
+            rate: bytes_per_sec,
+            burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    /// A limiter for `kbps` kilobits per second.
+    pub fn from_kbps(kbps: u64) -> Self {
+        Self::new(kbps.saturating_mul(1000) / 8)
+    }
+
+    /// Take `len` bytes from the bucket. Returns `false` when the packet
+    /// is over the limit and must be dropped.
+    pub fn admit(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        let len = len as f64;
+        if self.tokens < len {
+            self.stats.dropped += 1;
+            self.stats.dropped_bytes += len as u64;
+            return false;
+        }
+        self.tokens -= len;
+        self.stats.passed += 1;
+        true
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+}
+
+/// Counters of a [`RateLimiter`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateLimitStats {
+    /// Packets within the limit.
+    pub passed: u64,
+    /// Packets dropped for exceeding it.
+    pub dropped: u64,
+    pub dropped_bytes: u64,
+}
+
+impl fmt::Display for RateLimitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packets passed, {} dropped ({} bytes)",
+            self.passed, self.dropped, self.dropped_bytes
+        )
+    }
+}
@@ -2,8 +2,17 @@ pub fn init_networking() {
     // Placeholder: in the future, setup TUN/TAP, routing, DNS.
 }
 
+pub mod packet;
+#[cfg(target_os = "linux")]
+pub mod route;
 #[cfg(target_os = "linux")]
 pub mod tun;
+pub mod vnet;
 
 #[cfg(target_os = "linux")]
-pub use tun::{open_tun, open_tun_named, read_one_packet, run_tun_poc};
+pub use route::{apply_dns, apply_routes, remove_route, set_link_mtu, set_route_mtu};
+#[cfg(target_os = "linux")]
+pub use tun::{
+    is_fatal_read_error, open_tun, open_tun_named, open_tun_with, read_one_packet, run_tun_poc,
+    FlowWriter, TunDevice, TunOptions, TunReader, TunWriter, DEFAULT_MTU,
+};
@@ -0,0 +1,708 @@
+//! Minimal IPv4/IPv6 header inspection and ICMP error synthesis for packets
+//! read from or written to a TUN device.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const ICMP_HEADER_LEN: usize = 8;
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_ICMPV6: u8 = 58;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+const PROTO_ESP: u8 = 50;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_SYN: u8 = 0x02;
+const TCPOPT_EOL: u8 = 0;
+const TCPOPT_NOP: u8 = 1;
+const TCPOPT_MSS: u8 = 2;
+/// ICMPv4 errors are kept within the minimum reassembly size (RFC 1812).
+const ICMPV4_MAX_LEN: usize = 576;
+/// ICMPv6 errors must fit the IPv6 minimum MTU (RFC 4443).
+const ICMPV6_MAX_LEN: usize = 1280;
+
+/// IP version of a raw packet, if it looks like IPv4 or IPv6.
+pub fn version(pkt: &[u8]) -> Option<u8> {
+    match pkt.first()? >> 4 {
+        4 if pkt.len() >= IPV4_HEADER_LEN => Some(4),
+        6 if pkt.len() >= IPV6_HEADER_LEN => Some(6),
+        _ => None,
+    }
+}
+
+/// Source address of a raw IP packet.
+pub fn src_addr(pkt: &[u8]) -> Option<IpAddr> {
+    match version(pkt)? {
+        4 => Some(IpAddr::V4(ipv4_at(pkt, 12))),
+        _ => Some(IpAddr::V6(ipv6_at(pkt, 8))),
+    }
+}
+
+/// Destination address of a raw IP packet.
+pub fn dst_addr(pkt: &[u8]) -> Option<IpAddr> {
+    match version(pkt)? {
+        4 => Some(IpAddr::V4(ipv4_at(pkt, 16))),
+        _ => Some(IpAddr::V6(ipv6_at(pkt, 24))),
+    }
+}
+
+/// Whether `pkt` must not be fragmented on the way: IPv4 with the DF bit
+/// set, or any IPv6 packet, which only its sender may fragment.
+pub fn dont_fragment(pkt: &[u8]) -> bool {
+    match version(pkt) {
+        Some(4) => pkt[6] & 0x40 != 0,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+fn ipv4_at(pkt: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(pkt[at], pkt[at + 1], pkt[at + 2], pkt[at + 3])
+}
+
+fn ipv6_at(pkt: &[u8], at: usize) -> Ipv6Addr {
+    let octets: [u8; 16] = pkt[at..at + 16].try_into().unwrap();
+    Ipv6Addr::from(octets)
+}
+
+/// Protocol, addresses and ports of a packet, as firewall rules see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: u8,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Source and destination port of TCP and UDP packets; `None` for other
+    /// protocols and for fragments after the first, which carry no ports.
+    pub ports: Option<(u16, u16)>,
+}
+
+/// The [`Flow`] of a raw IP packet. IPv6 extension headers are not walked,
+/// so packets with them have no ports.
+pub fn flow(pkt: &[u8]) -> Option<Flow> {
+    let (protocol, l4) = match version(pkt)? {
+        4 => {
+            let ihl = usize::from(pkt[0] & 0x0f) * 4;
+            let first = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff == 0;
+            (pkt[9], (first && ihl >= IPV4_HEADER_LEN).then_some(ihl))
+        }
+        _ => (pkt[6], Some(IPV6_HEADER_LEN)),
+    };
+    let ports = match (protocol, l4) {
+        (PROTO_TCP | PROTO_UDP, Some(at)) => pkt.get(at..at + 4).map(|p| {
+            (
+                u16::from_be_bytes([p[0], p[1]]),
+                u16::from_be_bytes([p[2], p[3]]),
+            )
+        }),
+        _ => None,
+    };
+    Some(Flow {
+        protocol,
+        src: src_addr(pkt)?,
+        dst: dst_addr(pkt)?,
+        ports,
+    })
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            PROTO_TCP => write!(f, "tcp ")?,
+            PROTO_UDP => write!(f, "udp ")?,
+            PROTO_ICMP => write!(f, "icmp ")?,
+            PROTO_ICMPV6 => write!(f, "icmpv6 ")?,
+            other => write!(f, "protocol {other} ")?,
+        }
+        match self.ports {
+            Some((src, dst)) => write!(
+                f,
+                "{} -> {}",
+                SocketAddr::new(self.src, src),
+                SocketAddr::new(self.dst, dst)
+            ),
+            None => write!(f, "{} -> {}", self.src, self.dst),
+        }
+    }
+}
+
+/// Hash of a packet's flow: protocol, addresses and, for unfragmented TCP
+/// and UDP, ports. Both directions of a flow hash the same, so replies can
+/// be steered to the queue that carried the request.
+///
+/// Fragments hash by protocol and addresses only, keeping every fragment of
+/// a datagram with its first one.
+pub fn flow_hash(pkt: &[u8]) -> Option<u32> {
+    let (proto, src, dst, l4) = match version(pkt)? {
+        4 => {
+            let ihl = usize::from(pkt[0] & 0x0f) * 4;
+            let fragmented = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x3fff != 0;
+            let l4 = (!fragmented && ihl >= IPV4_HEADER_LEN).then_some(ihl);
+            (pkt[9], &pkt[12..16], &pkt[16..20], l4)
+        }
+        // Extension headers are not walked; such packets hash by address
+        _ => (pkt[6], &pkt[8..24], &pkt[24..40], Some(IPV6_HEADER_LEN)),
+    };
+    let ports = match (proto, l4) {
+        (PROTO_TCP | PROTO_UDP, Some(at)) => pkt.get(at..at + 4),
+        _ => None,
+    };
+    let (a, b) = match ports {
+        Some(p) => ((src, &p[..2]), (dst, &p[2..])),
+        None => ((src, &[][..]), (dst, &[][..])),
+    };
+    // Order the endpoints so the hash is the same in both directions
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    // FNV-1a
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in [proto]
+        .iter()
+        .chain(lo.0)
+        .chain(lo.1)
+        .chain(hi.0)
+        .chain(hi.1)
+    {
+        hash = (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193);
+    }
+    Some(hash)
+}
+
+/// Well-known ports of protocols that encrypt their payload: SSH, HTTPS
+/// and HTTP/3, IKE and its NAT traversal, DNS over TLS or QUIC, OpenVPN and
+/// WireGuard.
+const ENCRYPTED_PORTS: [u16; 7] = [22, 443, 500, 853, 1194, 4500, 51820];
+
+/// Whether the payload of `pkt` is most likely encrypted, and so will not
+/// compress: IPsec ESP, or TCP and UDP to or from a port in
+/// [`ENCRYPTED_PORTS`]. Only looks at headers, so it is cheap enough to ask
+/// for every packet.
+pub fn likely_encrypted(pkt: &[u8]) -> bool {
+    let (proto, l4) = match version(pkt) {
+        Some(4) => {
+            let ihl = usize::from(pkt[0] & 0x0f) * 4;
+            let first = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff == 0;
+            (pkt[9], (first && ihl >= IPV4_HEADER_LEN).then_some(ihl))
+        }
+        // Extension headers are not walked
+        Some(_) => (pkt[6], Some(IPV6_HEADER_LEN)),
+        None => return false,
+    };
+    if proto == PROTO_ESP {
+        return true;
+    }
+    let ports = match (proto, l4) {
+        (PROTO_TCP | PROTO_UDP, Some(at)) => pkt.get(at..at + 4),
+        _ => None,
+    };
+    ports.is_some_and(|p| {
+        let src = u16::from_be_bytes([p[0], p[1]]);
+        let dst = u16::from_be_bytes([p[2], p[3]]);
+        ENCRYPTED_PORTS.contains(&src) || ENCRYPTED_PORTS.contains(&dst)
+    })
+}
+
+/// One's-complement sum over `data`, folded into a 16-bit checksum.
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([c[0], c[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Lower the MSS option of a TCP SYN or SYN-ACK in `pkt` so that its
+/// segments fit in packets of `mtu` bytes, fixing up the TCP checksum.
+/// Returns whether the packet was changed.
+///
+/// SYNs without an MSS option are left alone; their peers already assume
+/// the 536/1220-byte default.
+pub fn clamp_mss(pkt: &mut [u8], mtu: u16) -> bool {
+    let (l4, overhead) = match version(pkt) {
+        Some(4) => {
+            let ihl = usize::from(pkt[0] & 0x0f) * 4;
+            let first = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff == 0;
+            if pkt[9] != PROTO_TCP || !first || ihl < IPV4_HEADER_LEN {
+                return false;
+            }
+            (ihl, IPV4_HEADER_LEN + TCP_HEADER_LEN)
+        }
+        // Extension headers are not walked
+        Some(_) if pkt[6] == PROTO_TCP => (IPV6_HEADER_LEN, IPV6_HEADER_LEN + TCP_HEADER_LEN),
+        _ => return false,
+    };
+    let Some(tcp) = pkt.get_mut(l4..) else {
+        return false;
+    };
+    if tcp.len() < TCP_HEADER_LEN || tcp[13] & TCP_SYN == 0 {
+        return false;
+    }
+    let max = mtu.saturating_sub(overhead as u16);
+    let data_offset = (usize::from(tcp[12] >> 4) * 4).min(tcp.len());
+    let mut at = TCP_HEADER_LEN;
+    while at < data_offset {
+        match tcp[at] {
+            TCPOPT_EOL => break,
+            TCPOPT_NOP => at += 1,
+            kind => {
+                let len = usize::from(*tcp.get(at + 1).unwrap_or(&0));
+                if len < 2 || at + len > data_offset {
+                    return false;
+                }
+                if kind == TCPOPT_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[at + 2], tcp[at + 3]]);
+                    if mss <= max {
+                        return false;
+                    }
+                    tcp[at + 2..at + 4].copy_from_slice(&max.to_be_bytes());
+                    let sum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                    let sum = update_checksum(sum, mss, max);
+                    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+                    return true;
+                }
+                at += len;
+            }
+        }
+    }
+    false
+}
+
+/// Update a checksum for one 16-bit word changing from `old` to `new`
+/// (RFC 1624, eqn. 3).
+fn update_checksum(sum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!sum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Kind of ICMP error to synthesize for an undeliverable packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    /// Destination host is not reachable through the tunnel.
+    HostUnreachable,
+    /// The packet is larger than the tunnel MTU and must not be fragmented:
+    /// ICMPv4 "fragmentation needed" or ICMPv6 "packet too big".
+    TooBig { mtu: u16 },
+}
+
+/// Build an ICMPv4/ICMPv6 error about `orig`, sourced from `from`, to be
+/// written back towards the sender of `orig`.
+///
+/// Returns `None` where RFC 1122 / RFC 4443 forbid an error reply: for ICMP
+/// errors, non-initial fragments, multicast/broadcast traffic, or when `from`
+/// is not of the same family as `orig`.
+pub fn icmp_error(orig: &[u8], from: IpAddr, kind: IcmpError) -> Option<Vec<u8>> {
+    match (version(orig)?, from) {
+        (4, IpAddr::V4(from)) => icmpv4_error(orig, from, kind),
+        (6, IpAddr::V6(from)) => icmpv6_error(orig, from, kind),
+        _ => None,
+    }
+}
+
+fn icmpv4_error(orig: &[u8], from: Ipv4Addr, kind: IcmpError) -> Option<Vec<u8>> {
+    let src = ipv4_at(orig, 12);
+    let dst = ipv4_at(orig, 16);
+    if src.is_unspecified() || src.is_multicast() || src.is_broadcast() {
+        return None;
+    }
+    if dst.is_multicast() || dst.is_broadcast() {
+        return None;
+    }
+    // Only the first fragment of a datagram gets an error
+    if u16::from_be_bytes([orig[6], orig[7]]) & 0x1fff != 0 {
+        return None;
+    }
+    let ihl = usize::from(orig[0] & 0x0f) * 4;
+    if orig[9] == PROTO_ICMP {
+        // Never answer an ICMP error with another one
+        if matches!(orig.get(ihl), Some(3 | 4 | 5 | 11 | 12) | None) {
+            return None;
+        }
+    }
+
+    let (icmp_type, code, rest) = match kind {
+        IcmpError::HostUnreachable => (3u8, 1u8, [0; 4]),
+        // Next-hop MTU in the low half of the otherwise unused word (RFC 1191)
+        IcmpError::TooBig { mtu } => {
+            let [hi, lo] = mtu.to_be_bytes();
+            (3, 4, [0, 0, hi, lo])
+        }
+    };
+    let quoted = orig
+        .len()
+        .min(ICMPV4_MAX_LEN - IPV4_HEADER_LEN - ICMP_HEADER_LEN);
+    let total = IPV4_HEADER_LEN + ICMP_HEADER_LEN + quoted;
+
+    let mut out = Vec::with_capacity(total);
+    out.extend_from_slice(&[0x45, 0]);
+    out.extend_from_slice(&(total as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0, 64, PROTO_ICMP, 0, 0]);
+    out.extend_from_slice(&from.octets());
+    out.extend_from_slice(&src.octets());
+    let ip_sum = checksum(&out[..IPV4_HEADER_LEN], 0);
+    out[10..12].copy_from_slice(&ip_sum.to_be_bytes());
+
+    out.extend_from_slice(&[icmp_type, code, 0, 0]);
+    out.extend_from_slice(&rest);
+    out.extend_from_slice(&orig[..quoted]);
+    let icmp_sum = checksum(&out[IPV4_HEADER_LEN..], 0);
+    out[IPV4_HEADER_LEN + 2..IPV4_HEADER_LEN + 4].copy_from_slice(&icmp_sum.to_be_bytes());
+    Some(out)
+}
+
+fn icmpv6_error(orig: &[u8], from: Ipv6Addr, kind: IcmpError) -> Option<Vec<u8>> {
+    let src = ipv6_at(orig, 8);
+    let dst = ipv6_at(orig, 24);
+    // Packet Too Big is the one error also sent for multicast (RFC 4443 2.4)
+    let too_big = matches!(kind, IcmpError::TooBig { .. });
+    if src.is_unspecified() || src.is_multicast() || (dst.is_multicast() && !too_big) {
+        return None;
+    }
+    if orig[6] == PROTO_ICMPV6 {
+        // Error messages have types below 128
+        if matches!(orig.get(IPV6_HEADER_LEN), Some(t) if *t < 128) {
+            return None;
+        }
+    }
+
+    let (icmp_type, code, rest) = match kind {
+        IcmpError::HostUnreachable => (1u8, 3u8, [0; 4]),
+        IcmpError::TooBig { mtu } => (2, 0, u32::from(mtu).to_be_bytes()),
+    };
+    let quoted = orig
+        .len()
+        .min(ICMPV6_MAX_LEN - IPV6_HEADER_LEN - ICMP_HEADER_LEN);
+    let payload_len = ICMP_HEADER_LEN + quoted;
+
+    let mut out = Vec::with_capacity(IPV6_HEADER_LEN + payload_len);
+    out.extend_from_slice(&[0x60, 0, 0, 0]);
+    out.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    out.extend_from_slice(&[PROTO_ICMPV6, 64]);
+    out.extend_from_slice(&from.octets());
+    out.extend_from_slice(&src.octets());
+    out.extend_from_slice(&[icmp_type, code, 0, 0]);
+    out.extend_from_slice(&rest);
+    out.extend_from_slice(&orig[..quoted]);
+
+    // Checksum covers the pseudo-header: addresses, upper-layer length, next header
+    let mut pseudo = 0u32;
+    for c in out[8..IPV6_HEADER_LEN].chunks_exact(2) {
+        pseudo += u32::from(u16::from_be_bytes([c[0], c[1]]));
+    }
+    pseudo += payload_len as u32 + u32::from(PROTO_ICMPV6);
+    let icmp_sum = checksum(&out[IPV6_HEADER_LEN..], pseudo);
+    out[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&icmp_sum.to_be_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 123, 0, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const CLIENT6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x7b, 0, 0, 0, 0, 0, 2);
+    const REMOTE6: Ipv6Addr =
+        Ipv6Addr::new(0x2606, 0x2800, 0x220, 1, 0x248, 0x1893, 0x25c8, 0x1946);
+
+    /// One's-complement sum of `data` plus `initial`, folded, written out
+    /// independently of the code under test.
+    fn ones_sum(data: &[u8], initial: u32) -> u16 {
+        let mut sum = u64::from(initial);
+        for (i, byte) in data.iter().enumerate() {
+            sum += if i % 2 == 0 {
+                u64::from(*byte) << 8
+            } else {
+                u64::from(*byte)
+            };
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    fn pseudo_v4(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&src.octets());
+        pseudo.extend_from_slice(&dst.octets());
+        pseudo.extend_from_slice(&[0, proto]);
+        pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+        u32::from(ones_sum(&pseudo, 0))
+    }
+
+    fn pseudo_v6(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, len: usize) -> u32 {
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&src.octets());
+        pseudo.extend_from_slice(&dst.octets());
+        pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, proto]);
+        u32::from(ones_sum(&pseudo, 0))
+    }
+
+    /// Fill in the checksum at `at` in `data` from scratch.
+    fn set_checksum(data: &mut [u8], at: usize, pseudo: u32) {
+        data[at..at + 2].fill(0);
+        let sum = !ones_sum(data, pseudo);
+        data[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    fn ipv4(proto: u8, src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let total = IPV4_HEADER_LEN + payload.len();
+        let mut pkt = vec![0x45, 0];
+        pkt.extend_from_slice(&(total as u16).to_be_bytes());
+        pkt.extend_from_slice(&[0x12, 0x34, 0x40, 0, 64, proto, 0, 0]);
+        pkt.extend_from_slice(&src.octets());
+        pkt.extend_from_slice(&dst.octets());
+        set_checksum(&mut pkt, 10, 0);
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    fn ipv6(proto: u8, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0x60, 0, 0, 0];
+        pkt.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        pkt.extend_from_slice(&[proto, 64]);
+        pkt.extend_from_slice(&src.octets());
+        pkt.extend_from_slice(&dst.octets());
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    /// A SYN as Linux sends it: MSS, SACK permitted, timestamps and window
+    /// scale options.
+    fn syn(sport: u16, mss: u16) -> Vec<u8> {
+        let mut tcp = Vec::new();
+        tcp.extend_from_slice(&sport.to_be_bytes());
+        tcp.extend_from_slice(&443u16.to_be_bytes());
+        tcp.extend_from_slice(&0x5a3c_91e7u32.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(&[0xa0, TCP_SYN, 0xfa, 0xf0, 0, 0, 0, 0]);
+        tcp.extend_from_slice(&[TCPOPT_MSS, 4]);
+        tcp.extend_from_slice(&mss.to_be_bytes());
+        tcp.extend_from_slice(&[4, 2, 8, 10, 0x1b, 0x2e, 0x8a, 0x01, 0, 0, 0, 0]);
+        tcp.extend_from_slice(&[TCPOPT_NOP, 3, 3, 7]);
+        tcp
+    }
+
+    fn syn_v4(sport: u16, mss: u16) -> Vec<u8> {
+        let mut tcp = syn(sport, mss);
+        let pseudo = pseudo_v4(CLIENT, REMOTE, PROTO_TCP, tcp.len());
+        set_checksum(&mut tcp, 16, pseudo);
+        ipv4(PROTO_TCP, CLIENT, REMOTE, &tcp)
+    }
+
+    fn syn_v6(sport: u16, mss: u16) -> Vec<u8> {
+        let mut tcp = syn(sport, mss);
+        let pseudo = pseudo_v6(CLIENT6, REMOTE6, PROTO_TCP, tcp.len());
+        set_checksum(&mut tcp, 16, pseudo);
+        ipv6(PROTO_TCP, CLIENT6, REMOTE6, &tcp)
+    }
+
+    fn mss_of(pkt: &[u8], l4: usize) -> u16 {
+        u16::from_be_bytes([pkt[l4 + 22], pkt[l4 + 23]])
+    }
+
+    #[test]
+    fn checksum_matches_reference() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(1001).collect();
+        for len in [0, 1, 2, 3, 20, 999, 1001] {
+            assert_eq!(checksum(&data[..len], 0), !ones_sum(&data[..len], 0));
+            assert_eq!(
+                checksum(&data[..len], 0x1_fffe),
+                !ones_sum(&data[..len], 0x1_fffe)
+            );
+        }
+    }
+
+    #[test]
+    fn update_checksum_matches_recompute() {
+        let mut data: Vec<u8> = (0..64u8).map(|i| i.wrapping_mul(37)).collect();
+        // Cover the carries and the all-ones and zero words RFC 1624 is about
+        for (old, new) in [
+            (0x05b4, 0x0578),
+            (0xffff, 0),
+            (0, 0xffff),
+            (0x1234, 0x1234),
+            (1, 0xfffe),
+        ] {
+            data[10..12].copy_from_slice(&u16::to_be_bytes(old));
+            let before = !ones_sum(&data, 0);
+            data[10..12].copy_from_slice(&u16::to_be_bytes(new));
+            let updated = update_checksum(before, old, new);
+            // Both representations of zero verify the same way
+            let mut check = data.clone();
+            check.extend_from_slice(&updated.to_be_bytes());
+            assert_eq!(ones_sum(&check, 0), 0xffff, "{old:#06x} -> {new:#06x}");
+        }
+    }
+
+    #[test]
+    fn clamps_ipv4_syn() {
+        for sport in [40000, 51000, 60001, 65535] {
+            let mut pkt = syn_v4(sport, 1460);
+            let before = pkt.clone();
+            assert!(clamp_mss(&mut pkt, 1400));
+            assert_eq!(mss_of(&pkt, IPV4_HEADER_LEN), 1360);
+            // Only the MSS and the TCP checksum changed
+            let changed: Vec<_> = (0..pkt.len()).filter(|&i| pkt[i] != before[i]).collect();
+            assert!(
+                changed.iter().all(|i| [36, 37, 42, 43].contains(i)),
+                "{changed:?}"
+            );
+            let mut recomputed = pkt[IPV4_HEADER_LEN..].to_vec();
+            let pseudo = pseudo_v4(CLIENT, REMOTE, PROTO_TCP, recomputed.len());
+            set_checksum(&mut recomputed, 16, pseudo);
+            assert_eq!(&pkt[IPV4_HEADER_LEN..], &recomputed[..]);
+            assert_eq!(ones_sum(&pkt[..IPV4_HEADER_LEN], 0), 0xffff);
+        }
+    }
+
+    #[test]
+    fn clamps_ipv6_syn() {
+        let mut pkt = syn_v6(51000, 1440);
+        assert!(clamp_mss(&mut pkt, 1280));
+        assert_eq!(mss_of(&pkt, IPV6_HEADER_LEN), 1220);
+        let tcp = &pkt[IPV6_HEADER_LEN..];
+        let pseudo = pseudo_v6(CLIENT6, REMOTE6, PROTO_TCP, tcp.len());
+        assert_eq!(ones_sum(tcp, pseudo), 0xffff);
+    }
+
+    #[test]
+    fn leaves_other_packets_alone() {
+        // Already small enough
+        let mut pkt = syn_v4(51000, 1300);
+        assert!(!clamp_mss(&mut pkt, 1400));
+        assert_eq!(pkt, syn_v4(51000, 1300));
+
+        // Not a SYN
+        let mut pkt = syn_v4(51000, 1460);
+        pkt[IPV4_HEADER_LEN + 13] = 0x10;
+        assert!(!clamp_mss(&mut pkt, 1400));
+
+        // No MSS option
+        let mut pkt = syn_v4(51000, 1460);
+        pkt[IPV4_HEADER_LEN + 20..IPV4_HEADER_LEN + 24].fill(TCPOPT_NOP);
+        assert!(!clamp_mss(&mut pkt, 1400));
+
+        // An option running past the header
+        let mut pkt = syn_v4(51000, 1460);
+        pkt[IPV4_HEADER_LEN + 20..IPV4_HEADER_LEN + 22].copy_from_slice(&[TCPOPT_NOP, TCPOPT_NOP]);
+        pkt[IPV4_HEADER_LEN + 22..IPV4_HEADER_LEN + 24].copy_from_slice(&[8, 40]);
+        assert!(!clamp_mss(&mut pkt, 1400));
+
+        // A later fragment, whose payload only looks like a SYN
+        let mut pkt = syn_v4(51000, 1460);
+        pkt[6..8].copy_from_slice(&[0, 0x10]);
+        assert!(!clamp_mss(&mut pkt, 1400));
+
+        // Truncated
+        let mut pkt = syn_v4(51000, 1460)[..IPV4_HEADER_LEN + 10].to_vec();
+        assert!(!clamp_mss(&mut pkt, 1400));
+    }
+
+    #[test]
+    fn builds_fragmentation_needed() {
+        let udp: Vec<u8> = (0..1480u32).map(|i| i as u8).collect();
+        let orig = ipv4(PROTO_UDP, CLIENT, REMOTE, &udp);
+        let server = Ipv4Addr::new(10, 123, 0, 1);
+        let out = icmp_error(&orig, IpAddr::V4(server), IcmpError::TooBig { mtu: 1400 }).unwrap();
+
+        assert_eq!(out.len(), ICMPV4_MAX_LEN);
+        assert_eq!(out[0], 0x45);
+        assert_eq!(u16::from_be_bytes([out[2], out[3]]), ICMPV4_MAX_LEN as u16);
+        assert_eq!((out[8], out[9]), (64, PROTO_ICMP));
+        assert_eq!(ipv4_at(&out, 12), server);
+        assert_eq!(ipv4_at(&out, 16), CLIENT);
+        assert_eq!(ones_sum(&out[..IPV4_HEADER_LEN], 0), 0xffff);
+
+        let icmp = &out[IPV4_HEADER_LEN..];
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(&icmp[4..8], &[0, 0, 0x05, 0x78]);
+        assert_eq!(&icmp[8..], &orig[..ICMPV4_MAX_LEN - 28]);
+        assert_eq!(ones_sum(icmp, 0), 0xffff);
+    }
+
+    #[test]
+    fn builds_host_unreachable_quoting_small_packets_whole() {
+        let orig = syn_v4(51000, 1460);
+        let server = IpAddr::V4(Ipv4Addr::new(10, 123, 0, 1));
+        let out = icmp_error(&orig, server, IcmpError::HostUnreachable).unwrap();
+        assert_eq!(out.len(), 28 + orig.len());
+        assert_eq!((out[20], out[21]), (3, 1));
+        assert_eq!(&out[24..28], &[0; 4]);
+        assert_eq!(&out[28..], &orig[..]);
+        assert_eq!(ones_sum(&out[20..], 0), 0xffff);
+    }
+
+    #[test]
+    fn builds_packet_too_big() {
+        let payload: Vec<u8> = (0..1460u32).map(|i| (i * 7) as u8).collect();
+        let orig = ipv6(PROTO_UDP, CLIENT6, REMOTE6, &payload);
+        let server = Ipv6Addr::new(0xfd00, 0x7b, 0, 0, 0, 0, 0, 1);
+        let out = icmp_error(&orig, IpAddr::V6(server), IcmpError::TooBig { mtu: 1280 }).unwrap();
+
+        assert_eq!(out.len(), ICMPV6_MAX_LEN);
+        assert_eq!(&out[..4], &[0x60, 0, 0, 0]);
+        let payload_len = ICMPV6_MAX_LEN - IPV6_HEADER_LEN;
+        assert_eq!(
+            usize::from(u16::from_be_bytes([out[4], out[5]])),
+            payload_len
+        );
+        assert_eq!((out[6], out[7]), (PROTO_ICMPV6, 64));
+        assert_eq!(ipv6_at(&out, 8), server);
+        assert_eq!(ipv6_at(&out, 24), CLIENT6);
+
+        let icmp = &out[IPV6_HEADER_LEN..];
+        assert_eq!((icmp[0], icmp[1]), (2, 0));
+        assert_eq!(&icmp[4..8], &1280u32.to_be_bytes());
+        assert_eq!(&icmp[8..], &orig[..payload_len - ICMP_HEADER_LEN]);
+        let pseudo = pseudo_v6(server, CLIENT6, PROTO_ICMPV6, payload_len);
+        assert_eq!(ones_sum(icmp, pseudo), 0xffff);
+    }
+
+    #[test]
+    fn suppresses_forbidden_errors() {
+        let server4 = IpAddr::V4(Ipv4Addr::new(10, 123, 0, 1));
+        let server6 = IpAddr::V6(Ipv6Addr::new(0xfd00, 0x7b, 0, 0, 0, 0, 0, 1));
+        let unreachable = IcmpError::HostUnreachable;
+
+        // An ICMP error about an ICMP error
+        let icmp = [3, 1, 0, 0, 0, 0, 0, 0];
+        let orig = ipv4(PROTO_ICMP, CLIENT, REMOTE, &icmp);
+        assert_eq!(icmp_error(&orig, server4, unreachable), None);
+        // but echo requests are answered
+        let echo = [8, 0, 0, 0, 0, 1, 0, 1];
+        let orig = ipv4(PROTO_ICMP, CLIENT, REMOTE, &echo);
+        assert!(icmp_error(&orig, server4, unreachable).is_some());
+
+        // Later fragments, broadcast and multicast
+        let mut orig = ipv4(PROTO_UDP, CLIENT, REMOTE, &[0; 16]);
+        orig[6..8].copy_from_slice(&[0x20, 0x10]);
+        assert_eq!(icmp_error(&orig, server4, unreachable), None);
+        let orig = ipv4(PROTO_UDP, CLIENT, Ipv4Addr::new(224, 0, 0, 251), &[0; 16]);
+        assert_eq!(icmp_error(&orig, server4, unreachable), None);
+        let orig = ipv4(PROTO_UDP, CLIENT, Ipv4Addr::BROADCAST, &[0; 16]);
+        assert_eq!(icmp_error(&orig, server4, unreachable), None);
+
+        // Address family mismatch
+        let orig = ipv4(PROTO_UDP, CLIENT, REMOTE, &[0; 16]);
+        assert_eq!(icmp_error(&orig, server6, unreachable), None);
+
+        // ICMPv6 errors, and multicast other than for Packet Too Big
+        let orig = ipv6(PROTO_ICMPV6, CLIENT6, REMOTE6, &[1, 3, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(icmp_error(&orig, server6, unreachable), None);
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+        let orig = ipv6(PROTO_UDP, CLIENT6, group, &[0; 16]);
+        assert_eq!(icmp_error(&orig, server6, unreachable), None);
+        assert!(icmp_error(&orig, server6, IcmpError::TooBig { mtu: 1280 }).is_some());
+    }
+}
@@ -0,0 +1,85 @@
+//! Linux route and resolver setup for a TUN interface, driven by the
+//! parameters the server pushes during the session handshake.
+
+#![cfg(target_os = "linux")]
+
+use ipnet::IpNet;
+use std::{error::Error, net::IpAddr};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+pub(crate) async fn run(
+    program: &str,
+    args: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let out = Command::new(program).args(args).output().await?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        return Err(format!("{program} {} failed: {}", args.join(" "), stderr.trim()).into());
+    }
+    Ok(())
+}
+
+/// Route each destination in `routes` through the TUN device `dev`.
+pub async fn apply_routes(dev: &str, routes: &[IpNet]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for route in routes {
+        let family = if route.addr().is_ipv6() { "-6" } else { "-4" };
+        let args = [family, "route", "replace", &route.to_string(), "dev", dev].map(String::from);
+        run("ip", &args).await?;
+        info!("route {route} via {dev}");
+    }
+    Ok(())
+}
+
+/// Set the MTU of the interface `dev`.
+pub async fn set_link_mtu(dev: &str, mtu: u16) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = ["link", "set", "dev", dev, "mtu", &mtu.to_string()].map(String::from);
+    run("ip", &args).await
+}
+
+/// Route `dest` through `dev` with a path MTU of `mtu`. The kernel then
+/// answers larger packets for `dest` that must not be fragmented with
+/// "fragmentation needed" / "packet too big", and local TCP sizes its
+/// segments to fit.
+pub async fn set_route_mtu(
+    dev: &str,
+    dest: IpNet,
+    mtu: u16,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let family = if dest.addr().is_ipv6() { "-6" } else { "-4" };
+    let args = [
+        family,
+        "route",
+        "replace",
+        &dest.to_string(),
+        "dev",
+        dev,
+        "mtu",
+        &mtu.to_string(),
+    ]
+    .map(String::from);
+    run("ip", &args).await
+}
+
+/// Remove the route for `dest` through `dev`.
+pub async fn remove_route(dev: &str, dest: IpNet) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let family = if dest.addr().is_ipv6() { "-6" } else { "-4" };
+    let args = [family, "route", "del", &dest.to_string(), "dev", dev].map(String::from);
+    run("ip", &args).await
+}
+
+/// Point the resolver for `dev` at the pushed DNS servers.
+///
+/// Uses `resolvectl` (systemd-resolved). Hosts without it keep their current
+/// resolver configuration and only get a warning.
+pub async fn apply_dns(dev: &str, servers: &[IpAddr]) {
+    if servers.is_empty() {
+        return;
+    }
+    let mut args = vec!["dns".to_string(), dev.to_string()];
+    args.extend(servers.iter().map(IpAddr::to_string));
+    match run("resolvectl", &args).await {
+        Ok(()) => info!("DNS for {dev}: {servers:?}"),
+        Err(e) => warn!("could not apply pushed DNS servers {servers:?}: {e}"),
+    }
+}
@@ -3,32 +3,376 @@
 
 #![cfg(target_os = "linux")]
 
-use std::{error::Error, net::Ipv4Addr};
-use tokio::io::AsyncReadExt;
-use tracing::info;
+use crate::route::run;
+use crate::vnet::{segment, VnetHdr, VNET_HDR_LEN};
+use ipnet::Ipv4Net;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, IoSlice, Read, Write},
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::{Arc, Mutex},
+};
+use tokio::io::{unix::AsyncFd, AsyncReadExt};
+use tracing::{info, warn};
+use tun::Device as _;
+use xeonvpn_core::frame::MAX_PAYLOAD;
 
-/// Open (and keep) a Linux TUN with the given name and IPv4 address.
+/// Default MTU for TUN devices whose MTU is not negotiated.
+pub const DEFAULT_MTU: u16 = 1500;
+
+/// How to open a TUN device with [`open_tun_with`].
+#[derive(Debug, Clone)]
+pub struct TunOptions {
+    /// Number of queues; more than one opens the device `IFF_MULTI_QUEUE`.
+    pub queues: usize,
+    /// Open the device with `IFF_VNET_HDR` and enable checksum and TCP
+    /// segmentation offloads. Every packet read then starts with a
+    /// [`VnetHdr`] and may be a GSO super-segment.
+    pub offload: bool,
+}
+
+impl Default for TunOptions {
+    fn default() -> Self {
+        Self {
+            queues: 1,
+            offload: false,
+        }
+    }
+}
+
+struct Shared {
+    /// Non-blocking duplicate of the device fd used for packet I/O. Reads
+    /// and writes wait on separate readiness, so neither blocks the other.
+    io: AsyncFd<File>,
+    /// The device itself, kept open (and configurable) for as long as any
+    /// queue of it is alive. Devices opened with offloads have no separate
+    /// handle; they live as long as their queues.
+    _device: Option<Arc<Mutex<tun::platform::Device>>>,
+    /// Packets carry a virtio-net header.
+    offload: bool,
+}
+
+/// One queue of an open TUN device. [`TunDevice::split`] turns it into a
+/// reader and a writer that can be used from different tasks without any
+/// locking.
+pub struct TunDevice {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of a [`TunDevice`]. There is one reader per queue.
+pub struct TunReader {
+    shared: Arc<Shared>,
+}
+
+/// Sending half of a [`TunDevice`]. Clones share the device; each packet is
+/// written with a single `write`, so concurrent writers never interleave.
+#[derive(Clone)]
+pub struct TunWriter {
+    shared: Arc<Shared>,
+}
+
+impl TunDevice {
+    /// Wrap every queue of `device`.
+    fn queues(mut device: tun::platform::Device, queues: usize) -> io::Result<Vec<Self>> {
+        let mut fds = Vec::with_capacity(queues);
+        for index in 0..queues {
+            let queue = device
+                .queue(index)
+                .ok_or_else(|| io::Error::other(format!("TUN queue {index} missing")))?;
+            queue.set_nonblock()?;
+            // SAFETY: the fd is owned by `device`, which outlives this borrow.
+            // The duplicate shares the open file, including its non-blocking
+            // flag.
+            let fd = unsafe { BorrowedFd::borrow_raw(queue.as_raw_fd()) }.try_clone_to_owned()?;
+            fds.push(fd);
+        }
+        Self::from_fds(fds, Some(Arc::new(Mutex::new(device))), false)
+    }
+
+    fn from_fds(
+        fds: Vec<OwnedFd>,
+        device: Option<Arc<Mutex<tun::platform::Device>>>,
+        offload: bool,
+    ) -> io::Result<Vec<Self>> {
+        fds.into_iter()
+            .map(|fd| {
+                Ok(Self {
+                    shared: Arc::new(Shared {
+                        io: AsyncFd::new(File::from(fd))?,
+                        _device: device.clone(),
+                        offload,
+                    }),
+                })
+            })
+            .collect()
+    }
+
+    /// Packets on this device carry a virtio-net header.
+    pub fn offload(&self) -> bool {
+        self.shared.offload
+    }
+
+    pub fn split(self) -> (TunReader, TunWriter) {
+        let reader = TunReader {
+            shared: self.shared.clone(),
+        };
+        (
+            reader,
+            TunWriter {
+                shared: self.shared,
+            },
+        )
+    }
+}
+
+impl TunReader {
+    /// Read one packet into `buf`, returning its length. With offloads the
+    /// packet is preceded by a [`VnetHdr`], and `buf` should have room for
+    /// a 64 KiB packet plus [`VNET_HDR_LEN`].
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.shared
+            .io
+            .async_io(tokio::io::Interest::READABLE, |mut file| file.read(buf))
+            .await
+    }
+
+    /// Packets read carry a virtio-net header.
+    pub fn offload(&self) -> bool {
+        self.shared.offload
+    }
+}
+
+/// Whether an error from [`TunReader::recv`] means the device is gone, so
+/// no later read can succeed either. Other errors may clear up, such as
+/// `EIO` while the link is down.
+pub fn is_fatal_read_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EBADFD | libc::ENODEV | libc::ENXIO)
+    )
+}
+
+impl TunWriter {
+    /// Write one IP packet.
+    pub async fn send(&self, pkt: &[u8]) -> io::Result<()> {
+        if self.shared.offload {
+            let hdr = VnetHdr::default().encode();
+            return self.write(&[IoSlice::new(&hdr), IoSlice::new(pkt)]).await;
+        }
+        self.write(&[IoSlice::new(pkt)]).await
+    }
+
+    /// Write one packet preceded by its [`VnetHdr`]. A device without
+    /// offloads gets the packet finished and segmented first.
+    pub async fn send_offload(&self, pkt: &[u8]) -> io::Result<()> {
+        if self.shared.offload {
+            return self.write(&[IoSlice::new(pkt)]).await;
+        }
+        let mut segments = Vec::new();
+        VnetHdr::parse(pkt)
+            .and_then(|hdr| segment(&hdr, &pkt[VNET_HDR_LEN..], |s| segments.push(s.to_vec())))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed GSO packet"))?;
+        for seg in segments {
+            self.write(&[IoSlice::new(&seg)]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let n = self
+            .shared
+            .io
+            .async_io(tokio::io::Interest::WRITABLE, |mut file| {
+                file.write_vectored(bufs)
+            })
+            .await?;
+        if n != len {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short TUN write"));
+        }
+        Ok(())
+    }
+}
+
+/// Writes each packet to one queue of a multi-queue TUN, chosen by the hash
+/// of its flow, so packets of one flow always leave through the same queue
+/// and stay in order. The kernel also steers a flow's replies to the queue it
+/// last saw that flow on.
+#[derive(Clone)]
+pub struct FlowWriter {
+    queues: Arc<[TunWriter]>,
+}
+
+impl FlowWriter {
+    /// Writer over `queues`, which must not be empty.
+    pub fn new(queues: Vec<TunWriter>) -> Self {
+        assert!(!queues.is_empty(), "FlowWriter needs at least one queue");
+        Self {
+            queues: queues.into(),
+        }
+    }
+
+    /// The queues carry virtio-net headers.
+    pub fn offload(&self) -> bool {
+        self.queues[0].shared.offload
+    }
+
+    fn queue(&self, pkt: &[u8]) -> &TunWriter {
+        let hash = crate::packet::flow_hash(pkt).unwrap_or(0);
+        &self.queues[hash as usize % self.queues.len()]
+    }
+
+    /// Write one IP packet to the queue of its flow.
+    pub async fn send(&self, pkt: &[u8]) -> io::Result<()> {
+        self.queue(pkt).send(pkt).await
+    }
+
+    /// Write one packet preceded by its [`VnetHdr`] to the queue of its flow.
+    pub async fn send_offload(&self, pkt: &[u8]) -> io::Result<()> {
+        let inner = pkt.get(VNET_HDR_LEN..).unwrap_or_default();
+        self.queue(inner).send_offload(pkt).await
+    }
+}
+
+/// Open (and keep) a Linux TUN with the given name, IPv4 address/prefix and MTU.
 pub async fn open_tun_named(
     name: &str,
-    addr: Ipv4Addr,
-) -> Result<tun::AsyncDevice, Box<dyn Error + Send + Sync>> {
-    info!("starting Linux TUN POC ({name})");
+    addr: Ipv4Net,
+    mtu: u16,
+) -> Result<TunDevice, Box<dyn Error + Send + Sync>> {
+    let mut queues = open_tun_with(name, addr, mtu, &TunOptions::default()).await?;
+    Ok(queues.remove(0))
+}
+
+/// Like [`open_tun_named`], with the queues and offloads of `options`. The
+/// kernel spreads packets over the queues by flow, so each queue can be read
+/// by its own worker.
+pub async fn open_tun_with(
+    name: &str,
+    addr: Ipv4Net,
+    mtu: u16,
+    options: &TunOptions,
+) -> Result<Vec<TunDevice>, Box<dyn Error + Send + Sync>> {
+    let queues = options.queues;
+    info!(
+        "opening Linux TUN {name} with {addr}, mtu {mtu}, {queues} queue(s){}",
+        if options.offload { ", offloads" } else { "" }
+    );
+    if options.offload {
+        return open_offload(name, addr, mtu, queues).await;
+    }
 
     let mut config = tun::Configuration::default();
     config
         .name(name)
-        .address(addr)
-        .netmask(Ipv4Addr::new(255, 255, 255, 0))
-        .mtu(1500)
+        .address(addr.addr())
+        .netmask(addr.netmask())
+        .mtu(i32::from(mtu))
+        .queues(queues)
         .up();
 
-    let dev = tun::create_as_async(&config)?;
-    Ok(dev)
+    Ok(TunDevice::queues(tun::create(&config)?, queues)?)
+}
+
+// The `tun` crate cannot open devices with IFF_VNET_HDR, so offload devices
+// are set up with the raw ioctls from <linux/if_tun.h>
+const TUNSETIFF: u32 = 0x4004_54ca;
+const TUNSETOFFLOAD: u32 = 0x4004_54d0;
+const TUNSETVNETLE: u32 = 0x4004_54dc;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+const TUN_F_TSO6: libc::c_ulong = 0x04;
+const TUN_F_TSO_ECN: libc::c_ulong = 0x08;
+
+/// Largest packet the kernel may hand over, so that it still fits in one
+/// frame together with its virtio-net header.
+const GSO_MAX_SIZE: usize = MAX_PAYLOAD - VNET_HDR_LEN;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+fn ioctl<T>(fd: &OwnedFd, request: u32, arg: T) -> io::Result<()> {
+    // SAFETY: `fd` is a valid TUN fd and every caller passes the argument
+    // type its request expects.
+    if unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Attach one queue with virtio-net headers and offloads to TUN `name`,
+/// creating the device on the first call.
+fn open_offload_queue(name: &str, multi_queue: bool) -> io::Result<OwnedFd> {
+    let flags = libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC;
+    // SAFETY: plain open(2) of a NUL-terminated path.
+    let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and is owned by nothing else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: IFF_TUN | IFF_NO_PI | IFF_VNET_HDR,
+        _pad: [0; 22],
+    };
+    if multi_queue {
+        req.flags |= IFF_MULTI_QUEUE;
+    }
+    if name.len() >= req.name.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TUN name too long",
+        ));
+    }
+    req.name[..name.len()].copy_from_slice(name.as_bytes());
+    ioctl(&fd, TUNSETIFF, &mut req as *mut IfReq)?;
+    // Little-endian headers on every host keep the wire format fixed
+    let le: libc::c_int = 1;
+    ioctl(&fd, TUNSETVNETLE, &le as *const libc::c_int)?;
+    let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_TSO_ECN;
+    ioctl(&fd, TUNSETOFFLOAD, offloads)?;
+    Ok(fd)
+}
+
+async fn open_offload(
+    name: &str,
+    addr: Ipv4Net,
+    mtu: u16,
+    queues: usize,
+) -> Result<Vec<TunDevice>, Box<dyn Error + Send + Sync>> {
+    let fds = (0..queues)
+        .map(|_| open_offload_queue(name, queues > 1))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| format!("cannot open TUN {name} with offloads: {e}"))?;
+    let ip = |args: &str| {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        async move { run("ip", &args).await }
+    };
+    ip(&format!("-4 addr replace {addr} dev {name}")).await?;
+    ip(&format!("link set dev {name} mtu {mtu} up")).await?;
+    if let Err(e) = ip(&format!("link set dev {name} gso_max_size {GSO_MAX_SIZE}")).await {
+        // Oversized packets are then segmented before they are sent
+        warn!("cannot limit GSO size of {name}: {e}");
+    }
+    Ok(TunDevice::from_fds(fds, None, true)?)
 }
 
-/// Open (and keep) a Linux TUN `xeonvpn0` interface up. Returns the async device handle.
-pub async fn open_tun() -> Result<tun::AsyncDevice, Box<dyn Error + Send + Sync>> {
-    open_tun_named("xeonvpn0", Ipv4Addr::new(10, 123, 0, 2)).await
+/// Open (and keep) a Linux TUN `xeonvpn0` interface up. Returns the device handle.
+pub async fn open_tun() -> Result<TunDevice, Box<dyn Error + Send + Sync>> {
+    let addr = Ipv4Net::new(Ipv4Addr::new(10, 123, 0, 2), 24)?;
+    open_tun_named("xeonvpn0", addr, DEFAULT_MTU).await
 }
 
 /// Read a single packet from a Linux TUN `xeonvpn0` and return the bytes (truncated to length).
@@ -0,0 +1,438 @@
+//! virtio-net headers as used by TUN devices opened with `IFF_VNET_HDR`.
+//!
+//! With offloads enabled every packet read from or written to the TUN is
+//! preceded by a [`VnetHdr`]. The kernel may then hand over TCP packets whose
+//! checksum is left for us to finish, or whole TCP "super-segments" of up to
+//! 64 KiB that still have to be cut into MSS-sized segments (GSO). A peer
+//! whose TUN also has offloads can take such packets as they are; for anyone
+//! else they are finished here with [`finish_checksum`] and [`segment`].
+
+use crate::packet::checksum;
+
+/// Size of the virtio-net header on the TUN (without `num_buffers`).
+pub const VNET_HDR_LEN: usize = 10;
+
+/// The checksum from `csum_start` to the end of the packet is left to us.
+pub const F_NEEDS_CSUM: u8 = 1;
+pub const GSO_NONE: u8 = 0;
+pub const GSO_TCPV4: u8 = 1;
+pub const GSO_UDP: u8 = 3;
+pub const GSO_TCPV6: u8 = 4;
+/// Flag on `gso_type`: the segments may carry ECN's CWR flag.
+pub const GSO_ECN: u8 = 0x80;
+
+const PROTO_TCP: u8 = 6;
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_CWR: u8 = 0x80;
+
+/// A virtio-net header. Multi-byte fields are little-endian on the wire; the
+/// TUN is switched to little-endian headers when offloads are enabled, so the
+/// encoding is the same on every host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VnetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    /// Length of the headers to replicate in front of every segment.
+    pub hdr_len: u16,
+    /// Payload bytes per segment (the MSS) for GSO packets.
+    pub gso_size: u16,
+    /// Offset of the transport header, where checksumming starts.
+    pub csum_start: u16,
+    /// Offset of the checksum field from `csum_start`.
+    pub csum_offset: u16,
+}
+
+impl VnetHdr {
+    /// Parse the header at the start of `buf`.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let b = buf.get(..VNET_HDR_LEN)?;
+        let u16_at = |at: usize| u16::from_le_bytes([b[at], b[at + 1]]);
+        Some(Self {
+            flags: b[0],
+            gso_type: b[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; VNET_HDR_LEN] {
+        let mut b = [0u8; VNET_HDR_LEN];
+        b[0] = self.flags;
+        b[1] = self.gso_type;
+        b[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        b[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        b[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        b[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+        b
+    }
+
+    /// The packet has to be segmented before a plain TUN accepts it.
+    pub fn is_gso(&self) -> bool {
+        self.gso_type & !GSO_ECN != GSO_NONE
+    }
+
+    pub fn needs_csum(&self) -> bool {
+        self.flags & F_NEEDS_CSUM != 0
+    }
+
+    /// The packet is complete as it is: no segmentation, no checksum left.
+    pub fn is_plain(&self) -> bool {
+        !self.is_gso() && !self.needs_csum()
+    }
+}
+
+/// Finish the partial checksum the kernel left in `pkt`, if any. Returns
+/// `None` if the header points outside the packet.
+pub fn finish_checksum(hdr: &VnetHdr, pkt: &mut [u8]) -> Option<()> {
+    if !hdr.needs_csum() {
+        return Some(());
+    }
+    let start = usize::from(hdr.csum_start);
+    let field = start + usize::from(hdr.csum_offset);
+    if field + 2 > pkt.len() {
+        return None;
+    }
+    // The field already holds the pseudo-header sum, so summing over it
+    // yields the final checksum
+    let sum = checksum(&pkt[start..], 0);
+    pkt[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+    Some(())
+}
+
+/// Turn `pkt`, described by `hdr`, into packets a plain TUN accepts, handing
+/// each to `emit`: a GSO TCP packet is cut into segments of `gso_size`
+/// payload bytes with their lengths, sequence numbers, flags and checksums
+/// fixed up, and a pending checksum is finished.
+///
+/// Returns `None` (having emitted nothing) for malformed packets and for GSO
+/// types other than TCP.
+pub fn segment(hdr: &VnetHdr, pkt: &[u8], mut emit: impl FnMut(&[u8])) -> Option<()> {
+    if !hdr.is_gso() {
+        let mut pkt = pkt.to_vec();
+        finish_checksum(hdr, &mut pkt)?;
+        emit(&pkt);
+        return Some(());
+    }
+    let v4 = match hdr.gso_type & !GSO_ECN {
+        GSO_TCPV4 => true,
+        GSO_TCPV6 => false,
+        _ => return None,
+    };
+    let ihl = if v4 {
+        usize::from(pkt.first()? & 0x0f) * 4
+    } else {
+        40
+    };
+    let l4 = usize::from(hdr.csum_start);
+    let mss = usize::from(hdr.gso_size);
+    if !hdr.needs_csum() || l4 < ihl || mss == 0 || pkt.len() < l4 + 20 {
+        return None;
+    }
+    let tcp_hlen = usize::from(pkt[l4 + 12] >> 4) * 4;
+    let hlen = l4 + tcp_hlen;
+    if tcp_hlen < 20 || pkt.len() < hlen {
+        return None;
+    }
+    let payload = &pkt[hlen..];
+    let count = payload.len().div_ceil(mss).max(1);
+    let seq = u32::from_be_bytes(pkt[l4 + 4..l4 + 8].try_into().unwrap());
+    let id = u16::from_be_bytes([pkt[4], pkt[5]]);
+
+    let mut seg = Vec::with_capacity(hlen + mss);
+    for i in 0..count {
+        let chunk = &payload[(i * mss).min(payload.len())..((i + 1) * mss).min(payload.len())];
+        seg.clear();
+        seg.extend_from_slice(&pkt[..hlen]);
+        seg.extend_from_slice(chunk);
+        let len = seg.len();
+
+        let pseudo = if v4 {
+            seg[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            seg[4..6].copy_from_slice(&id.wrapping_add(i as u16).to_be_bytes());
+            seg[10..12].fill(0);
+            let sum = checksum(&seg[..ihl], 0);
+            seg[10..12].copy_from_slice(&sum.to_be_bytes());
+            words(&seg[12..20])
+        } else {
+            seg[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+            words(&seg[8..40])
+        };
+
+        let tcp = &mut seg[l4..];
+        let offset = (i * mss) as u32;
+        tcp[4..8].copy_from_slice(&seq.wrapping_add(offset).to_be_bytes());
+        if i + 1 < count {
+            tcp[13] &= !(TCP_FIN | TCP_PSH);
+        }
+        if i > 0 {
+            tcp[13] &= !TCP_CWR;
+        }
+        tcp[16..18].fill(0);
+        let tcp_len = (len - l4) as u32;
+        let sum = checksum(tcp, pseudo + u32::from(PROTO_TCP) + tcp_len);
+        tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+        emit(&seg);
+    }
+    Some(())
+}
+
+/// Sum of the big-endian 16-bit words of `data`, unfolded.
+fn words(data: &[u8]) -> u32 {
+    data.chunks_exact(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC4: [u8; 4] = [10, 123, 0, 1];
+    const DST4: [u8; 4] = [10, 123, 0, 2];
+    const SEQ: u32 = 0xfffff000;
+    const TCP_ACK: u8 = 0x10;
+
+    /// One's-complement sum of `data` plus `initial`, folded.
+    fn ones_sum(data: &[u8], initial: u32) -> u16 {
+        let mut sum = initial + words(data);
+        if data.len() % 2 == 1 {
+            sum += u32::from(data[data.len() - 1]) << 8;
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    /// Pseudo-header sum of the TCP segment at `l4` in `pkt`.
+    fn pseudo(pkt: &[u8], l4: usize) -> u32 {
+        let addrs = if pkt[0] >> 4 == 4 {
+            &pkt[12..20]
+        } else {
+            &pkt[8..40]
+        };
+        words(addrs) + u32::from(PROTO_TCP) + (pkt.len() - l4) as u32
+    }
+
+    /// TCP header with timestamps, as the kernel hands a TSO packet over.
+    fn tcp_header(flags: u8) -> Vec<u8> {
+        let mut tcp = vec![0x9c, 0x40, 0x01, 0xbb];
+        tcp.extend_from_slice(&SEQ.to_be_bytes());
+        tcp.extend_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x80, flags, 0x01, 0xf5]);
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(&[1, 1, 8, 10, 0, 0, 0x30, 0x39, 0, 0, 0xd4, 0x31]);
+        tcp
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 251) as u8).collect()
+    }
+
+    fn tso_v4(payload: &[u8], flags: u8) -> Vec<u8> {
+        let tcp = tcp_header(flags);
+        let total = 20 + tcp.len() + payload.len();
+        let mut pkt = vec![0x45, 0];
+        pkt.extend_from_slice(&(total as u16).to_be_bytes());
+        pkt.extend_from_slice(&[0xff, 0xfe, 0x40, 0, 64, PROTO_TCP, 0, 0]);
+        pkt.extend_from_slice(&SRC4);
+        pkt.extend_from_slice(&DST4);
+        pkt.extend_from_slice(&tcp);
+        pkt.extend_from_slice(payload);
+        // The kernel leaves the pseudo-header sum in the checksum field
+        let partial = ones_sum(&[], pseudo(&pkt, 20));
+        pkt[36..38].copy_from_slice(&partial.to_be_bytes());
+        pkt
+    }
+
+    fn tso_v6(payload: &[u8]) -> Vec<u8> {
+        let tcp = tcp_header(TCP_ACK | TCP_PSH);
+        let mut pkt = vec![0x60, 0, 0, 0];
+        pkt.extend_from_slice(&((tcp.len() + payload.len()) as u16).to_be_bytes());
+        pkt.extend_from_slice(&[PROTO_TCP, 64]);
+        pkt.extend_from_slice(&[0xfd, 0, 0, 0x7b, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        pkt.extend_from_slice(&[0xfd, 0, 0, 0x7b, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        pkt.extend_from_slice(&tcp);
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    fn gso(gso_type: u8, l4: u16, mss: u16) -> VnetHdr {
+        VnetHdr {
+            flags: F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: l4 + 32,
+            gso_size: mss,
+            csum_start: l4,
+            csum_offset: 16,
+        }
+    }
+
+    fn segments(hdr: &VnetHdr, pkt: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let mut out = Vec::new();
+        segment(hdr, pkt, |seg| out.push(seg.to_vec()))?;
+        Some(out)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let wire = [1, 0x81, 66, 0, 0xa8, 0x05, 34, 0, 16, 0];
+        let hdr = VnetHdr::parse(&wire).unwrap();
+        assert_eq!(
+            hdr,
+            VnetHdr {
+                flags: F_NEEDS_CSUM,
+                gso_type: GSO_TCPV4 | GSO_ECN,
+                hdr_len: 66,
+                gso_size: 1448,
+                csum_start: 34,
+                csum_offset: 16,
+            }
+        );
+        assert_eq!(hdr.encode(), wire);
+        assert!(hdr.is_gso() && hdr.needs_csum() && !hdr.is_plain());
+        assert!(VnetHdr::parse(&wire[..9]).is_none());
+        let plain = VnetHdr::parse(&[0; 12]).unwrap();
+        assert_eq!(plain, VnetHdr::default());
+        assert!(plain.is_plain());
+        let ecn_only = VnetHdr {
+            gso_type: GSO_ECN,
+            ..VnetHdr::default()
+        };
+        assert!(!ecn_only.is_gso());
+    }
+
+    #[test]
+    fn segments_tcpv4() {
+        let data = payload(4000);
+        let pkt = tso_v4(&data, TCP_ACK | TCP_PSH | TCP_FIN | TCP_CWR);
+        let segs = segments(&gso(GSO_TCPV4, 20, 1448), &pkt).unwrap();
+        let lens: Vec<_> = segs.iter().map(Vec::len).collect();
+        assert_eq!(lens, [52 + 1448, 52 + 1448, 52 + 1104]);
+
+        for (i, seg) in segs.iter().enumerate() {
+            let last = i + 1 == segs.len();
+            assert_eq!(usize::from(u16::from_be_bytes([seg[2], seg[3]])), seg.len());
+            // IP IDs count up from the super-packet's, wrapping
+            let id = u16::from_be_bytes([seg[4], seg[5]]);
+            assert_eq!(id, 0xfffe_u16.wrapping_add(i as u16));
+            assert_eq!(ones_sum(&seg[..20], 0), 0xffff, "IP checksum of {i}");
+            assert_eq!(&seg[12..20], &pkt[12..20]);
+
+            let tcp = &seg[20..];
+            let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+            assert_eq!(seq, SEQ.wrapping_add(i as u32 * 1448));
+            assert_eq!(tcp[13] & (TCP_FIN | TCP_PSH) != 0, last, "FIN/PSH of {i}");
+            assert_eq!(tcp[13] & TCP_CWR != 0, i == 0, "CWR of {i}");
+            assert_ne!(tcp[13] & TCP_ACK, 0);
+            assert_eq!(&tcp[20..32], &pkt[40..52], "options of {i}");
+            assert_eq!(
+                ones_sum(tcp, pseudo(seg, 20)),
+                0xffff,
+                "TCP checksum of {i}"
+            );
+            assert_eq!(
+                &tcp[32..],
+                &data[i * 1448..(i * 1448 + 1448).min(data.len())]
+            );
+        }
+    }
+
+    #[test]
+    fn segments_tcpv6() {
+        let data = payload(2000);
+        let pkt = tso_v6(&data);
+        let segs = segments(&gso(GSO_TCPV6, 40, 1220), &pkt).unwrap();
+        assert_eq!(segs.len(), 2);
+        for (i, seg) in segs.iter().enumerate() {
+            let payload_len = usize::from(u16::from_be_bytes([seg[4], seg[5]]));
+            assert_eq!(payload_len, seg.len() - 40);
+            let tcp = &seg[40..];
+            let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+            assert_eq!(seq, SEQ.wrapping_add(i as u32 * 1220));
+            assert_eq!(
+                ones_sum(tcp, pseudo(seg, 40)),
+                0xffff,
+                "TCP checksum of {i}"
+            );
+        }
+        assert_eq!(segs[1].len(), 72 + 780);
+    }
+
+    #[test]
+    fn exact_multiple_and_empty_payloads() {
+        let pkt = tso_v4(&payload(2 * 1000), TCP_ACK);
+        assert_eq!(segments(&gso(GSO_TCPV4, 20, 1000), &pkt).unwrap().len(), 2);
+        // A bare header still goes out, as one segment
+        let pkt = tso_v4(&[], TCP_ACK | TCP_FIN);
+        let segs = segments(&gso(GSO_TCPV4, 20, 1000), &pkt).unwrap();
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].len(), 52);
+        assert_eq!(ones_sum(&segs[0][20..], pseudo(&segs[0], 20)), 0xffff);
+    }
+
+    #[test]
+    fn finishes_partial_checksums() {
+        let mut pkt = tso_v4(&payload(777), TCP_ACK | TCP_PSH);
+        let hdr = VnetHdr {
+            flags: F_NEEDS_CSUM,
+            csum_start: 20,
+            csum_offset: 16,
+            ..VnetHdr::default()
+        };
+        let segs = segments(&hdr, &pkt).unwrap();
+        assert_eq!(segs.len(), 1);
+        finish_checksum(&hdr, &mut pkt).unwrap();
+        assert_eq!(segs[0], pkt);
+        assert_eq!(ones_sum(&pkt[20..], pseudo(&pkt, 20)), 0xffff);
+
+        // Nothing to do without the flag
+        let before = pkt.clone();
+        finish_checksum(&VnetHdr::default(), &mut pkt).unwrap();
+        assert_eq!(pkt, before);
+
+        let outside = VnetHdr {
+            csum_start: 20,
+            csum_offset: pkt.len() as u16,
+            ..hdr
+        };
+        assert!(finish_checksum(&outside, &mut pkt).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_gso_packets() {
+        let pkt = tso_v4(&payload(3000), TCP_ACK);
+        let good = gso(GSO_TCPV4, 20, 1448);
+        assert!(segments(&good, &pkt).is_some());
+        let bad = [
+            VnetHdr {
+                gso_type: GSO_UDP,
+                ..good
+            },
+            VnetHdr {
+                gso_size: 0,
+                ..good
+            },
+            VnetHdr { flags: 0, ..good },
+            VnetHdr {
+                csum_start: 10,
+                ..good
+            },
+            VnetHdr {
+                csum_start: 4000,
+                ..good
+            },
+        ];
+        for hdr in bad {
+            let mut emitted = false;
+            assert!(segment(&hdr, &pkt, |_| emitted = true).is_none(), "{hdr:?}");
+            assert!(!emitted);
+        }
+        // A TCP data offset past the end of the packet
+        let mut short = pkt[..60].to_vec();
+        short[32] = 0xf0;
+        assert!(segments(&good, &short).is_none());
+    }
+}
@@ -0,0 +1,565 @@
+//! Uplink access control: which destinations the packets a session sends
+//! into the server TUN may go to.
+//!
+//! ```toml
+//! [acl]
+//! default = "allow"                  # for sessions no policy decides
+//! log_denied = true                  # log the first packet of each denied flow
+//!
+//! [acl.groups.contractors]
+//! members = ["alice", "bob-laptop"]  # also set by `groups` in the user database
+//! default = "deny"
+//! rules = [
+//!     { action = "allow", dst = ["10.20.0.0/16"], protocol = "tcp", ports = [443, "8000-8099"] },
+//!     { action = "allow", dst = ["10.30.1.0/24"] },
+//! ]
+//!
+//! [acl.users.carol]
+//! rules = [{ action = "deny", protocol = "tcp", ports = [22] }]
+//! ```
+//!
+//! A session is checked against the rules of its user's policy, then those
+//! of each of its groups in name order; the first rule matching a packet
+//! decides. Packets no rule matches get the user's default, otherwise the
+//! groups' (`deny` if any of them denies), otherwise `acl.default`. Rules
+//! apply to every destination, the tunnel subnet included. Fragments after
+//! the first carry no ports, so only rules without ports match them.
+//!
+//! User policies and group `members` only apply to proven identities: a
+//! certificate or token naming the user, or a client id with the user's own
+//! pre-shared key. Any client can claim a client id, so sessions known only
+//! by one (or anonymous) get the policies of the groups the authorizer puts
+//! them in and otherwise `acl.default`. Restricting such clients takes
+//! `default = "deny"`.
+
+use crate::config::{AclPolicySettings, AclSettings};
+use ipnet::IpNet;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tracing::{debug, info};
+use xeonvpn_net::packet::{self, Flow, PROTO_ICMP, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Denied flows remembered per session so each is logged once.
+const MAX_LOGGED_FLOWS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for AclAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AclAction::Allow => "allow",
+            AclAction::Deny => "deny",
+        })
+    }
+}
+
+/// Protocols a rule can be limited to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclProtocol {
+    #[default]
+    Any,
+    Tcp,
+    Udp,
+    /// ICMP and ICMPv6.
+    Icmp,
+}
+
+impl AclProtocol {
+    fn matches(self, protocol: u8) -> bool {
+        match self {
+            AclProtocol::Any => true,
+            AclProtocol::Tcp => protocol == PROTO_TCP,
+            AclProtocol::Udp => protocol == PROTO_UDP,
+            AclProtocol::Icmp => protocol == PROTO_ICMP || protocol == PROTO_ICMPV6,
+        }
+    }
+}
+
+/// Destination ports `first..=last`, written `443` or `"8000-8099"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range {s:?}");
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first = first.trim().parse().map_err(|_| invalid())?;
+        let last = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PortVisitor;
+
+        impl Visitor<'_> for PortVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a port or a range like \"8000-8099\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<PortRange, E> {
+                let port = u16::try_from(v).map_err(|_| E::custom(format!("invalid port {v}")))?;
+                Ok(PortRange {
+                    first: port,
+                    last: port,
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<PortRange, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PortVisitor)
+    }
+}
+
+/// One rule of a policy. Unset fields match everything.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    pub action: AclAction,
+    /// Destination networks.
+    #[serde(default)]
+    pub dst: Vec<IpNet>,
+    #[serde(default)]
+    pub protocol: AclProtocol,
+    /// Destination ports; only for `tcp` and `udp` rules.
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+}
+
+impl AclRule {
+    fn matches(&self, flow: &Flow) -> bool {
+        (self.dst.is_empty() || self.dst.iter().any(|net| net.contains(&flow.dst)))
+            && self.protocol.matches(flow.protocol)
+            && (self.ports.is_empty()
+                || flow
+                    .ports
+                    .is_some_and(|(_, dst)| self.ports.iter().any(|range| range.contains(dst))))
+    }
+}
+
+struct Policy {
+    default: Option<AclAction>,
+    rules: Vec<AclRule>,
+}
+
+impl Policy {
+    fn new(name: &str, settings: &AclPolicySettings) -> Result<Self, BoxError> {
+        for (i, rule) in settings.rules.iter().enumerate() {
+            let ports_allowed = matches!(rule.protocol, AclProtocol::Tcp | AclProtocol::Udp);
+            if !rule.ports.is_empty() && !ports_allowed {
+                return Err(
+                    format!("{name} rule {}: ports need protocol tcp or udp", i + 1).into(),
+                );
+            }
+        }
+        Ok(Self {
+            default: settings.default,
+            rules: settings.rules.clone(),
+        })
+    }
+}
+
+/// The configured policies.
+pub struct Acl {
+    default: AclAction,
+    log_denied: bool,
+    users: HashMap<String, Policy>,
+    groups: BTreeMap<String, Policy>,
+    /// Groups listing each user as a member.
+    members: HashMap<String, BTreeSet<String>>,
+}
+
+impl Acl {
+    /// Compile the `[acl]` settings; `None` when nothing is configured.
+    pub fn from_settings(settings: &AclSettings) -> Result<Option<Self>, BoxError> {
+        if settings.users.is_empty()
+            && settings.groups.is_empty()
+            && settings.default == AclAction::Allow
+        {
+            return Ok(None);
+        }
+        let mut users = HashMap::new();
+        for (name, policy) in &settings.users {
+            let label = format!("acl.users.{name}");
+            if !policy.members.is_empty() {
+                return Err(format!("{label}: only groups have members").into());
+            }
+            users.insert(name.clone(), Policy::new(&label, policy)?);
+        }
+        let mut groups = BTreeMap::new();
+        let mut members: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for (name, policy) in &settings.groups {
+            groups.insert(
+                name.clone(),
+                Policy::new(&format!("acl.groups.{name}"), policy)?,
+            );
+            for user in &policy.members {
+                members
+                    .entry(user.clone())
+                    .or_default()
+                    .insert(name.clone());
+            }
+        }
+        Ok(Some(Self {
+            default: settings.default,
+            log_denied: settings.log_denied,
+            users,
+            groups,
+            members,
+        }))
+    }
+
+    /// The filter for session `session_id` of the proven `user`, if any,
+    /// that is also in `groups` besides those listing the user as a member.
+    /// `None` when the session may send anything.
+    pub fn session(
+        &self,
+        session_id: u64,
+        user: Option<&str>,
+        groups: &[String],
+    ) -> Option<SessionAcl> {
+        let mut names: BTreeSet<&str> = groups.iter().map(String::as_str).collect();
+        if let Some(listed) = user.and_then(|user| self.members.get(user)) {
+            names.extend(listed.iter().map(String::as_str));
+        }
+        let mut rules = Vec::new();
+        let mut policies = Vec::new();
+        let mut default = None;
+        if let Some((user, policy)) = user.and_then(|user| Some((user, self.users.get(user)?))) {
+            policies.push(format!("user {user}"));
+            for (i, rule) in policy.rules.iter().enumerate() {
+                rules.push((format!("user {user} rule {}", i + 1), rule.clone()));
+            }
+            default = policy.default;
+        }
+        let mut group_default = None;
+        for name in names {
+            let Some(policy) = self.groups.get(name) else {
+                debug!("session {session_id}: no ACL policy for group {name}");
+                continue;
+            };
+            policies.push(format!("group {name}"));
+            for (i, rule) in policy.rules.iter().enumerate() {
+                rules.push((format!("group {name} rule {}", i + 1), rule.clone()));
+            }
+            group_default = match (group_default, policy.default) {
+                (Some(AclAction::Deny), _) | (_, Some(AclAction::Deny)) => Some(AclAction::Deny),
+                (current, next) => current.or(next),
+            };
+        }
+        let default = default.or(group_default).unwrap_or(self.default);
+        if rules.is_empty() && default == AclAction::Allow {
+            return None;
+        }
+        policies.push(format!("default {default}"));
+        let denied = (0..=rules.len()).map(|_| AtomicU64::new(0)).collect();
+        Some(SessionAcl {
+            session_id,
+            summary: policies.join(", "),
+            rules,
+            default,
+            log_denied: self.log_denied,
+            denied,
+            logged: Mutex::new(HashSet::new()),
+        })
+    }
+}
+
+/// The rules one session's uplink packets are checked against.
+pub struct SessionAcl {
+    session_id: u64,
+    summary: String,
+    /// Rules in the order they are tried, with a label for logs.
+    rules: Vec<(String, AclRule)>,
+    default: AclAction,
+    log_denied: bool,
+    /// Packets denied by each rule, then by the default.
+    denied: Vec<AtomicU64>,
+    logged: Mutex<HashSet<Flow>>,
+}
+
+impl SessionAcl {
+    /// The policies that apply, for logging.
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    /// Whether the IP packet `pkt` may pass. Packets that cannot be parsed
+    /// are denied by the default.
+    pub fn check(&self, pkt: &[u8]) -> bool {
+        let Some(flow) = packet::flow(pkt) else {
+            return self.default == AclAction::Allow;
+        };
+        let (index, action) = self
+            .rules
+            .iter()
+            .position(|(_, rule)| rule.matches(&flow))
+            .map_or((self.rules.len(), self.default), |i| {
+                (i, self.rules[i].1.action)
+            });
+        if action == AclAction::Allow {
+            return true;
+        }
+        self.denied[index].fetch_add(1, Ordering::Relaxed);
+        if self.log_denied {
+            let mut logged = self.logged.lock().unwrap();
+            if logged.len() >= MAX_LOGGED_FLOWS {
+                logged.clear();
+            }
+            if logged.insert(flow) {
+                let by = self.rules.get(index).map_or("default", |(label, _)| label);
+                info!("session {}: denied {flow} by {by}", self.session_id);
+            }
+        }
+        false
+    }
+
+    pub fn stats(&self) -> AclStats {
+        let labels = self
+            .rules
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .chain(["default"]);
+        let denied = labels
+            .zip(&self.denied)
+            .map(|(label, count)| (label.to_string(), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        AclStats { denied }
+    }
+}
+
+/// Deny counters of a [`SessionAcl`].
+#[derive(Debug, Default, Clone)]
+pub struct AclStats {
+    /// Packets denied by each rule (or the default) that denied any.
+    pub denied: Vec<(String, u64)>,
+}
+
+impl AclStats {
+    /// All packets denied.
+    pub fn total(&self) -> u64 {
+        self.denied.iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl fmt::Display for AclStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "denied {} packets", self.total())?;
+        for (i, (label, count)) in self.denied.iter().enumerate() {
+            let sep = if i == 0 { " (" } else { ", " };
+            write!(f, "{sep}{label}: {count}")?;
+        }
+        if !self.denied.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(config: &str) -> Acl {
+        let settings: AclSettings = toml::from_str(config).unwrap();
+        Acl::from_settings(&settings).unwrap().unwrap()
+    }
+
+    /// An IPv4 packet of `protocol` to `dst`, port `port` for TCP and UDP.
+    fn packet(protocol: u8, dst: [u8; 4], port: u16) -> Vec<u8> {
+        let mut pkt = vec![0u8; 40];
+        pkt[0] = 0x45;
+        pkt[2..4].copy_from_slice(&40u16.to_be_bytes());
+        pkt[8] = 64;
+        pkt[9] = protocol;
+        pkt[12..16].copy_from_slice(&[10, 123, 0, 2]);
+        pkt[16..20].copy_from_slice(&dst);
+        pkt[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        pkt[22..24].copy_from_slice(&port.to_be_bytes());
+        pkt
+    }
+
+    fn tcp(dst: [u8; 4], port: u16) -> Vec<u8> {
+        packet(PROTO_TCP, dst, port)
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        let range = |first, last| Ok(PortRange { first, last });
+        assert_eq!("443".parse(), range(443, 443));
+        assert_eq!("8000-8099".parse(), range(8000, 8099));
+        assert_eq!(" 80 - 90 ".parse(), range(80, 90));
+        assert_eq!("0-65535".parse(), range(0, 65535));
+        for bad in ["", "x", "9-1", "1-", "-5", "1-2-3", "65536", "80-65536"] {
+            assert!(bad.parse::<PortRange>().is_err(), "{bad:?}");
+        }
+
+        let rule = "action = \"allow\"\nprotocol = \"tcp\"\nports = [22, \"8000-8099\"]";
+        let rule: AclRule = toml::from_str(rule).unwrap();
+        assert_eq!(
+            rule.ports,
+            [range(22, 22).unwrap(), range(8000, 8099).unwrap()]
+        );
+        for ports in ["[70000]", "[-1]", "[\"http\"]", "[\"90-80\"]"] {
+            let rule = format!("action = \"allow\"\nprotocol = \"tcp\"\nports = {ports}");
+            assert!(toml::from_str::<AclRule>(&rule).is_err(), "{ports}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = acl(r#"
+            [users.alice]
+            default = "deny"
+            rules = [
+                { action = "deny", dst = ["10.20.0.5/32"], protocol = "tcp", ports = [443] },
+                { action = "allow", dst = ["10.20.0.0/16"], protocol = "tcp", ports = ["400-500"] },
+                { action = "deny", dst = ["10.20.0.0/16"] },
+                { action = "allow", protocol = "udp" },
+            ]
+        "#);
+        let session = acl.session(1, Some("alice"), &[]).unwrap();
+        assert!(!session.check(&tcp([10, 20, 0, 5], 443)));
+        assert!(session.check(&tcp([10, 20, 0, 6], 443)));
+        assert!(!session.check(&tcp([10, 20, 0, 6], 22)));
+        // The udp allow comes after the subnet deny
+        assert!(!session.check(&packet(PROTO_UDP, [10, 20, 9, 9], 53)));
+        assert!(session.check(&packet(PROTO_UDP, [8, 8, 8, 8], 53)));
+        assert!(!session.check(&packet(PROTO_ICMP, [8, 8, 8, 8], 0)));
+        // Unparseable packets get the default
+        assert!(!session.check(&[0x45, 0, 0]));
+        let stats = session.stats();
+        let denied: Vec<_> = stats.denied.iter().map(|(l, n)| (l.as_str(), *n)).collect();
+        assert_eq!(
+            denied,
+            [
+                ("user alice rule 1", 1),
+                ("user alice rule 3", 2),
+                ("default", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn user_policy_comes_before_groups() {
+        let acl = acl(r#"
+            [groups.contractors]
+            members = ["alice", "bob"]
+            default = "deny"
+            rules = [{ action = "allow", dst = ["10.30.1.0/24"] }]
+
+            [users.alice]
+            default = "allow"
+            rules = [{ action = "deny", dst = ["10.30.1.0/24"], protocol = "tcp", ports = [22] }]
+        "#);
+        let alice = acl.session(1, Some("alice"), &[]).unwrap();
+        // The user's rules are tried first, the group's after them
+        assert!(!alice.check(&tcp([10, 30, 1, 1], 22)));
+        assert!(alice.check(&tcp([10, 30, 1, 1], 443)));
+        // The user's default wins over the group's
+        assert!(alice.check(&tcp([8, 8, 8, 8], 443)));
+        assert_eq!(
+            alice.summary(),
+            "user alice, group contractors, default allow"
+        );
+
+        let bob = acl.session(2, Some("bob"), &[]).unwrap();
+        assert!(bob.check(&tcp([10, 30, 1, 1], 22)));
+        assert!(!bob.check(&tcp([8, 8, 8, 8], 443)));
+    }
+
+    #[test]
+    fn any_group_denying_makes_the_default_deny() {
+        let acl = acl(r#"
+            [groups.staff]
+            default = "allow"
+            [groups.contractors]
+            default = "deny"
+            [groups.guests]
+            rules = [{ action = "deny", protocol = "udp" }]
+        "#);
+        let groups = ["staff".to_string(), "guests".to_string()];
+        let session = acl.session(1, None, &groups).unwrap();
+        assert!(session.check(&tcp([8, 8, 8, 8], 443)));
+        assert!(!session.check(&packet(PROTO_UDP, [8, 8, 8, 8], 53)));
+        let groups = ["staff".to_string(), "contractors".to_string()];
+        let session = acl.session(1, None, &groups).unwrap();
+        assert!(!session.check(&tcp([8, 8, 8, 8], 443)));
+        assert!(acl.session(1, None, &["staff".to_string()]).is_none());
+    }
+
+    #[test]
+    fn unproven_sessions_get_the_global_default() {
+        let acl = acl(r#"
+            default = "deny"
+            [groups.contractors]
+            members = ["alice"]
+            default = "allow"
+        "#);
+        // A member only by a claimed name, and not put in the group by the
+        // authorizer, is held to `acl.default`
+        let session = acl.session(1, None, &[]).unwrap();
+        assert!(!session.check(&tcp([8, 8, 8, 8], 443)));
+        assert!(acl.session(2, Some("alice"), &[]).is_none());
+        assert!(acl.session(3, None, &["contractors".to_string()]).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        let settings = |config: &str| toml::from_str::<AclSettings>(config).unwrap();
+        let ports_without_protocol = settings(
+            r#"
+            [groups.ops]
+            rules = [{ action = "allow", ports = [22] }]
+        "#,
+        );
+        assert!(Acl::from_settings(&ports_without_protocol).is_err());
+        let user_members = settings(
+            r#"
+            [users.alice]
+            members = ["bob"]
+        "#,
+        );
+        assert!(Acl::from_settings(&user_members).is_err());
+        assert!(Acl::from_settings(&AclSettings::default())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! Session authentication performed in the handshake, before the server
+//! accepts any data from the client.
+//!
+//! A pre-shared key is never sent on the wire. Instead the client proves
+//! knowledge of it with `HMAC-SHA256(psk, exporter)`, where `exporter` is
+//! keying material exported from this connection's TLS session (RFC 5705).
+//! Both peers derive the same exporter value, but it is unique per
+//! connection, so a captured proof is useless on any other connection.
+//!
+//! Clients may present a signed token instead; see [`crate::token`].
+
+use crate::token::{TokenClaims, TokenError, TokenKeySet};
+use quinn::Connection;
+use ring::hmac;
+use std::fmt;
+use xeonvpn_core::control::Credentials;
+
+/// TLS exporter label for PSK proofs.
+pub const PSK_EXPORTER_LABEL: &[u8] = b"EXPORTER-xeonvpn-psk-v1";
+/// Shortest accepted pre-shared key, in bytes.
+pub const MIN_PSK_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The TLS session could not export keying material.
+    Exporter,
+    /// The client did not present the credentials the server requires.
+    MissingCredentials,
+    /// The client presented credentials of a method the server does not
+    /// accept.
+    UnsupportedMethod,
+    /// The presented credentials did not verify.
+    InvalidCredentials,
+    /// The presented token was rejected.
+    Token(TokenError),
+    /// The client certificate is on the deny-list or the CRL.
+    Revoked,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Exporter => write!(f, "TLS keying material export failed"),
+            AuthError::MissingCredentials => write!(f, "no credentials presented"),
+            AuthError::UnsupportedMethod => write!(f, "credential method not accepted"),
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+            AuthError::Token(e) => e.fmt(f),
+            AuthError::Revoked => write!(f, "client certificate revoked"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Validate and normalize a key read from a file or config value.
+pub fn parse_psk(raw: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let key = raw.trim().as_bytes().to_vec();
+    if key.len() < MIN_PSK_LEN {
+        return Err(format!("pre-shared key must be at least {MIN_PSK_LEN} bytes").into());
+    }
+    Ok(key)
+}
+
+fn exporter(conn: &Connection, label: &[u8]) -> Result<[u8; 32], AuthError> {
+    let mut out = [0u8; 32];
+    conn.export_keying_material(&mut out, label, b"")
+        .map_err(|_| AuthError::Exporter)?;
+    Ok(out)
+}
+
+/// Client side: proof of knowledge of `psk`, bound to this connection.
+pub fn psk_proof(conn: &Connection, psk: &[u8]) -> Result<Vec<u8>, AuthError> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, psk);
+    let tag = hmac::sign(&key, &exporter(conn, PSK_EXPORTER_LABEL)?);
+    Ok(tag.as_ref().to_vec())
+}
+
+/// Server side: check a client's PSK proof in constant time.
+pub fn verify_psk(conn: &Connection, psk: &[u8], proof: &[u8]) -> Result<(), AuthError> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, psk);
+    hmac::verify(&key, &exporter(conn, PSK_EXPORTER_LABEL)?, proof)
+        .map_err(|_| AuthError::InvalidCredentials)
+}
+
+/// Server side: check the credentials a client presented against the
+/// configured pre-shared key and token keys, either of which will do.
+/// Returns the claims of a verified token.
+pub fn authenticate(
+    conn: &Connection,
+    psk: Option<&[u8]>,
+    tokens: Option<&TokenKeySet>,
+    credentials: Option<&Credentials>,
+) -> Result<Option<TokenClaims>, AuthError> {
+    if psk.is_none() && tokens.is_none() {
+        return Ok(None);
+    }
+    match (credentials, psk, tokens) {
+        (Some(Credentials::Psk { proof }), Some(psk), _) => {
+            verify_psk(conn, psk, proof).map(|()| None)
+        }
+        (Some(Credentials::Token { token }), _, Some(tokens)) => {
+            tokens.verify(token).map(Some).map_err(AuthError::Token)
+        }
+        (Some(_), _, _) => Err(AuthError::UnsupportedMethod),
+        (None, _, _) => Err(AuthError::MissingCredentials),
+    }
+}
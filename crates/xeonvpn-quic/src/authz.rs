@@ -0,0 +1,380 @@
+//! External authorization: every new session is put to an outside service,
+//! which allows or denies it and may override its settings.
+//!
+//! The server sends an [`AuthzRequest`] as JSON and expects an
+//! [`AuthzDecision`] back, either from a program it runs (request on stdin,
+//! decision on stdout, exit status 0) or from an HTTP service on a Unix
+//! socket (`POST` to the configured path, decision in a `2xx` response):
+//!
+//! ```json
+//! {"session_id": 7, "identity": "alice", "identity_source": "token",
+//!  "client_id": "alice-laptop", "remote": "203.0.113.5:50123",
+//!  "region": "sg", "bandwidth_class": "gold",
+//!  "capabilities": {"datagrams": true, "offload": false, "batching": true, "fragments": true, "probes": true},
+//!  "compression": ["lz4"]}
+//! ```
+//! ```json
+//! {"allow": true, "address": "10.123.0.20", "routes": ["192.168.50.0/24"], "rate_limit_kbps": 20000,
+//!  "groups": ["contractors"]}
+//! {"allow": false, "reason": "account suspended"}
+//! ```
+//!
+//! Sessions are refused when the authorizer denies them, and also when it
+//! fails or does not answer in time.
+
+use crate::config::AuthorizerSettings;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use xeonvpn_core::compress::Compression;
+use xeonvpn_core::control::Capabilities;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Largest response read from an HTTP authorizer.
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// What the authorizer is told about a session.
+#[derive(Debug, Serialize)]
+pub struct AuthzRequest {
+    pub session_id: u64,
+    /// Identity the session runs under.
+    pub identity: String,
+    /// Where `identity` comes from.
+    pub identity_source: IdentitySource,
+    /// Identifier the client declared in its hello, if any.
+    pub client_id: Option<String>,
+    pub remote: SocketAddr,
+    /// Region of this server.
+    pub region: Option<String>,
+    /// Bandwidth class from the user database or the client's token.
+    pub bandwidth_class: Option<String>,
+    /// Features the client asked for.
+    pub capabilities: Capabilities,
+    /// Compression the client accepts, preferred first.
+    pub compression: Vec<Compression>,
+}
+
+/// Where the identity of a session comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentitySource {
+    Certificate,
+    Token,
+    ClientId,
+    /// None given; the identity is made up from the remote address.
+    Anonymous,
+}
+
+/// The authorizer's answer. Overrides apply to this session only.
+#[derive(Debug, Deserialize)]
+pub struct AuthzDecision {
+    pub allow: bool,
+    /// Why the session was denied, passed on to the client.
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Tunnel address to assign instead of a leased one.
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    /// Routes to push instead of the configured ones; also the only
+    /// destinations outside the tunnel subnet the session may reach.
+    #[serde(default)]
+    pub routes: Option<Vec<IpNet>>,
+    /// Limit on each direction of the session, in kilobits per second.
+    #[serde(default)]
+    pub rate_limit_kbps: Option<u64>,
+    /// ACL groups the session is in, on top of its user's; see
+    /// [`crate::acl`].
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+enum Target {
+    Command(Vec<String>),
+    Http { socket: PathBuf, path: String },
+}
+
+/// Client of the configured authorizer.
+pub struct Authorizer {
+    target: Target,
+    timeout: Duration,
+}
+
+impl Authorizer {
+    pub fn new(settings: &AuthorizerSettings) -> Self {
+        let target = match &settings.socket {
+            Some(socket) => Target::Http {
+                socket: socket.clone(),
+                path: settings.path.clone(),
+            },
+            None => Target::Command(settings.command.clone()),
+        };
+        Self {
+            target,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        }
+    }
+
+    /// Where requests go, for logging.
+    pub fn describe(&self) -> String {
+        match &self.target {
+            Target::Command(command) => format!("command {}", command.join(" ")),
+            Target::Http { socket, path } => format!("http://unix:{}{path}", socket.display()),
+        }
+    }
+
+    /// Ask whether the session in `request` may start.
+    pub async fn authorize(&self, request: &AuthzRequest) -> Result<AuthzDecision, BoxError> {
+        let body = serde_json::to_vec(request)?;
+        let call = async {
+            match &self.target {
+                Target::Command(command) => run_command(command, &body).await,
+                Target::Http { socket, path } => post_unix(socket, path, &body).await,
+            }
+        };
+        let response = tokio::time::timeout(self.timeout, call)
+            .await
+            .map_err(|_| format!("no answer within {} ms", self.timeout.as_millis()))??;
+        serde_json::from_slice(&response).map_err(|e| format!("invalid decision: {e}").into())
+    }
+}
+
+async fn run_command(command: &[String], body: &[u8]) -> Result<Vec<u8>, BoxError> {
+    let (program, args) = command.split_first().ok_or("empty command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("cannot run {program}: {e}"))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(body).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(format!("{program} exited with {}", output.status).into());
+    }
+    Ok(output.stdout)
+}
+
+/// `POST` `body` as JSON to `path` over the Unix socket `socket`.
+async fn post_unix(socket: &Path, path: &str, body: &[u8]) -> Result<Vec<u8>, BoxError> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("cannot connect to {}: {e}", socket.display()))?;
+    let head = format!(
+        "POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_RESPONSE {
+        return Err(format!("HTTP response over {MAX_RESPONSE} bytes").into());
+    }
+    let (status, body) = parse_response(&response)?;
+    if !(200..300).contains(&status) {
+        return Err(format!("HTTP status {status}").into());
+    }
+    Ok(body)
+}
+
+/// Status and body of an HTTP/1.1 response read until the server closed
+/// the connection.
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), BoxError> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("incomplete HTTP response")?;
+    let head = std::str::from_utf8(&response[..end])?;
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or("invalid HTTP status line")?;
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse::<usize>()?);
+        }
+    }
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        match length {
+            Some(len) => body.get(..len).ok_or("truncated HTTP body")?.to_vec(),
+            None => body.to_vec(),
+        }
+    };
+    Ok((status, body))
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, BoxError> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or("truncated chunk")?;
+        let size = std::str::from_utf8(&data[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(data.get(..size).ok_or("truncated chunk")?);
+        data = match data[size..].strip_prefix(b"\r\n") {
+            Some(rest) => rest,
+            None if data.len() < size + 2 => return Err("truncated chunk".into()),
+            None => return Err("chunk longer than its size".into()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(response: &str) -> Result<(u16, Vec<u8>), String> {
+        parse_response(response.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_sized_and_unsized_bodies() {
+        let sized = "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n{\"a\": true}trailing";
+        assert_eq!(parse(sized).unwrap(), (200, b"{\"a\": true}".to_vec()));
+        let unsized_body = "HTTP/1.1 403 Forbidden\r\nconnection: close\r\n\r\nno";
+        assert_eq!(parse(unsized_body).unwrap(), (403, b"no".to_vec()));
+    }
+
+    #[test]
+    fn parses_chunked_bodies() {
+        let response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5\r\n{\"all\r\n\
+                        a;ext=1\r\now\": true}\r\n\
+                        0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(
+            parse(response).unwrap(),
+            (200, b"{\"allow\": true}".to_vec())
+        );
+        let empty = "HTTP/1.1 204 No Content\r\ntransfer-encoding: CHUNKED\r\n\r\n0\r\n\r\n";
+        assert_eq!(parse(empty).unwrap(), (204, Vec::new()));
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        for response in [
+            "HTTP/1.1 200 OK\r\nContent-Length: 4",
+            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n{}",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n{}\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n",
+        ] {
+            assert!(parse(response).is_err(), "{response:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        for response in [
+            "HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 OK\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: many\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n{}\r\n0\r\n\r\n",
+        ] {
+            assert!(parse(response).is_err(), "{response:?}");
+        }
+    }
+
+    const REQUEST: &[u8] = b"{\"session_id\": 1}";
+
+    /// Answer one request on a fresh Unix socket with `response`, and
+    /// return the socket path.
+    fn serve(name: &str, response: Vec<u8>) -> PathBuf {
+        let socket = std::env::temp_dir().join(format!(
+            "xeonvpn-authz-test-{}-{name}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // The request is read in full so the client never writes to a
+            // closed socket
+            let mut request = Vec::new();
+            while !request.ends_with(REQUEST) {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "request ended early");
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(&response).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        socket
+    }
+
+    async fn post(name: &str, response: Vec<u8>) -> Result<Vec<u8>, String> {
+        let socket = serve(name, response);
+        let result = post_unix(&socket, "/authorize", REQUEST).await;
+        std::fs::remove_file(&socket).unwrap();
+        result.map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn posts_over_unix_socket() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         f\r\n{\"allow\": true}\r\n0\r\n\r\n";
+        assert_eq!(
+            post("chunked", response.to_vec()).await.unwrap(),
+            b"{\"allow\": true}"
+        );
+        let response = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+        let err = post("status", response.to_vec()).await.unwrap_err();
+        assert_eq!(err, "HTTP status 503");
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"allow\": ";
+        let err = post("truncated", response.to_vec()).await.unwrap_err();
+        assert_eq!(err, "truncated HTTP body");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_responses() {
+        let body = format!(
+            "{{\"allow\": true, \"reason\": \"{}\"}}",
+            "x".repeat(70_000)
+        );
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let err = post("oversized", response.into_bytes()).await.unwrap_err();
+        assert_eq!(err, format!("HTTP response over {MAX_RESPONSE} bytes"));
+    }
+}
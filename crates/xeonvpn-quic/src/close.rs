@@ -0,0 +1,85 @@
+//! Carrying a [`SessionClose`] over QUIC: close codes on connections and
+//! streams, and the close recovered from the errors they end with.
+
+use quinn::{Connection, ConnectionError, ReadError, VarInt, WriteError};
+use std::io;
+use xeonvpn_core::close::{CloseCode, SessionClose};
+use xeonvpn_core::frame::ProtocolError;
+
+/// The QUIC error code for `code`.
+pub fn error_code(code: CloseCode) -> VarInt {
+    VarInt::from(u16::from(code))
+}
+
+fn from_error_code(code: VarInt) -> CloseCode {
+    u16::try_from(code.into_inner()).map_or(CloseCode::Other(u16::MAX), CloseCode::from)
+}
+
+/// Close `conn` with the code and reason of `close`.
+pub fn close_connection(conn: &Connection, close: &SessionClose) {
+    conn.close(error_code(close.code), close.reason.as_bytes());
+}
+
+/// How a connection that ended with `e` was closed. Failures below the
+/// session layer map to the closest code.
+pub fn connection_closed(e: &ConnectionError) -> SessionClose {
+    match e {
+        ConnectionError::ApplicationClosed(close) => SessionClose::new(
+            from_error_code(close.error_code),
+            String::from_utf8_lossy(&close.reason),
+        ),
+        ConnectionError::TimedOut => SessionClose::new(CloseCode::IdleTimeout, e.to_string()),
+        ConnectionError::VersionMismatch => {
+            SessionClose::new(CloseCode::VersionMismatch, e.to_string())
+        }
+        ConnectionError::TransportError(_) | ConnectionError::ConnectionClosed(_) => {
+            SessionClose::new(CloseCode::ProtocolViolation, e.to_string())
+        }
+        // A stateless reset: the peer lost the connection, e.g. in a restart
+        ConnectionError::Reset => SessionClose::new(CloseCode::Unavailable, e.to_string()),
+        ConnectionError::LocallyClosed => SessionClose::new(CloseCode::Normal, e.to_string()),
+    }
+}
+
+/// The close behind a failed stream read, if the peer or the connection
+/// gave one.
+pub fn read_closed(e: &ReadError) -> Option<SessionClose> {
+    match e {
+        ReadError::Reset(code) => Some(SessionClose::new(from_error_code(*code), e.to_string())),
+        ReadError::ConnectionLost(e) => Some(connection_closed(e)),
+        _ => None,
+    }
+}
+
+/// The close behind a failed stream write, if the peer or the connection
+/// gave one.
+pub fn write_closed(e: &WriteError) -> Option<SessionClose> {
+    match e {
+        WriteError::Stopped(code) => Some(SessionClose::new(from_error_code(*code), e.to_string())),
+        WriteError::ConnectionLost(e) => Some(connection_closed(e)),
+        _ => None,
+    }
+}
+
+/// The close behind an error reading frames from a stream: the stream or
+/// connection error inside an I/O error, or what to tell a peer that sent a
+/// bad frame.
+pub fn frame_closed(e: &ProtocolError) -> Option<SessionClose> {
+    match e {
+        ProtocolError::Io(e) => io_closed(e),
+        e => Some(SessionClose::protocol(e)),
+    }
+}
+
+fn io_closed(e: &io::Error) -> Option<SessionClose> {
+    let inner = e.get_ref()?;
+    if let Some(e) = inner.downcast_ref::<ReadError>() {
+        read_closed(e)
+    } else if let Some(e) = inner.downcast_ref::<WriteError>() {
+        write_closed(e)
+    } else {
+        inner
+            .downcast_ref::<ConnectionError>()
+            .map(connection_closed)
+    }
+}
@@ -0,0 +1,345 @@
+//! Server-side tunnel settings, loaded from a TOML file.
+//!
+//! ```toml
+//! listen = "[::]:4433"
+//! tun_name = "xeonvpnS0"
+//! subnet = "10.123.0.0/24"
+//! mtu = 1400
+//! tun_queues = 8
+//! tun_offload = true
+//! dns = ["10.123.0.1"]
+//! routes = ["10.123.0.0/24", "192.168.50.0/24"]
+//! region = "sg"
+//!
+//! [tls]
+//! cert_file = "/etc/xeonvpn/server.pem"
+//! key_file = "/etc/xeonvpn/server.key"
+//!
+//! [transport]
+//! congestion = "bbr"
+//! idle_timeout_secs = 30
+//!
+//! [auth]
+//! psk_file = "/etc/xeonvpn/psk"
+//! client_ca_file = "/etc/xeonvpn/client-ca.pem"
+//! crl_file = "/etc/xeonvpn/client-ca.crl"
+//! deny_list_file = "/etc/xeonvpn/denied-certs.txt"
+//! token_leeway_secs = 60
+//! users_file = "/etc/xeonvpn/users.toml"
+//!
+//! [[auth.token_keys]]
+//! id = "2026-10"
+//! algorithm = "ed25519"
+//! key_file = "/etc/xeonvpn/tokens/2026-10.pub"
+//!
+//! [auth.authorizer]
+//! socket = "/run/access/authz.sock"
+//! path = "/vpn/authorize"
+//!
+//! [acl]
+//! log_denied = true
+//!
+//! [acl.groups.contractors]
+//! members = ["alice-laptop"]
+//! default = "deny"
+//! rules = [{ action = "allow", dst = ["10.20.0.0/16"], protocol = "tcp", ports = [443] }]
+//!
+//! [ipam]
+//! lease_file = "/var/lib/xeonvpn/leases.json"
+//! idle_timeout_secs = 86400
+//!
+//! [ipam.reservations]
+//! "alice-laptop" = "10.123.0.10"
+//! ```
+
+use crate::acl::{AclAction, AclRule};
+use crate::token::TokenAlgorithm;
+use crate::transport::TransportSettings;
+use ipnet::{IpNet, Ipv4Net};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunServerSettings {
+    /// UDP address the server listens on. The default `[::]:4433` accepts
+    /// both IPv6 and IPv4 clients.
+    pub listen: SocketAddr,
+    /// Name of the server TUN device.
+    pub tun_name: String,
+    /// Tunnel subnet. The server takes the first host address; clients are
+    /// assigned the remaining ones.
+    pub subnet: Ipv4Net,
+    /// MTU of the server TUN and the MTU pushed to clients.
+    pub mtu: u16,
+    /// Queues of the server TUN, each served by its own packet worker.
+    /// `0` opens one per CPU core.
+    pub tun_queues: usize,
+    /// Open the server TUN with virtio-net headers and TCP segmentation
+    /// offloads, and exchange unsegmented packets with clients that do too.
+    pub tun_offload: bool,
+    /// DNS servers pushed to clients.
+    pub dns: Vec<IpAddr>,
+    /// Routes pushed to clients. The tunnel subnet itself is always reachable.
+    pub routes: Vec<IpNet>,
+    /// Region this server is in. Tokens limited to a list of regions are
+    /// only accepted when it is on the list.
+    pub region: Option<String>,
+    pub tls: TlsSettings,
+    /// QUIC transport tuning, see [`crate::transport`].
+    pub transport: TransportSettings,
+    pub auth: AuthSettings,
+    /// Uplink access control, see [`crate::acl`].
+    pub acl: AclSettings,
+    pub ipam: IpamSettings,
+}
+
+/// Certificate the server presents to clients.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// Certificate chain, leaf first (PEM or DER).
+    pub cert_file: Option<PathBuf>,
+    /// Private key for `cert_file` (PEM or DER).
+    pub key_file: Option<PathBuf>,
+    /// Generate a throwaway self-signed certificate on every start instead.
+    /// Every restart invalidates the certificate clients trust, so this is
+    /// only meant for local testing.
+    pub ephemeral: bool,
+}
+
+/// Client authentication settings. Sessions are unauthenticated when no
+/// method is configured. A client presents either the pre-shared key or a
+/// token when both are configured; a configured client CA applies on top.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// File holding the pre-shared key every client must prove knowledge of.
+    pub psk_file: Option<PathBuf>,
+    /// CA (PEM or DER) that must have issued every client certificate.
+    /// Enables mutual TLS.
+    pub client_ca_file: Option<PathBuf>,
+    /// Certificate revocation list(s) from the client CA, PEM or DER.
+    pub crl_file: Option<PathBuf>,
+    /// Revoked client certificates as SHA-256 fingerprints in hex, one per
+    /// line; `#` starts a comment.
+    pub deny_list_file: Option<PathBuf>,
+    /// How often the CRL and deny-list are checked for changes, in seconds.
+    pub revocation_reload_secs: u64,
+    /// Keys session tokens may be signed with; see [`crate::token`].
+    pub token_keys: Vec<TokenKeySettings>,
+    /// Seconds an expired token is still accepted, for clock skew between
+    /// the token issuer and the server.
+    pub token_leeway_secs: u64,
+    /// User database; see [`crate::users`]. Every session needs an enabled
+    /// entry when set.
+    pub users_file: Option<PathBuf>,
+    /// How often the user database is checked for changes and expired
+    /// users, in seconds.
+    pub users_reload_secs: u64,
+    /// External service every session must be allowed by; see
+    /// [`crate::authz`].
+    pub authorizer: Option<AuthorizerSettings>,
+}
+
+/// A key in the token key set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenKeySettings {
+    /// Name tokens refer to the key by.
+    pub id: String,
+    pub algorithm: TokenAlgorithm,
+    /// The HMAC secret (at least 16 bytes), or the Ed25519 public key as
+    /// PEM.
+    pub key_file: PathBuf,
+}
+
+/// How to reach the external authorizer: a program to run, or an HTTP
+/// endpoint on a Unix socket.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthorizerSettings {
+    /// Program and arguments run for every session.
+    pub command: Vec<String>,
+    /// Unix socket of an HTTP service asked for every session.
+    pub socket: Option<PathBuf>,
+    /// Request path on `socket`.
+    pub path: String,
+    /// Time the authorizer has to answer before the session is refused.
+    pub timeout_ms: u64,
+}
+
+impl Default for AuthorizerSettings {
+    fn default() -> Self {
+        Self {
+            command: Vec::new(),
+            socket: None,
+            path: "/authorize".into(),
+            timeout_ms: 2000,
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            psk_file: None,
+            client_ca_file: None,
+            crl_file: None,
+            deny_list_file: None,
+            revocation_reload_secs: 30,
+            token_keys: Vec::new(),
+            token_leeway_secs: 60,
+            users_file: None,
+            users_reload_secs: 5,
+            authorizer: None,
+        }
+    }
+}
+
+impl AuthSettings {
+    /// Read the pre-shared key, if one is configured.
+    pub fn load_psk(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(path) = &self.psk_file else {
+            return Ok(None);
+        };
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read PSK file {}: {e}", path.display()))?;
+        crate::auth::parse_psk(&raw).map(Some)
+    }
+}
+
+/// Uplink access control policies.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclSettings {
+    /// Action for packets of sessions no policy decides.
+    pub default: AclAction,
+    /// Log the first packet of every flow a session is denied.
+    pub log_denied: bool,
+    /// Policies by group.
+    pub groups: BTreeMap<String, AclPolicySettings>,
+    /// Policies by user, tried before their groups'.
+    pub users: BTreeMap<String, AclPolicySettings>,
+}
+
+impl Default for AclSettings {
+    fn default() -> Self {
+        Self {
+            default: AclAction::Allow,
+            log_denied: false,
+            groups: BTreeMap::new(),
+            users: BTreeMap::new(),
+        }
+    }
+}
+
+/// The rules of one user or group.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclPolicySettings {
+    /// Users in the group.
+    pub members: Vec<String>,
+    /// Action for packets no rule matches.
+    pub default: Option<AclAction>,
+    /// Rules tried in order; the first match decides.
+    pub rules: Vec<AclRule>,
+}
+
+/// Tunnel address allocation settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpamSettings {
+    /// Where leases are persisted across restarts. Leases are kept in memory
+    /// only when unset.
+    pub lease_file: Option<PathBuf>,
+    /// Seconds a lease survives without any connected session before its
+    /// address may be handed to another client.
+    pub idle_timeout_secs: u64,
+    /// Fixed addresses per client identity.
+    pub reservations: BTreeMap<String, Ipv4Addr>,
+}
+
+impl Default for IpamSettings {
+    fn default() -> Self {
+        Self {
+            lease_file: None,
+            idle_timeout_secs: 24 * 60 * 60,
+            reservations: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for TunServerSettings {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), crate::endpoint::DEFAULT_PORT),
+            tun_name: "xeonvpnS0".into(),
+            subnet: Ipv4Net::new(Ipv4Addr::new(10, 123, 0, 0), 24).unwrap(),
+            mtu: 1500,
+            tun_queues: 0,
+            tun_offload: false,
+            dns: Vec::new(),
+            routes: Vec::new(),
+            region: None,
+            tls: TlsSettings::default(),
+            transport: TransportSettings::default(),
+            auth: AuthSettings::default(),
+            acl: AclSettings::default(),
+            ipam: IpamSettings::default(),
+        }
+    }
+}
+
+impl TunServerSettings {
+    /// Load settings from a TOML file; missing keys keep their defaults.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let text = std::fs::read_to_string(path)?;
+        let settings: Self = toml::from_str(&text)?;
+        if settings.subnet.prefix_len() > 30 {
+            return Err(format!("tunnel subnet {} is too small", settings.subnet).into());
+        }
+        if settings.tls.cert_file.is_some() != settings.tls.key_file.is_some() {
+            return Err("tls.cert_file and tls.key_file must be set together".into());
+        }
+        settings
+            .transport
+            .transport_config()
+            .and(settings.transport.endpoint_config())
+            .map_err(|e| format!("invalid [transport] settings: {e}"))?;
+        if let Some(authorizer) = &settings.auth.authorizer {
+            if authorizer.command.is_empty() == authorizer.socket.is_none() {
+                return Err("auth.authorizer needs exactly one of command and socket".into());
+            }
+        }
+        let server = settings.server_addr().addr();
+        for (client, addr) in &settings.ipam.reservations {
+            if !settings.subnet.contains(addr) || *addr == server {
+                return Err(format!(
+                    "reservation {addr} for {client} is not a client address in {}",
+                    settings.subnet
+                )
+                .into());
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Number of TUN queues to open, resolving `0` to the number of cores.
+    pub fn tun_queues(&self) -> usize {
+        match self.tun_queues {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    /// Address (with prefix) of the server end of the tunnel.
+    pub fn server_addr(&self) -> Ipv4Net {
+        let first = self.subnet.hosts().next().unwrap_or(self.subnet.addr());
+        Ipv4Net::new(first, self.subnet.prefix_len()).unwrap()
+    }
+}
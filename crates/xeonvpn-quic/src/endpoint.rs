@@ -0,0 +1,217 @@
+//! UDP endpoint setup and server address selection.
+//!
+//! Endpoints bind dual-stack IPv6 sockets where the host allows it, so one
+//! socket serves both IPv4 and IPv6 peers. Clients resolve every configured
+//! server to its A and AAAA records and race the addresses in the style of
+//! Happy Eyeballs (RFC 8305): attempts start a short delay apart, alternating
+//! address families, and the first completed handshake wins.
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use quinn::{Connection, Endpoint, EndpointConfig, ServerConfig, TokioRuntime};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Port used when a server entry does not name one.
+pub const DEFAULT_PORT: u16 = 4433;
+
+/// Delay before racing the next address while earlier attempts are pending.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Bind a UDP socket. An unspecified IPv6 address is bound dual-stack so it
+/// also accepts IPv4 (as v4-mapped addresses).
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Create a server endpoint on `addr`, falling back to IPv4 when an
+/// unspecified IPv6 address cannot be bound on this host.
+pub fn server_endpoint(
+    endpoint_config: EndpointConfig,
+    config: ServerConfig,
+    addr: SocketAddr,
+) -> io::Result<Endpoint> {
+    let socket = match bind_udp(addr) {
+        Err(e) if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            warn!("cannot bind {addr} ({e}); listening on IPv4 only");
+            bind_udp(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()))?
+        }
+        other => other?,
+    };
+    Endpoint::new(
+        endpoint_config,
+        Some(config),
+        socket,
+        Arc::new(TokioRuntime),
+    )
+}
+
+/// Create a client endpoint on an ephemeral port, dual-stack if possible.
+pub fn client_endpoint(endpoint_config: EndpointConfig) -> io::Result<Endpoint> {
+    let socket = bind_udp(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)).or_else(|e| {
+        debug!("no dual-stack socket ({e}); using IPv4 only");
+        bind_udp(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+    })?;
+    Endpoint::new(endpoint_config, None, socket, Arc::new(TokioRuntime))
+}
+
+/// A server entry: `host`, `host:port`, `v4addr[:port]`, `v6addr` or
+/// `[v6addr]:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::str::FromStr for ServerAddr {
+    type Err = BoxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+            });
+        }
+        let bare = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+        if let Ok(ip) = bare.unwrap_or(s).parse::<IpAddr>() {
+            return Ok(Self {
+                host: ip.to_string(),
+                port: DEFAULT_PORT,
+            });
+        }
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| format!("bad port in {s:?}"))?,
+            ),
+            None => (s, DEFAULT_PORT),
+        };
+        if host.is_empty() || host.contains(['[', ']']) {
+            return Err(format!("invalid host in {s:?}").into());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Resolve every server to its addresses, ordered for racing: IPv6 first,
+/// then alternating families, keeping resolver order within a family.
+/// Servers that fail to resolve are skipped.
+pub async fn resolve(servers: &[ServerAddr]) -> Vec<SocketAddr> {
+    let mut v6 = Vec::new();
+    let mut v4 = Vec::new();
+    for server in servers {
+        match tokio::net::lookup_host((server.host.as_str(), server.port)).await {
+            Ok(addrs) => {
+                for addr in addrs {
+                    let family = if addr.is_ipv6() { &mut v6 } else { &mut v4 };
+                    if !family.contains(&addr) {
+                        family.push(addr);
+                    }
+                }
+            }
+            Err(e) => warn!("failed to resolve {server}: {e}"),
+        }
+    }
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+/// Connect to the first of `addrs` that completes a handshake. A new attempt
+/// starts every [`ATTEMPT_DELAY`], or right away when all pending attempts
+/// have failed; the losers are abandoned once one succeeds.
+pub async fn connect_any(
+    endpoint: &Endpoint,
+    addrs: &[SocketAddr],
+    server_name: &str,
+) -> Result<Connection, BoxError> {
+    let mut pending = FuturesUnordered::new();
+    let mut remaining = addrs.iter();
+    let mut last_err: Option<BoxError> = None;
+    loop {
+        if pending.is_empty() {
+            // Nothing in flight: start the next address immediately
+            let Some(&addr) = remaining.next() else {
+                return Err(last_err.unwrap_or_else(|| "no server addresses to try".into()));
+            };
+            start(endpoint, addr, server_name, &mut pending, &mut last_err);
+            continue;
+        }
+        tokio::select! {
+            Some((addr, result)) = pending.next() => match result {
+                Ok(conn) => {
+                    info!("connected to {addr}");
+                    return Ok(conn);
+                }
+                Err(e) => {
+                    warn!("connection attempt to {addr} failed: {e}");
+                    last_err = Some(e.into());
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if remaining.len() > 0 => {
+                if let Some(&addr) = remaining.next() {
+                    start(endpoint, addr, server_name, &mut pending, &mut last_err);
+                }
+            }
+        }
+    }
+}
+
+type Attempt = std::pin::Pin<
+    Box<
+        dyn std::future::Future<Output = (SocketAddr, Result<Connection, quinn::ConnectionError>)>
+            + Send,
+    >,
+>;
+
+fn start(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+    pending: &mut FuturesUnordered<Attempt>,
+    last_err: &mut Option<BoxError>,
+) {
+    debug!("trying {addr}");
+    match endpoint.connect(addr, server_name) {
+        Ok(connecting) => pending.push(Box::pin(async move { (addr, connecting.await) })),
+        Err(e) => {
+            warn!("cannot connect to {addr}: {e}");
+            *last_err = Some(e.into());
+        }
+    }
+}
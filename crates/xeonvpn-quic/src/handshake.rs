@@ -0,0 +1,167 @@
+//! Session handshake on the control stream.
+//!
+//! The client opens the first bidi stream of a connection as the control
+//! stream and sends a [`ClientHello`]. The server replies with the
+//! [`SessionParams`] it assigned (or a rejection), after which the client
+//! opens its data stream. The control stream stays open for the lifetime of
+//! the session.
+
+use crate::close::{connection_closed, frame_closed};
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+use std::{fmt, time::Duration};
+use xeonvpn_core::{
+    close::{CloseCode, SessionClose},
+    control::{ClientHello, ControlMessage, SessionParams},
+    frame::{Frame, FrameRead, FrameWrite, ProtocolError},
+};
+
+/// How long either side waits for the other's handshake message.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Both halves of a session's control stream.
+pub struct ControlStream {
+    pub send: SendStream,
+    pub recv: RecvStream,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Connection(ConnectionError),
+    Protocol(ProtocolError),
+    /// The peer sent a well-formed message that is not valid at this point.
+    Unexpected(&'static str),
+    /// The server refused the session.
+    Rejected(SessionClose),
+    Timeout,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Connection(e) => write!(f, "connection failed during handshake: {e}"),
+            HandshakeError::Protocol(e) => write!(f, "handshake protocol error: {e}"),
+            HandshakeError::Unexpected(what) => write!(f, "unexpected handshake message: {what}"),
+            HandshakeError::Rejected(reason) => write!(f, "server rejected session: {reason}"),
+            HandshakeError::Timeout => write!(f, "handshake timed out"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl HandshakeError {
+    /// The close code and reason this failure amounts to: the server's for
+    /// a rejection or a closed connection, the closest match otherwise.
+    pub fn session_close(&self) -> SessionClose {
+        match self {
+            HandshakeError::Connection(e) => connection_closed(e),
+            HandshakeError::Protocol(e) => {
+                frame_closed(e).unwrap_or_else(|| SessionClose::protocol(e))
+            }
+            HandshakeError::Unexpected(what) => {
+                SessionClose::new(CloseCode::ProtocolViolation, *what)
+            }
+            HandshakeError::Rejected(close) => close.clone(),
+            HandshakeError::Timeout => SessionClose::new(CloseCode::IdleTimeout, self.to_string()),
+        }
+    }
+}
+
+impl From<ProtocolError> for HandshakeError {
+    fn from(e: ProtocolError) -> Self {
+        HandshakeError::Protocol(e)
+    }
+}
+
+impl From<ConnectionError> for HandshakeError {
+    fn from(e: ConnectionError) -> Self {
+        HandshakeError::Connection(e)
+    }
+}
+
+/// Read the next control message, answering pings along the way.
+pub async fn read_control(ctrl: &mut ControlStream) -> Result<ControlMessage, HandshakeError> {
+    loop {
+        match ctrl.recv.read_frame().await? {
+            Some(Frame::Control(payload)) => return Ok(ControlMessage::from_payload(&payload)?),
+            Some(Frame::Ping(nonce)) => ctrl.send.write_frame(&Frame::Pong(nonce)).await?,
+            Some(Frame::Pong(_)) => {}
+            Some(Frame::Close { code, reason }) => {
+                return Err(HandshakeError::Rejected(SessionClose::new(
+                    code.into(),
+                    reason,
+                )))
+            }
+            Some(
+                Frame::Data(_)
+                | Frame::Offload(_)
+                | Frame::Batch(_)
+                | Frame::Compressed(_)
+                | Frame::Fragment(_)
+                | Frame::Probe { .. },
+            ) => return Err(HandshakeError::Unexpected("data on control stream")),
+            None => return Err(HandshakeError::Unexpected("control stream closed")),
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    fut: impl std::future::Future<Output = Result<T, HandshakeError>>,
+) -> Result<T, HandshakeError> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, fut)
+        .await
+        .unwrap_or(Err(HandshakeError::Timeout))
+}
+
+/// Client side: open the control stream, send `hello` and wait for the
+/// server's session parameters.
+pub async fn client_handshake(
+    conn: &Connection,
+    hello: ClientHello,
+) -> Result<(SessionParams, ControlStream), HandshakeError> {
+    with_timeout(async {
+        let (send, recv) = conn.open_bi().await?;
+        let mut ctrl = ControlStream { send, recv };
+        ctrl.send
+            .write_frame(&ControlMessage::Hello(hello).to_frame())
+            .await?;
+        match read_control(&mut ctrl).await? {
+            ControlMessage::Welcome(params) => Ok((params, ctrl)),
+            ControlMessage::Reject { reason, code } => Err(HandshakeError::Rejected(
+                SessionClose::new(code.into(), reason),
+            )),
+            ControlMessage::Hello(_) => Err(HandshakeError::Unexpected("hello from server")),
+        }
+    })
+    .await
+}
+
+/// Server side: accept the control stream and read the client's hello. The
+/// caller answers with [`ControlMessage::Welcome`] or [`ControlMessage::Reject`].
+pub async fn accept_handshake(
+    conn: &Connection,
+) -> Result<(ClientHello, ControlStream), HandshakeError> {
+    with_timeout(async {
+        let (send, recv) = conn.accept_bi().await?;
+        let mut ctrl = ControlStream { send, recv };
+        match read_control(&mut ctrl).await? {
+            ControlMessage::Hello(hello) => Ok((hello, ctrl)),
+            _ => Err(HandshakeError::Unexpected("expected hello")),
+        }
+    })
+    .await
+}
+
+/// Keep a session's control stream serviced after the handshake: answer
+/// pings and return once the peer closes it.
+pub async fn serve_control(mut ctrl: ControlStream) {
+    loop {
+        match read_control(&mut ctrl).await {
+            Ok(msg) => tracing::warn!("ignoring control message {msg:?}"),
+            Err(e) => {
+                tracing::debug!("control stream ended: {e}");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Tunnel address allocation for connected clients.
+//!
+//! Every client identity holds a lease on one address of the tunnel subnet,
+//! and one more for each further session it has open at the same time, as
+//! identities may be shared across devices. Leases outlive the connection
+//! that created them, so a reconnecting client gets its previous address
+//! back; an address only returns to the pool once its lease has had no
+//! connected session for the configured idle period.
+//! Leases are optionally persisted to a JSON file so they survive restarts.
+
+use crate::config::IpamSettings;
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Lease {
+    client_id: String,
+    /// Unix time the lease was last taken or released by a session.
+    last_seen: u64,
+    /// Sessions currently using the lease; not persisted.
+    #[serde(skip)]
+    active: u32,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LeaseFile {
+    leases: BTreeMap<Ipv4Addr, Lease>,
+}
+
+/// Hands out host addresses from the tunnel subnet, skipping the server's own.
+pub struct AddressPool {
+    subnet: Ipv4Net,
+    server: Ipv4Addr,
+    /// Reservations from the settings and the user database, the latter
+    /// taking precedence.
+    reservations: HashMap<String, Ipv4Addr>,
+    configured: HashMap<String, Ipv4Addr>,
+    leases: BTreeMap<Ipv4Addr, Lease>,
+    idle_timeout: Duration,
+    lease_file: Option<PathBuf>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl AddressPool {
+    /// Create the pool, restoring leases from the configured lease file.
+    pub fn new(subnet: Ipv4Net, server: Ipv4Addr, settings: &IpamSettings) -> Self {
+        let configured: HashMap<String, Ipv4Addr> = settings
+            .reservations
+            .iter()
+            .map(|(id, addr)| (id.clone(), *addr))
+            .collect();
+        let mut pool = Self {
+            subnet,
+            server,
+            reservations: configured.clone(),
+            configured,
+            leases: BTreeMap::new(),
+            idle_timeout: Duration::from_secs(settings.idle_timeout_secs),
+            lease_file: settings.lease_file.clone(),
+        };
+        pool.load();
+        pool
+    }
+
+    fn load(&mut self) {
+        let Some(path) = &self.lease_file else {
+            return;
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("failed to read lease file {}: {e}", path.display());
+                return;
+            }
+        };
+        match serde_json::from_str::<LeaseFile>(&text) {
+            Ok(file) => {
+                // Drop leases that no longer fit the configured subnet
+                self.leases = file
+                    .leases
+                    .into_iter()
+                    .filter(|(addr, _)| self.is_client_addr(*addr))
+                    .collect();
+                info!(
+                    "restored {} leases from {}",
+                    self.leases.len(),
+                    path.display()
+                );
+            }
+            Err(e) => warn!("ignoring corrupt lease file {}: {e}", path.display()),
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.lease_file else {
+            return;
+        };
+        let file = LeaseFile {
+            leases: self.leases.clone(),
+        };
+        let json = serde_json::to_vec_pretty(&file).expect("lease file serializes");
+        // Write to a temporary file and rename so a crash never leaves a torn file
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&tmp, json).and_then(|()| std::fs::rename(&tmp, path)) {
+            warn!("failed to persist leases to {}: {e}", path.display());
+        }
+    }
+
+    /// Replace the static addresses of the user database. Addresses outside
+    /// the client range, or reserved in the settings for another client,
+    /// are ignored.
+    pub fn set_user_reservations(&mut self, users: HashMap<String, Ipv4Addr>) {
+        let mut reservations = self.configured.clone();
+        for (user, addr) in users {
+            if !self.is_client_addr(addr) {
+                warn!(
+                    "ignoring address {addr} of {user}: not a client address in {}",
+                    self.subnet
+                );
+                continue;
+            }
+            if let Some((other, _)) = self
+                .configured
+                .iter()
+                .find(|(id, a)| **a == addr && **id != user)
+            {
+                warn!("ignoring address {addr} of {user}: reserved for {other}");
+                continue;
+            }
+            reservations.insert(user, addr);
+        }
+        self.reservations = reservations;
+    }
+
+    fn is_client_addr(&self, addr: Ipv4Addr) -> bool {
+        self.subnet.contains(&addr)
+            && addr != self.server
+            && addr != self.subnet.network()
+            && addr != self.subnet.broadcast()
+    }
+
+    fn is_idle(&self, lease: &Lease, now: u64) -> bool {
+        lease.active == 0 && now.saturating_sub(lease.last_seen) >= self.idle_timeout.as_secs()
+    }
+
+    /// Lease an address to `client_id`, preferring its reservation and then
+    /// its previous lease. An address is never shared by two connected
+    /// sessions: further sessions of a client get further addresses. Returns
+    /// `None` if the pool is exhausted or the client's reserved address is
+    /// held by another client's connected session.
+    pub fn allocate(&mut self, client_id: &str) -> Option<Ipv4Net> {
+        let now = now();
+        let reserved = self
+            .reservations
+            .get(client_id)
+            .map(|addr| (*addr, self.leases.get(addr).cloned()));
+        let addr = match reserved {
+            Some((_, Some(lease))) if lease.client_id != client_id && lease.active > 0 => {
+                return None
+            }
+            Some((addr, lease)) if lease.as_ref().is_none_or(|lease| lease.active == 0) => {
+                // A client newly given a reservation drops the dynamic lease
+                // it would have had instead; leases of its other devices stay
+                if lease.is_none_or(|lease| lease.client_id != client_id) {
+                    if let Some(replaced) = self.idle_lease_of(client_id) {
+                        self.leases.remove(&replaced);
+                    }
+                }
+                addr
+            }
+            // No reservation, or the reserved address is in use by another
+            // session of the same client
+            _ => match self.idle_lease_of(client_id) {
+                Some(addr) => addr,
+                None => self.free_addr(now)?,
+            },
+        };
+        self.lease(addr, client_id, now)
+    }
+
+    /// Lease `addr` to `client_id`, as decided outside the pool. Returns
+    /// `None` if it is not a client address, is reserved for another client
+    /// or is held by a connected session.
+    pub fn allocate_addr(&mut self, client_id: &str, addr: Ipv4Addr) -> Option<Ipv4Net> {
+        if !self.is_client_addr(addr) {
+            return None;
+        }
+        if self
+            .reservations
+            .iter()
+            .any(|(id, a)| *a == addr && id != client_id)
+        {
+            return None;
+        }
+        if self.leases.get(&addr).is_some_and(|lease| lease.active > 0) {
+            return None;
+        }
+        self.lease(addr, client_id, now())
+    }
+
+    fn lease(&mut self, addr: Ipv4Addr, client_id: &str, now: u64) -> Option<Ipv4Net> {
+        let lease = self.leases.entry(addr).or_insert_with(|| Lease {
+            client_id: client_id.to_string(),
+            last_seen: now,
+            active: 0,
+        });
+        if lease.client_id != client_id {
+            info!(
+                "reassigning {addr} from idle client {} to {client_id}",
+                lease.client_id
+            );
+            lease.client_id = client_id.to_string();
+        }
+        lease.active += 1;
+        lease.last_seen = now;
+        self.save();
+        Ipv4Net::new(addr, self.subnet.prefix_len()).ok()
+    }
+
+    /// A lease of `client_id` that no connected session holds.
+    fn idle_lease_of(&self, client_id: &str) -> Option<Ipv4Addr> {
+        self.leases
+            .iter()
+            .find(|(_, l)| l.client_id == client_id && l.active == 0)
+            .map(|(a, _)| *a)
+    }
+
+    /// Lowest address that is neither reserved nor leased, falling back to
+    /// the longest-idle expired lease.
+    fn free_addr(&self, now: u64) -> Option<Ipv4Addr> {
+        let reserved: Vec<Ipv4Addr> = self.reservations.values().copied().collect();
+        let unused = self
+            .subnet
+            .hosts()
+            .filter(|a| *a != self.server && !reserved.contains(a))
+            .find(|a| !self.leases.contains_key(a));
+        unused.or_else(|| {
+            self.leases
+                .iter()
+                .filter(|(a, l)| !reserved.contains(a) && self.is_idle(l, now))
+                .min_by_key(|(_, l)| l.last_seen)
+                .map(|(a, _)| *a)
+        })
+    }
+
+    /// Mark one session holding `addr` as gone. The lease itself is kept
+    /// until it has been idle for the configured timeout.
+    pub fn release(&mut self, addr: Ipv4Addr) {
+        if let Some(lease) = self.leases.get_mut(&addr) {
+            lease.active = lease.active.saturating_sub(1);
+            lease.last_seen = now();
+            self.save();
+        }
+    }
+
+    /// Drop the lease on `addr` right away, for clients without a stable
+    /// identity that could ever reclaim it.
+    pub fn forget(&mut self, addr: Ipv4Addr) {
+        if self.leases.remove(&addr).is_some() {
+            self.save();
+        }
+    }
+
+    /// Forget leases that have been idle longer than the idle timeout.
+    pub fn reclaim_idle(&mut self) {
+        let now = now();
+        let before = self.leases.len();
+        let idle: Vec<Ipv4Addr> = self
+            .leases
+            .iter()
+            .filter(|(_, l)| self.is_idle(l, now))
+            .map(|(a, _)| *a)
+            .collect();
+        for addr in idle {
+            self.leases.remove(&addr);
+        }
+        let reclaimed = before - self.leases.len();
+        if reclaimed > 0 {
+            info!("reclaimed {reclaimed} idle leases");
+            self.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 123, 0, 1);
+
+    fn pool(settings: &IpamSettings) -> AddressPool {
+        AddressPool::new("10.123.0.0/24".parse().unwrap(), SERVER, settings)
+    }
+
+    fn host(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 123, 0, last)
+    }
+
+    fn allocate(pool: &mut AddressPool, client_id: &str) -> Option<Ipv4Addr> {
+        pool.allocate(client_id).map(|net| net.addr())
+    }
+
+    #[test]
+    fn reconnecting_client_gets_its_address_back() {
+        let mut pool = pool(&IpamSettings::default());
+        assert_eq!(
+            pool.allocate("alice"),
+            Some("10.123.0.2/24".parse().unwrap())
+        );
+        pool.release(host(2));
+        assert_eq!(allocate(&mut pool, "bob"), Some(host(3)));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+    }
+
+    #[test]
+    fn concurrent_sessions_keep_separate_addresses() {
+        let mut pool = pool(&IpamSettings::default());
+        assert_eq!(allocate(&mut pool, "shared"), Some(host(2)));
+        assert_eq!(allocate(&mut pool, "shared"), Some(host(3)));
+        pool.release(host(2));
+        pool.release(host(3));
+        assert_eq!(allocate(&mut pool, "shared"), Some(host(2)));
+        assert_eq!(allocate(&mut pool, "shared"), Some(host(3)));
+        assert_eq!(allocate(&mut pool, "other"), Some(host(4)));
+    }
+
+    #[test]
+    fn reservation_wins_over_dynamic_lease() {
+        let mut pool = pool(&IpamSettings::default());
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(3)));
+        pool.release(host(2));
+        pool.release(host(3));
+        pool.set_user_reservations(HashMap::from([("alice".into(), host(10))]));
+
+        // The reservation replaces one dynamic lease; the other device
+        // keeps its address
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(10)));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(3)));
+        assert_eq!(allocate(&mut pool, "bob"), Some(host(2)));
+        pool.release(host(10));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(10)));
+
+        // Nobody else is handed the reserved address
+        for _ in 0..5 {
+            assert_ne!(allocate(&mut pool, "carol"), Some(host(10)));
+        }
+    }
+
+    #[test]
+    fn reserved_address_is_taken_from_idle_clients_only() {
+        let mut pool = pool(&IpamSettings::default());
+        assert_eq!(
+            pool.allocate_addr("carol", host(10)).unwrap().addr(),
+            host(10)
+        );
+        pool.set_user_reservations(HashMap::from([("alice".into(), host(10))]));
+        assert_eq!(allocate(&mut pool, "alice"), None);
+        pool.release(host(10));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(10)));
+    }
+
+    #[test]
+    fn settings_reservations_win_over_user_database() {
+        let settings = IpamSettings {
+            reservations: BTreeMap::from([("gw".to_string(), host(20))]),
+            ..IpamSettings::default()
+        };
+        let mut pool = pool(&settings);
+        pool.set_user_reservations(HashMap::from([
+            ("alice".into(), host(20)),
+            ("bob".into(), SERVER),
+            ("carol".into(), Ipv4Addr::new(192, 168, 0, 1)),
+        ]));
+        assert_eq!(allocate(&mut pool, "gw"), Some(host(20)));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+        assert_eq!(allocate(&mut pool, "bob"), Some(host(3)));
+        assert_eq!(allocate(&mut pool, "carol"), Some(host(4)));
+    }
+
+    #[test]
+    fn allocate_addr_refuses_taken_addresses() {
+        let settings = IpamSettings {
+            reservations: BTreeMap::from([("gw".to_string(), host(20))]),
+            ..IpamSettings::default()
+        };
+        let mut pool = pool(&settings);
+        assert!(pool.allocate_addr("alice", SERVER).is_none());
+        assert!(pool.allocate_addr("alice", host(0)).is_none());
+        assert!(pool.allocate_addr("alice", host(255)).is_none());
+        assert!(pool
+            .allocate_addr("alice", Ipv4Addr::new(10, 9, 0, 5))
+            .is_none());
+        assert!(pool.allocate_addr("alice", host(20)).is_none());
+        assert!(pool.allocate_addr("gw", host(20)).is_some());
+        assert!(pool.allocate_addr("alice", host(30)).is_some());
+        assert!(pool.allocate_addr("alice", host(30)).is_none());
+    }
+
+    #[test]
+    fn idle_leases_are_reclaimed() {
+        let mut pool = pool(&IpamSettings {
+            idle_timeout_secs: 0,
+            ..IpamSettings::default()
+        });
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+        assert_eq!(allocate(&mut pool, "bob"), Some(host(3)));
+        pool.release(host(2));
+        pool.reclaim_idle();
+        // Connected sessions keep their leases whatever the timeout
+        assert_eq!(pool.leases.keys().copied().collect::<Vec<_>>(), [host(3)]);
+        assert_eq!(allocate(&mut pool, "carol"), Some(host(2)));
+    }
+
+    #[test]
+    fn leases_are_kept_for_the_idle_period() {
+        let mut pool = pool(&IpamSettings {
+            idle_timeout_secs: 3600,
+            ..IpamSettings::default()
+        });
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+        pool.release(host(2));
+        pool.reclaim_idle();
+        assert_eq!(allocate(&mut pool, "bob"), Some(host(3)));
+        assert_eq!(allocate(&mut pool, "alice"), Some(host(2)));
+    }
+
+    #[test]
+    fn exhausted_pool_falls_back_to_expired_leases() {
+        let subnet = "10.123.0.0/29".parse().unwrap();
+        let mut pool = AddressPool::new(
+            subnet,
+            SERVER,
+            &IpamSettings {
+                idle_timeout_secs: 0,
+                ..IpamSettings::default()
+            },
+        );
+        for (i, client) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            assert_eq!(allocate(&mut pool, client), Some(host(2 + i as u8)));
+        }
+        assert_eq!(allocate(&mut pool, "f"), None);
+        pool.release(host(4));
+        assert_eq!(allocate(&mut pool, "f"), Some(host(4)));
+    }
+
+    #[test]
+    fn leases_survive_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("xeonvpn-ipam-test-{}.json", std::process::id()));
+        let settings = IpamSettings {
+            lease_file: Some(path.clone()),
+            ..IpamSettings::default()
+        };
+        let mut before = pool(&settings);
+        assert_eq!(allocate(&mut before, "alice"), Some(host(2)));
+        assert_eq!(allocate(&mut before, "bob"), Some(host(3)));
+        before.release(host(2));
+        drop(before);
+
+        let mut after = pool(&settings);
+        // Sessions do not survive a restart, only their leases
+        assert!(after.leases.values().all(|lease| lease.active == 0));
+        assert_eq!(allocate(&mut after, "carol"), Some(host(4)));
+        assert_eq!(allocate(&mut after, "bob"), Some(host(3)));
+        assert_eq!(allocate(&mut after, "alice"), Some(host(2)));
+
+        // Leases outside a changed subnet are dropped
+        let moved = AddressPool::new("10.124.0.0/24".parse().unwrap(), SERVER, &settings);
+        assert!(moved.leases.is_empty());
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(pool(&settings).leases.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,56 @@
-use quinn::{Endpoint, ServerConfig};
-use rcgen::generate_simple_self_signed;
-use std::{net::SocketAddr, sync::Arc};
-use tracing::{error, info};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+pub mod acl;
+pub mod auth;
+#[cfg(unix)]
+pub mod authz;
+pub mod close;
+pub mod config;
+pub mod endpoint;
+pub mod handshake;
+pub mod ipam;
+pub mod pki;
+pub mod pmtu;
+pub mod session;
+pub mod tls;
+pub mod token;
+pub mod transport;
+pub mod tunnel;
+pub mod users;
+
+use acl::Acl;
+#[cfg(target_os = "linux")]
+use authz::{Authorizer, AuthzRequest, IdentitySource};
+use close::{close_connection, connection_closed, error_code, frame_closed, write_closed};
+use config::{TlsSettings, TunServerSettings};
+use handshake::{accept_handshake, serve_control, ControlStream};
+use ipam::AddressPool;
+use ipnet::IpNet;
+use pmtu::watch_path_mtu;
+use quinn::{ServerConfig, TransportConfig};
+use rustls::server::ClientCertVerifier;
+use session::{Dispatch, SessionTable};
+#[cfg(target_os = "linux")]
+use session::{SharedLimiter, UplinkPolicy, UplinkWriter};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tls::{ClientAuth, ServerIdentity};
+use token::TokenKeySet;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, warn};
+use transport::TransportSettings;
+use tunnel::{DataPlane, DatagramRecvError, StreamBatcher};
+use users::UserStore;
+use xeonvpn_core::batch;
+use xeonvpn_core::buffer::{PacketBuf, PacketPool};
+use xeonvpn_core::close::{CloseCode, SessionClose};
+use xeonvpn_core::compress::{self, Decompressor};
+use xeonvpn_core::control::{Capabilities, ControlMessage, SessionParams};
+use xeonvpn_core::frame::{Frame, FrameRead, FrameWrite, ProtocolError, PROTOCOL_VERSION};
+use xeonvpn_core::ratelimit::RateLimiter;
+use xeonvpn_net::packet::{self, IcmpError};
+#[cfg(target_os = "linux")]
+use xeonvpn_net::{vnet::VNET_HDR_LEN, FlowWriter, TunOptions, TunReader};
 
 async fn handle_doh_query(
     query: &str,
@@ -25,147 +72,979 @@ async fn handle_doh_query(
     Ok(out.into_bytes())
 }
 
-/// Linux-only QUIC server with basic TUN framing handler.
+/// How long a shutting down server waits for clients to acknowledge that
+/// their sessions are closed.
+#[cfg(target_os = "linux")]
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Delays between retries of a failing TUN read.
+#[cfg(target_os = "linux")]
+const TUN_RETRY_MIN: std::time::Duration = std::time::Duration::from_millis(10);
 #[cfg(target_os = "linux")]
-pub async fn serve_quic_tun(addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let server_config = build_server_config()?;
+const TUN_RETRY_MAX: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Linux-only QUIC server with basic TUN framing handler. Runs until
+/// `shutdown` completes, then closes every session with
+/// [`CloseCode::ShuttingDown`].
+#[cfg(target_os = "linux")]
+pub async fn serve_quic_tun(
+    addr: &str,
+    settings: TunServerSettings,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let identity = ServerIdentity::from_settings(&settings.tls)?;
+    let client_auth = ClientAuth::from_settings(&settings.auth)?;
+    let transport = Arc::new(settings.transport.transport_config()?);
+    let verifier = client_auth
+        .as_ref()
+        .map(|auth| auth.verifier())
+        .transpose()?;
+    let server_config = build_server_config(&identity, verifier, &transport)?;
     let addr: SocketAddr = addr.parse()?;
-    let endpoint = Endpoint::server(server_config, addr)?;
+    let endpoint_config = settings.transport.endpoint_config()?;
+    let endpoint = endpoint::server_endpoint(endpoint_config, server_config, addr)?;
     let local = endpoint.local_addr()?;
     info!("QUIC server listening on {local}");
 
-    // Open TUN once and share across tasks. Server IP: 10.123.0.1/24
-    let dev = Arc::new(Mutex::new(
-        xeonvpn_net::open_tun_named("xeonvpnS0", std::net::Ipv4Addr::new(10, 123, 0, 1))
-            .await?,
-    ));
+    // Pick up CRL and deny-list changes without a restart; new handshakes
+    // use the rebuilt TLS config, and live sessions are checked against it
+    if let Some(auth) = client_auth.clone() {
+        info!("client certificates required");
+        let endpoint = endpoint.clone();
+        let every = std::time::Duration::from_secs(settings.auth.revocation_reload_secs.max(1));
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            loop {
+                tick.tick().await;
+                if !auth.changed() {
+                    continue;
+                }
+                let rebuilt = auth
+                    .reload()
+                    .and_then(|()| auth.verifier())
+                    .and_then(|verifier| {
+                        let config =
+                            build_server_config(&identity, Some(verifier.clone()), &transport)?;
+                        Ok((config, verifier))
+                    });
+                match rebuilt {
+                    Ok((config, verifier)) => {
+                        endpoint.set_server_config(Some(config));
+                        info!("reloaded client certificate revocation data");
+                        auth.disconnect_revoked(verifier.as_ref());
+                    }
+                    Err(e) => error!("failed to reload revocation data, keeping previous: {e}"),
+                }
+            }
+        });
+    }
 
-    while let Some(connecting) = endpoint.accept().await {
+    // Open every TUN queue once; each queue's reader belongs to one worker
+    // and sessions write through all of them. The server takes the first
+    // host of the subnet
+    let server_addr = settings.server_addr();
+    let options = TunOptions {
+        queues: settings.tun_queues(),
+        offload: settings.tun_offload,
+    };
+    let queues =
+        xeonvpn_net::open_tun_with(&settings.tun_name, server_addr, settings.mtu, &options).await?;
+    let (tun_readers, writers): (Vec<_>, Vec<_>) = queues.into_iter().map(|q| q.split()).unzip();
+    let dev = FlowWriter::new(writers);
+    let pool = Arc::new(std::sync::Mutex::new(AddressPool::new(
+        settings.subnet,
+        server_addr.addr(),
+        &settings.ipam,
+    )));
+    let reclaim_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            tick.tick().await;
+            reclaim_pool.lock().unwrap().reclaim_idle();
+        }
+    });
+    let psk = settings.auth.load_psk()?.map(Arc::<[u8]>::from);
+    let tokens = TokenKeySet::from_settings(&settings.auth, settings.region.as_deref())?;
+    if let Some(tokens) = &tokens {
+        let mut ids: Vec<_> = tokens.key_ids().collect();
+        ids.sort_unstable();
+        info!("accepting tokens signed with key(s) {}", ids.join(", "));
+    }
+    let tokens = tokens.map(Arc::new);
+    let acl = Acl::from_settings(&settings.acl)?.map(Arc::new);
+    let users = match &settings.auth.users_file {
+        Some(path) => Some(Arc::new(UserStore::open(path)?)),
+        None => None,
+    };
+    // Pick up user database changes without a restart, and close the
+    // sessions of users that were disabled, removed or have expired
+    if let Some(users) = users.clone() {
+        pool.lock()
+            .unwrap()
+            .set_user_reservations(users.reservations());
+        let pool = pool.clone();
+        let every = std::time::Duration::from_secs(settings.auth.users_reload_secs.max(1));
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            loop {
+                tick.tick().await;
+                if users.changed() {
+                    match users.reload() {
+                        Ok(()) => pool
+                            .lock()
+                            .unwrap()
+                            .set_user_reservations(users.reservations()),
+                        Err(e) => error!("failed to reload users, keeping previous: {e}"),
+                    }
+                }
+                users.disconnect_refused();
+            }
+        });
+    }
+    let authorizer = settings.auth.authorizer.as_ref().map(|settings| {
+        let authorizer = Authorizer::new(settings);
+        info!("authorizing sessions with {}", authorizer.describe());
+        Arc::new(authorizer)
+    });
+    // Users without a key of their own can only be named by a certificate
+    // or token
+    if let Some(users) = &users {
+        if tokens.is_none() && client_auth.is_none() {
+            for name in users.without_psk() {
+                warn!("user {name} has no psk and no certificate or token can name it; it cannot connect");
+            }
+        }
+    }
+    if psk.is_none()
+        && tokens.is_none()
+        && users.is_none()
+        && client_auth.is_none()
+        && authorizer.is_none()
+    {
+        warn!("no client authentication configured; any client can open a session");
+    }
+    let settings = Arc::new(settings);
+    let mut next_session_id = 1u64;
+
+    // One worker per TUN queue, dispatching by inner destination address.
+    // The kernel hashes flows to queues, so a flow is always handled by the
+    // same worker and stays in order; the runtime spreads workers over cores
+    let sessions = SessionTable::default();
+    let read_len = if settings.tun_offload {
+        VNET_HDR_LEN + usize::from(u16::MAX)
+    } else {
+        packet_buf_len(settings.mtu)
+    };
+    info!("{} TUN packet worker(s)", tun_readers.len());
+    for reader in tun_readers {
+        tokio::spawn(tun_dispatcher(
+            reader,
+            dev.clone(),
+            sessions.clone(),
+            IpAddr::V4(server_addr.addr()),
+            read_len,
+        ));
+    }
+
+    tokio::pin!(shutdown);
+    loop {
+        let connecting = tokio::select! {
+            connecting = endpoint.accept() => match connecting {
+                Some(connecting) => connecting,
+                None => break,
+            },
+            () = &mut shutdown => {
+                let close = SessionClose::new(CloseCode::ShuttingDown, "server shutting down");
+                info!("closing all sessions: {close}");
+                endpoint.close(error_code(close.code), close.reason.as_bytes());
+                let _ = tokio::time::timeout(SHUTDOWN_GRACE, endpoint.wait_idle()).await;
+                break;
+            }
+        };
         let dev = dev.clone();
+        let pool = pool.clone();
+        let settings = settings.clone();
+        let sessions = sessions.clone();
+        let psk = psk.clone();
+        let tokens = tokens.clone();
+        let users = users.clone();
+        let authorizer = authorizer.clone();
+        let acl = acl.clone();
+        let client_auth = client_auth.clone();
+        let session_id = next_session_id;
+        next_session_id += 1;
         tokio::spawn(async move {
             match connecting.await {
                 Ok(connection) => {
-                    let remote = connection.remote_address();
-                    info!("new connection: {remote}");
-                    loop {
-                        match connection.accept_bi().await {
-                            Ok((mut send, mut recv)) => {
-                                // Split into two parallel tasks per stream: uplink and downlink
-                                let dev_u = dev.clone();
-                                let uplink = tokio::spawn(async move {
-                                    loop {
-                                        // Read header from QUIC
-                                        let mut hdr = [0u8; 8];
-                                        if let Err(e) = recv.read_exact(&mut hdr).await {
-                                            error!("recv header error: {e}");
-                                            break;
-                                        }
-                                        if &hdr[0..4] != b"TUN " {
-                                            error!("bad magic on TUN stream");
-                                            break;
-                                        }
-                                        let len = u32::from_be_bytes([
-                                            hdr[4], hdr[5], hdr[6], hdr[7],
-                                        ]) as usize;
-                                        let mut payload = vec![0u8; len.min(65535)];
-                                        if let Err(e) = recv.read_exact(&mut payload).await {
-                                            error!("recv payload error: {e}");
-                                            break;
-                                        }
+                    let session = Session {
+                        id: session_id,
+                        dev,
+                        settings,
+                        pool,
+                        sessions,
+                        psk,
+                        tokens,
+                        users,
+                        authorizer,
+                        acl,
+                        client_auth,
+                    };
+                    handle_tun_connection(connection, session).await
+                }
+                Err(e) => error!("handshake failed: {e}"),
+            }
+        });
+    }
 
-                                        // Write payload into server TUN (uplink inject)
-                                        let mut guard = dev_u.lock().await;
-                                        if let Err(e) = guard.write_all(&payload).await {
-                                            error!("tun write error: {e}");
-                                            break;
-                                        }
-                                    }
-                                });
-
-                                let dev_d = dev.clone();
-                                let downlink = tokio::spawn(async move {
-                                    loop {
-                                        // Read packet from TUN and forward to client
-                                        let mut buf = vec![0u8; 2000];
-                                        let n = {
-                                            let mut guard = dev_d.lock().await;
-                                            match guard.read(&mut buf).await {
-                                                Ok(n) => n,
-                                                Err(e) => {
-                                                    error!("tun read error: {e}");
-                                                    0
-                                                }
-                                            }
-                                        };
-                                        if n == 0 {
-                                            continue;
-                                        }
-                                        buf.truncate(n);
+    Ok(())
+}
 
-                                        let mut out = Vec::with_capacity(8 + buf.len());
-                                        out.extend_from_slice(b"TUN ");
-                                        out.extend_from_slice(&(buf.len() as u32).to_be_bytes());
-                                        out.extend_from_slice(&buf);
+/// Payload room of the pooled packet buffers for a tunnel `mtu`. Larger
+/// frames still work, they just bypass the pool.
+#[cfg(target_os = "linux")]
+fn packet_buf_len(mtu: u16) -> usize {
+    usize::from(mtu).max(2000)
+}
 
-                                        if let Err(e) = send.write_all(&out).await {
-                                            error!("send error: {e}");
-                                            break;
-                                        }
-                                    }
-                                });
+/// Read every packet from one server TUN queue and hand it to the session
+/// that owns its destination. Packets for unknown destinations are dropped and
+/// answered with an ICMP unreachable.
+#[cfg(target_os = "linux")]
+async fn tun_dispatcher(
+    mut reader: TunReader,
+    dev: FlowWriter,
+    sessions: SessionTable,
+    server_ip: IpAddr,
+    read_len: usize,
+) {
+    let mut buffers = PacketPool::new(read_len);
+    let mut backoff = std::time::Duration::ZERO;
+    loop {
+        let mut buf = buffers.take();
+        let n = match reader.recv(buf.space_mut()).await {
+            Ok(n) => {
+                backoff = std::time::Duration::ZERO;
+                n
+            }
+            Err(e) if xeonvpn_net::is_fatal_read_error(&e) => {
+                error!("tun read error, stopping TUN worker: {e}");
+                return;
+            }
+            Err(e) => {
+                // Retry ever more slowly while the error lasts, which also
+                // bounds how often it is logged
+                backoff = (backoff * 2).clamp(TUN_RETRY_MIN, TUN_RETRY_MAX);
+                error!(
+                    "tun read error, retrying in {} ms: {e}",
+                    backoff.as_millis()
+                );
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+        if n == 0 {
+            continue;
+        }
+        buf.truncate(n);
+        match sessions.dispatch(buf, reader.offload()) {
+            Dispatch::Delivered => {}
+            Dispatch::Congested => debug!("downlink queue full, dropping packet"),
+            Dispatch::Limited => debug!("session over its rate limit, dropping packet"),
+            Dispatch::Invalid => debug!("dropping unparseable packet from TUN"),
+            Dispatch::NoRoute(mut pkt) => {
+                if reader.offload() {
+                    pkt.advance(VNET_HDR_LEN);
+                }
+                let dst = packet::dst_addr(&pkt);
+                debug!("no session for {dst:?}, dropping packet");
+                if let Some(icmp) = packet::icmp_error(&pkt, server_ip, IcmpError::HostUnreachable)
+                {
+                    if let Err(e) = dev.send(&icmp).await {
+                        error!("tun write error: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
 
-                                let _ = tokio::join!(uplink, downlink);
-                            }
+/// Server-wide state a connection handler needs.
+#[cfg(target_os = "linux")]
+struct Session {
+    id: u64,
+    dev: FlowWriter,
+    settings: Arc<TunServerSettings>,
+    pool: Arc<std::sync::Mutex<AddressPool>>,
+    sessions: SessionTable,
+    /// Pre-shared key clients must prove knowledge of, if configured.
+    psk: Option<Arc<[u8]>>,
+    /// Keys clients' tokens may be signed with, if configured.
+    tokens: Option<Arc<TokenKeySet>>,
+    /// User database every session needs an entry in, if configured.
+    users: Option<Arc<UserStore>>,
+    /// External service every session must be allowed by, if configured.
+    authorizer: Option<Arc<Authorizer>>,
+    /// Uplink access control policies, if configured.
+    acl: Option<Arc<Acl>>,
+    /// Client-certificate authentication, if configured.
+    client_auth: Option<Arc<ClientAuth>>,
+}
+
+/// Run the session handshake for one connection, then forward packets
+/// between its data stream and the server TUN until either side goes away.
+#[cfg(target_os = "linux")]
+async fn handle_tun_connection(connection: quinn::Connection, session: Session) {
+    let Session {
+        id: session_id,
+        dev,
+        settings,
+        pool,
+        sessions,
+        psk,
+        tokens,
+        users,
+        authorizer,
+        acl,
+        client_auth,
+    } = session;
+    let remote = connection.remote_address();
+    info!("new connection: {remote}");
+
+    let (hello, mut ctrl) = match accept_handshake(&connection).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("session handshake with {remote} failed: {e}");
+            close_connection(&connection, &e.session_close());
+            return;
+        }
+    };
+    if hello.version != PROTOCOL_VERSION {
+        let reason = format!("unsupported protocol version {}", hello.version);
+        let close = SessionClose::new(CloseCode::VersionMismatch, reason);
+        reject_session(&connection, &mut ctrl, close).await;
+        return;
+    }
+    // A user's own pre-shared key takes the place of the server-wide one,
+    // and proving it is what ties the session to that user
+    let user_psk = match (&users, &hello.client_id) {
+        (Some(users), Some(id)) => users.get(id).and_then(|user| user.psk.clone()),
+        _ => None,
+    };
+    let token = match auth::authenticate(
+        &connection,
+        user_psk.as_deref().or(psk.as_deref()),
+        tokens.as_deref(),
+        hello.credentials.as_ref(),
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("session {session_id}: authentication of {remote} failed: {e}");
+            close_connection(
+                &connection,
+                &SessionClose::new(CloseCode::AuthFailed, e.to_string()),
+            );
+            return;
+        }
+    };
+    if let Some(token) = &token {
+        info!(
+            "session {session_id}: token for {} signed with key {}, expires at {}, bandwidth class {}",
+            token.user,
+            token.key_id,
+            token.expires,
+            token.bandwidth_class.as_deref().unwrap_or("none")
+        );
+    }
+
+    // A certificate identity, then a token's user, take precedence over the
+    // self-declared client id. Clients without a stable identity only keep
+    // their address while connected.
+    let user = match &client_auth {
+        Some(auth) => match auth.identify(&connection) {
+            Ok(identity) => {
+                info!(
+                    "session {session_id}: certificate {} ({})",
+                    identity.subject, identity.fingerprint
+                );
+                Some(identity.name)
+            }
+            Err(e) => {
+                warn!("session {session_id}: certificate of {remote} rejected: {e}");
+                let close = SessionClose::new(CloseCode::AuthFailed, "authentication failed");
+                close_connection(&connection, &close);
+                return;
+            }
+        },
+        None => None,
+    };
+    // Authentication succeeded with the user's key unless a token was used
+    let psk_proven = user_psk.is_some() && token.is_none();
+    let (token_user, token_class) = match token {
+        Some(token) => (Some(token.user), token.bandwidth_class),
+        None => (None, None),
+    };
+    let source = if user.is_some() {
+        IdentitySource::Certificate
+    } else if token_user.is_some() {
+        IdentitySource::Token
+    } else if hello.client_id.is_some() {
+        IdentitySource::ClientId
+    } else {
+        IdentitySource::Anonymous
+    };
+    let user = user.or(token_user);
+    let stable_id = user.is_some() || hello.client_id.is_some();
+    // A certificate or token names a proven user; a client id only counts
+    // once the user's own key was proven for it
+    let verified = user.is_some() || psk_proven;
+    let client_id = user
+        .or_else(|| hello.client_id.clone())
+        .unwrap_or_else(|| format!("anonymous@{remote}"));
+    let account = match &users {
+        Some(users) => match users.admit(&client_id, verified) {
+            Ok(account) => Some(account),
+            Err(e) => {
+                warn!("session {session_id}: {client_id} at {remote} refused: {e}");
+                close_connection(&connection, &e.into());
+                return;
+            }
+        },
+        None => None,
+    };
+    let bandwidth_class = account
+        .as_ref()
+        .and_then(|account| account.bandwidth_class.clone())
+        .or(token_class);
+    let decision = match &authorizer {
+        Some(authorizer) => {
+            let request = AuthzRequest {
+                session_id,
+                identity: client_id.clone(),
+                identity_source: source,
+                client_id: hello.client_id.clone(),
+                remote,
+                region: settings.region.clone(),
+                bandwidth_class: bandwidth_class.clone(),
+                capabilities: hello.capabilities.clone(),
+                compression: hello.compression.clone(),
+            };
+            match authorizer.authorize(&request).await {
+                Ok(decision) if decision.allow => Some(decision),
+                Ok(decision) => {
+                    let reason = decision.reason.unwrap_or_else(|| "not authorized".into());
+                    warn!("session {session_id}: {client_id} at {remote} denied: {reason}");
+                    let close = SessionClose::new(CloseCode::AuthFailed, reason);
+                    close_connection(&connection, &close);
+                    return;
+                }
+                Err(e) => {
+                    error!("session {session_id}: authorizing {client_id} failed: {e}");
+                    let close =
+                        SessionClose::new(CloseCode::Unavailable, "authorization unavailable");
+                    close_connection(&connection, &close);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    // The authorizer's routes, then the user's, replace the server's and
+    // bound what the session may reach
+    let session_routes = decision
+        .as_ref()
+        .and_then(|decision| decision.routes.clone())
+        .or_else(|| {
+            account
+                .as_ref()
+                .map(|account| account.routes.clone())
+                .filter(|routes| !routes.is_empty())
+        });
+    let rate_limit = decision
+        .as_ref()
+        .and_then(|decision| decision.rate_limit_kbps);
+    let allocated = match decision.as_ref().and_then(|decision| decision.address) {
+        Some(addr) => pool.lock().unwrap().allocate_addr(&client_id, addr),
+        None => pool.lock().unwrap().allocate(&client_id),
+    };
+    let Some(client_addr) = allocated else {
+        let close = SessionClose::new(CloseCode::Unavailable, "no tunnel address available");
+        reject_session(&connection, &mut ctrl, close).await;
+        return;
+    };
+
+    let plane = DataPlane::new(connection.clone());
+    let server_caps = Capabilities {
+        datagrams: plane.datagrams_available(),
+        offload: dev.offload(),
+        batching: true,
+        fragments: true,
+        probes: true,
+    };
+    let params = SessionParams {
+        session_id,
+        addresses: vec![IpNet::V4(client_addr)],
+        mtu: settings.mtu,
+        dns: settings.dns.clone(),
+        routes: session_routes
+            .clone()
+            .unwrap_or_else(|| settings.routes.clone()),
+        capabilities: hello.capabilities.intersect(&server_caps),
+        compression: compress::negotiate(&hello.compression, &settings.transport.compression),
+    };
+    let welcome = ControlMessage::Welcome(params.clone()).to_frame();
+    if let Err(e) = ctrl.send.write_frame(&welcome).await {
+        error!("session {session_id}: failed to send session parameters: {e}");
+        pool.lock().unwrap().release(client_addr.addr());
+        return;
+    }
+    info!("session {session_id}: {client_id} at {remote} assigned {client_addr}");
+    if let Some(class) = &bandwidth_class {
+        info!("session {session_id}: bandwidth class {class}");
+    }
+    if let Some(users) = &users {
+        users.register(&client_id, session_id, connection.clone());
+    }
+    if let Some(auth) = &client_auth {
+        auth.register(session_id, connection.clone());
+    }
+    let limits = rate_limit.map(|kbps| {
+        info!("session {session_id}: rate limit {kbps} kbit/s");
+        let limit =
+            || -> SharedLimiter { Arc::new(std::sync::Mutex::new(RateLimiter::from_kbps(kbps))) };
+        (limit(), limit())
+    });
+    let groups: Vec<String> = account
+        .iter()
+        .flat_map(|account| account.groups.iter())
+        .chain(decision.iter().flat_map(|decision| decision.groups.iter()))
+        .cloned()
+        .collect();
+    let session_acl = acl
+        .and_then(|acl| {
+            let user = verified.then_some(client_id.as_str());
+            acl.session(session_id, user, &groups)
+        })
+        .map(Arc::new);
+    if let Some(acl) = &session_acl {
+        info!("session {session_id}: acl {}", acl.summary());
+    }
+    let allowed = session_routes.map(|mut routes| {
+        routes.push(IpNet::V4(settings.subnet));
+        routes
+    });
+    let dev = UplinkWriter::new(
+        dev,
+        &params.addresses,
+        UplinkPolicy {
+            allowed,
+            acl: session_acl.clone(),
+            limit: limits.as_ref().map(|(up, _)| up.clone()),
+        },
+    );
+
+    if params.capabilities.offload {
+        info!("session {session_id}: offloads enabled");
+        plane.enable_offload();
+    }
+    if let Some(algorithm) = params.compression {
+        let transport = &settings.transport;
+        match plane.enable_compression(
+            algorithm,
+            transport.compression_level,
+            transport.compress_min_size,
+        ) {
+            Ok(()) => info!("session {session_id}: {algorithm} compression enabled"),
+            Err(e) => warn!("session {session_id}: cannot start {algorithm} compression: {e}"),
+        }
+    }
+    let datagrams = params.capabilities.datagrams && plane.enable_datagrams();
+    if datagrams {
+        info!("session {session_id}: datagram data plane enabled");
+        plane.enable_fragments(&settings.transport, params.capabilities.fragments);
+        if plane.fragments_enabled() {
+            info!("session {session_id}: fragmentation enabled");
+        }
+        if params.capabilities.probes {
+            plane.enable_probes();
+        }
+        tokio::spawn(datagram_uplink(plane.clone(), dev.clone()));
+    }
+    let control = tokio::spawn(serve_control(ctrl));
+    let tunnel_ip = IpAddr::V4(client_addr.addr());
+    let downlink_rx = sessions.register(
+        tunnel_ip,
+        session_id,
+        limits.as_ref().map(|(_, down)| down.clone()),
+    );
+    let client_route = IpNet::from(tunnel_ip);
+    plane.set_mtu(settings.mtu);
+    if settings.transport.clamp_mss {
+        plane.enable_mss_clamp();
+    }
+    let follow_mtu = settings.transport.auto_mtu.then(|| {
+        tokio::spawn(follow_route_mtu(
+            watch_path_mtu(plane.clone(), settings.mtu),
+            plane.clone(),
+            settings.tun_name.clone(),
+            client_route,
+            session_id,
+        ))
+    });
+
+    let close = match connection.accept_bi().await {
+        Ok((send, recv)) => {
+            forward_tun_stream(
+                send,
+                recv,
+                plane.clone(),
+                dev,
+                downlink_rx,
+                StreamBatcher::new(&settings.transport, params.capabilities.batching),
+                packet_buf_len(settings.mtu),
+            )
+            .await
+        }
+        Err(e) => {
+            error!("session {session_id}: accept data stream error: {e}");
+            connection_closed(&e)
+        }
+    };
+    // A close from the client or the transport wins over the session's own
+    let close = match connection.close_reason() {
+        Some(e) => connection_closed(&e),
+        None => {
+            close_connection(&connection, &close);
+            close
+        }
+    };
+
+    control.abort();
+    if let Some(algorithm) = params.compression {
+        info!(
+            "session {session_id}: {algorithm} compression: {}",
+            plane.compression_stats()
+        );
+    }
+    if datagrams {
+        info!(
+            "session {session_id}: fragmentation: {}",
+            plane.fragment_stats()
+        );
+    }
+    if let Some(acl) = &session_acl {
+        info!("session {session_id}: acl: {}", acl.stats());
+    }
+    if let Some((up, down)) = &limits {
+        info!(
+            "session {session_id}: rate limit: uplink {}; downlink {}",
+            up.lock().unwrap().stats(),
+            down.lock().unwrap().stats()
+        );
+    }
+    if let Some(follow_mtu) = follow_mtu {
+        follow_mtu.abort();
+        if let Err(e) = xeonvpn_net::remove_route(&settings.tun_name, client_route).await {
+            debug!("session {session_id}: {e}");
+        }
+    }
+    sessions.unregister(tunnel_ip, session_id);
+    if let Some(users) = &users {
+        users.unregister(&client_id, session_id);
+    }
+    if let Some(auth) = &client_auth {
+        auth.unregister(session_id);
+    }
+    if stable_id {
+        pool.lock().unwrap().release(client_addr.addr());
+    } else {
+        pool.lock().unwrap().forget(client_addr.addr());
+    }
+    info!("session {session_id}: {remote} disconnected ({close}), released {client_addr}");
+}
+
+/// Keep the session and the route to a client at the tunnel MTU of its
+/// path, so packets too big for it are refused instead of lost.
+#[cfg(target_os = "linux")]
+async fn follow_route_mtu(
+    mut mtu: watch::Receiver<u16>,
+    plane: DataPlane,
+    dev: String,
+    dest: IpNet,
+    session_id: u64,
+) {
+    loop {
+        let current = *mtu.borrow_and_update();
+        plane.set_mtu(current);
+        match xeonvpn_net::set_route_mtu(&dev, dest, current).await {
+            Ok(()) => info!("session {session_id}: tunnel MTU {current}"),
+            Err(e) => warn!("session {session_id}: cannot set route MTU {current}: {e}"),
+        }
+        if mtu.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Refuse a session on its control stream, then close the connection with
+/// the same code once the client has the rejection.
+#[cfg(target_os = "linux")]
+async fn reject_session(
+    connection: &quinn::Connection,
+    ctrl: &mut ControlStream,
+    close: SessionClose,
+) {
+    info!("rejecting session: {close}");
+    let reject = ControlMessage::Reject {
+        reason: close.reason.clone(),
+        code: close.code.into(),
+    };
+    let _ = ctrl.send.write_frame(&reject.to_frame()).await;
+    let _ = ctrl.send.finish().await;
+    close_connection(connection, &close);
+}
+
+/// Forward packets between one data stream and the server TUN. Downlink
+/// packets arrive on `downlink_rx` from the TUN dispatcher. Returns how the
+/// session should be closed.
+#[cfg(target_os = "linux")]
+async fn forward_tun_stream(
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    plane: DataPlane,
+    dev: UplinkWriter,
+    mut downlink_rx: mpsc::Receiver<PacketBuf>,
+    mut batcher: StreamBatcher,
+    buf_len: usize,
+) -> SessionClose {
+    // Split into two parallel tasks per stream: uplink and downlink.
+    // Frames the uplink needs to answer (pongs, close) go through
+    // the downlink task, which owns the send half.
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Frame>(16);
+    let vnet = dev.tun().offload();
+    let icmp_dev = dev.tun().clone();
+    let uplink_plane = plane.clone();
+    let uplink = tokio::spawn(async move {
+        let mut buffers = PacketPool::new(buf_len);
+        let mut decompressor = Decompressor::new();
+        'frames: loop {
+            let frame = match recv.read_frame_in(&mut buffers).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    info!("client closed TUN stream");
+                    break Some(SessionClose::new(
+                        CloseCode::Normal,
+                        "tunnel stream finished",
+                    ));
+                }
+                Err(ProtocolError::Io(e)) => {
+                    error!("recv frame error: {e}");
+                    break frame_closed(&ProtocolError::Io(e));
+                }
+                Err(e) => {
+                    // The stream is misaligned: stop the client sending on
+                    // it and tell it why
+                    error!("protocol error on TUN stream: {e}");
+                    let close = SessionClose::protocol(&e);
+                    let _ = recv.stop(error_code(close.code));
+                    let _ = reply_tx.send(close.to_frame()).await;
+                    break Some(close);
+                }
+            };
+            match frame {
+                Frame::Data(payload) => {
+                    // Write payload into server TUN (uplink inject)
+                    if let Err(e) = dev.send(&payload).await {
+                        break Some(tun_write_failed(e));
+                    }
+                }
+                Frame::Offload(payload) => {
+                    if let Err(e) = dev.send_offload(&payload).await {
+                        break Some(tun_write_failed(e));
+                    }
+                }
+                Frame::Batch(payload) => {
+                    for pkt in batch::unpack(payload) {
+                        let pkt = match pkt {
+                            Ok(pkt) => pkt,
                             Err(e) => {
-                                error!("accept_bi error: {e}");
+                                warn!("dropping rest of batch: {e}");
                                 break;
                             }
+                        };
+                        if let Err(e) = dev.send(&pkt).await {
+                            break 'frames Some(tun_write_failed(e));
                         }
                     }
                 }
-                Err(e) => error!("handshake failed: {e}"),
+                Frame::Compressed(payload) => {
+                    let pkt = match uplink_plane.decompress(&mut decompressor, &payload) {
+                        Ok(pkt) => pkt,
+                        Err(e) => {
+                            warn!("dropping compressed packet: {e}");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = dev.send(&pkt).await {
+                        break Some(tun_write_failed(e));
+                    }
+                }
+                Frame::Ping(nonce) => {
+                    if reply_tx.send(Frame::Pong(nonce)).await.is_err() {
+                        break None;
+                    }
+                }
+                // Fragments and probes only travel as datagrams
+                Frame::Pong(_) | Frame::Control(_) | Frame::Fragment(_) | Frame::Probe { .. } => {}
+                Frame::Close { code, reason } => {
+                    let close = SessionClose::new(code.into(), reason);
+                    info!("client closed tunnel: {close}");
+                    break Some(close);
+                }
             }
-        });
-    }
+        }
+    });
 
-    Ok(())
+    let downlink = tokio::spawn(async move {
+        // Frames for the stream; a GSO packet may turn into several
+        let mut pending = Vec::new();
+        let close = loop {
+            // Forward packets dispatched to this session to the client
+            let closing = tokio::select! {
+                biased;
+                reply = reply_rx.recv() => match reply {
+                    Some(frame) => match frame.to_bytes() {
+                        Ok(wire) => {
+                            pending.push(wire);
+                            matches!(frame, Frame::Close { .. })
+                        }
+                        Err(e) => {
+                            error!("cannot encode frame: {e}");
+                            continue;
+                        }
+                    },
+                    None => break None,
+                },
+                pkt = downlink_rx.recv() => match pkt {
+                    Some(pkt) => match plane.send_tun_packet(pkt, vnet, &mut pending) {
+                        Ok(None) => false,
+                        Ok(Some(icmp)) => {
+                            if let Err(e) = icmp_dev.send(&icmp).await {
+                                error!("tun write error: {e}");
+                            }
+                            false
+                        }
+                        Err(e) => {
+                            error!("datagram send error: {e}");
+                            break Some(connection_closed(&e));
+                        }
+                    },
+                    None => break None,
+                },
+                () = batcher.deadline() => false,
+            };
+            if pending.is_empty() || (!closing && batcher.hold(&pending)) {
+                continue;
+            }
+            batcher.flush(&mut pending);
+            if let Err(e) = send.write_all_chunks(&mut pending).await {
+                error!("send error: {e}");
+                break write_closed(&e);
+            }
+            pending.clear();
+            if closing {
+                let _ = send.finish().await;
+                break None;
+            }
+        };
+        if batcher.enabled() {
+            info!("downlink batching: {}", batcher.stats());
+        }
+        close
+    });
+
+    let (uplink, downlink) = tokio::join!(uplink, downlink);
+    uplink
+        .ok()
+        .flatten()
+        .or(downlink.ok().flatten())
+        .unwrap_or_else(|| SessionClose::new(CloseCode::Normal, "tunnel stream ended"))
 }
 
-fn build_server_config() -> Result<ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
-    let cert = generate_simple_self_signed(["localhost".into()])?;
-    let cert_der = cert.serialize_der()?;
-    let key_der = cert.serialize_private_key_der();
+/// Log a failed write to the server TUN and close the session over it.
+#[cfg(target_os = "linux")]
+fn tun_write_failed(e: std::io::Error) -> SessionClose {
+    error!("tun write error: {e}");
+    SessionClose::new(CloseCode::Internal, "server failed to deliver a packet")
+}
 
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
-    let priv_key = rustls::PrivateKey(key_der);
+/// Inject packets the client sends as QUIC datagrams into the server TUN.
+#[cfg(target_os = "linux")]
+async fn datagram_uplink(plane: DataPlane, dev: UplinkWriter) {
+    let mut decompressor = Decompressor::new();
+    loop {
+        match plane.recv_frame().await {
+            Ok(Frame::Data(payload)) => {
+                if let Err(e) = dev.send(&payload).await {
+                    error!("tun write error: {e}");
+                    break;
+                }
+            }
+            Ok(Frame::Offload(payload)) => {
+                if let Err(e) = dev.send_offload(&payload).await {
+                    error!("tun write error: {e}");
+                    break;
+                }
+            }
+            Ok(Frame::Compressed(payload)) => match plane.decompress(&mut decompressor, &payload) {
+                Ok(pkt) => {
+                    if let Err(e) = dev.send(&pkt).await {
+                        error!("tun write error: {e}");
+                        break;
+                    }
+                }
+                Err(e) => warn!("dropping compressed datagram: {e}"),
+            },
+            Ok(other) => warn!("ignoring non-data datagram frame: {other:?}"),
+            Err(DatagramRecvError::Protocol(e)) => warn!("dropping datagram: {e}"),
+            Err(DatagramRecvError::Connection(e)) => {
+                info!("datagram receive ended: {e}");
+                break;
+            }
+        }
+    }
+}
 
-    let mut server_crypto = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, priv_key)?;
+fn build_server_config(
+    identity: &ServerIdentity,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    transport: &Arc<TransportConfig>,
+) -> Result<ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_crypto =
+        builder.with_single_cert(identity.cert_chain.clone(), identity.key.clone())?;
 
     server_crypto.alpn_protocols = vec![b"hq-29".to_vec(), b"h3".to_vec()];
 
-    // Write server certificate to disk for client trust during local testing
-    if let Err(e) = std::fs::write("server_cert.der", &cert_der) {
-        error!("failed to write server_cert.der: {e}");
-    } else {
-        let len = cert_der.len();
-        info!("wrote server_cert.der ({len} bytes)");
-    }
-
     let mut server_config = ServerConfig::with_crypto(Arc::new(server_crypto));
-    let mut transport = quinn::TransportConfig::default();
-    transport.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
-    transport.max_concurrent_bidi_streams(100u32.into());
-    server_config.transport_config(Arc::new(transport));
+    server_config.transport_config(transport.clone());
     Ok(server_config)
 }
 
-pub async fn serve_quic(addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let server_config = build_server_config()?;
+pub async fn serve_quic(
+    addr: &str,
+    tls: &TlsSettings,
+    transport: &TransportSettings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let identity = ServerIdentity::from_settings(tls)?;
+    let server_config =
+        build_server_config(&identity, None, &Arc::new(transport.transport_config()?))?;
     let addr: SocketAddr = addr.parse()?;
-    let endpoint = Endpoint::server(server_config, addr)?;
+    let endpoint = endpoint::server_endpoint(transport.endpoint_config()?, server_config, addr)?;
     let local = endpoint.local_addr()?;
     info!("QUIC server listening on {local}");
 
@@ -0,0 +1,127 @@
+//! Certificate tooling for a private deployment PKI.
+//!
+//! A deployment has one CA that issues the server certificate (with the
+//! hostnames and addresses clients connect to) and, for mutual TLS, one
+//! client certificate per device. Everything is written as PEM so the files
+//! can be fed straight back into the server and client configuration.
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType, SerialNumber,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::IpAddr;
+use time::{Duration, OffsetDateTime};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A freshly issued certificate and its private key, PEM-encoded.
+pub struct Issued {
+    pub cert_pem: String,
+    pub key_pem: String,
+    /// DER form of the certificate, for tools that want a single blob.
+    pub cert_der: Vec<u8>,
+}
+
+/// What a leaf certificate is for.
+pub enum LeafKind {
+    /// Server certificate valid for the given DNS names and IP addresses.
+    Server { sans: Vec<String> },
+    /// Client certificate for mutual TLS; the common name becomes the
+    /// session's user identity.
+    Client,
+}
+
+fn base_params(common_name: &str, days: u32) -> Result<CertificateParams, BoxError> {
+    let mut params = CertificateParams::default();
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, common_name);
+    params.distinguished_name = dn;
+    // Backdate slightly so clocks that lag a little still accept the cert
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::hours(1);
+    params.not_after = now + Duration::days(days.into());
+    let mut serial = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut serial)
+        .map_err(|_| "failed to generate serial number")?;
+    // Keep the serial positive when DER-encoded
+    serial[0] &= 0x7f;
+    params.serial_number = Some(SerialNumber::from_slice(&serial));
+    Ok(params)
+}
+
+fn finish(cert: &Certificate, signer: Option<&Certificate>) -> Result<Issued, BoxError> {
+    let cert_pem = match signer {
+        Some(ca) => cert.serialize_pem_with_signer(ca)?,
+        None => cert.serialize_pem()?,
+    };
+    let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())?
+        .pop()
+        .ok_or("rcgen produced no certificate")?;
+    Ok(Issued {
+        cert_pem,
+        key_pem: cert.serialize_private_key_pem(),
+        cert_der,
+    })
+}
+
+/// Create a self-signed CA certificate.
+pub fn generate_ca(common_name: &str, days: u32) -> Result<Issued, BoxError> {
+    let mut params = base_params(common_name, days)?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let cert = Certificate::from_params(params)?;
+    finish(&cert, None)
+}
+
+/// Issue a server or client certificate signed by the CA in `ca_cert_pem`
+/// and `ca_key_pem`.
+pub fn issue(
+    ca_cert_pem: &str,
+    ca_key_pem: &str,
+    common_name: &str,
+    kind: &LeafKind,
+    days: u32,
+) -> Result<Issued, BoxError> {
+    let ca_key = KeyPair::from_pem(ca_key_pem)?;
+    let ca = Certificate::from_params(CertificateParams::from_ca_cert_pem(ca_cert_pem, ca_key)?)?;
+
+    let mut params = base_params(common_name, days)?;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    match kind {
+        LeafKind::Server { sans } => {
+            if sans.is_empty() {
+                return Err("a server certificate needs at least one DNS name or IP".into());
+            }
+            params.subject_alt_names = sans
+                .iter()
+                .map(|san| match san.parse::<IpAddr>() {
+                    Ok(ip) => SanType::IpAddress(ip),
+                    Err(_) => SanType::DnsName(san.clone()),
+                })
+                .collect();
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        }
+        LeafKind::Client => {
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        }
+    }
+    let cert = Certificate::from_params(params)?;
+    let mut issued = finish(&cert, Some(&ca))?;
+    if let LeafKind::Server { .. } = kind {
+        // Ship the CA along with the leaf so the file is a complete chain
+        issued.cert_pem.push_str(ca_cert_pem);
+    }
+    Ok(issued)
+}
+
+/// Generate an ephemeral self-signed server certificate for `names`.
+pub fn self_signed(names: &[String]) -> Result<Issued, BoxError> {
+    let cert = rcgen::generate_simple_self_signed(names.to_vec())?;
+    finish(&cert, None)
+}
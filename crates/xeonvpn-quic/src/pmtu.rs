@@ -0,0 +1,340 @@
+//! Tunnel MTU derived from the discovered path MTU.
+//!
+//! quinn runs path MTU discovery on every connection (see
+//! [`crate::transport`]): it probes upwards with padded QUIC packets, and
+//! drops back to 1200 bytes when it detects a black hole, that is when
+//! packets of the current size keep getting lost. The largest datagram the
+//! connection can send follows that estimate, so the largest inner packet
+//! that still fits in one datagram is that minus our frame header.
+//!
+//! On datagram sessions whose peer negotiated probes we check that estimate
+//! ourselves. Every [`PROBE_INTERVAL`] a [`Frame::Probe`] padded to the size
+//! of a data frame at the current tunnel MTU goes out as a datagram, and the
+//! peer answers it with a pong. When [`MAX_PROBE_LOSSES`] probes in a row
+//! go unanswered, the path has become a black hole for that size, even if
+//! quinn has not noticed yet: the tunnel MTU is cut halfway down to
+//! [`MIN_INNER_MTU`] and probed there. After [`RAISE_INTERVAL`] probes step
+//! back up, bisecting between the tunnel MTU and the smallest size lost,
+//! and every answered probe raises the tunnel MTU to its size.
+//!
+//! [`watch_path_mtu`] samples this per connection and publishes the inner
+//! MTU whenever it changes, so callers can resize the TUN (client) or the
+//! route to the peer (server). Inner packets above it are still delivered,
+//! over the stream, instead of disappearing into a black hole.
+//!
+//! [`Frame::Probe`]: xeonvpn_core::frame::Frame::Probe
+
+use crate::tunnel::DataPlane;
+use quinn::Connection;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use xeonvpn_core::frame::HEADER_LEN;
+
+/// IPv6 requires links to carry 1280-byte packets, so the tunnel MTU never
+/// goes lower. Packets between this and what fits in a datagram on a very
+/// small path take the stream.
+pub const MIN_INNER_MTU: u16 = 1280;
+
+/// How often the connection's path MTU is sampled.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the tunnel MTU is probed while probes are answered.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Unanswered probes of one size in a row that make it a black hole. A lost
+/// probe is retried at the next sample rather than after a full interval.
+pub const MAX_PROBE_LOSSES: u32 = 3;
+
+/// How long after a black hole probes try a larger tunnel MTU again.
+pub const RAISE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Shortest wait for a probe's answer, for paths with a tiny RTT.
+const MIN_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The tunnel MTU for `conn` on its current path, at most `cap`.
+///
+/// Connections whose peer takes no datagrams give no estimate; they get
+/// `cap`.
+pub fn inner_mtu(conn: &Connection, cap: u16) -> u16 {
+    match conn.max_datagram_size() {
+        Some(max) => {
+            let fits = max.saturating_sub(HEADER_LEN).min(usize::from(u16::MAX)) as u16;
+            fits.max(MIN_INNER_MTU).min(cap)
+        }
+        None => cap,
+    }
+}
+
+/// Follow the tunnel MTU of `plane`'s connection, capped at `cap`, until
+/// the connection closes or the receiver is dropped. Once
+/// [`DataPlane::probes_enabled`], the estimate is checked with probes.
+pub fn watch_path_mtu(plane: DataPlane, cap: u16) -> watch::Receiver<u16> {
+    let conn = plane.connection().clone();
+    let (tx, rx) = watch::channel(inner_mtu(&conn, cap));
+    tokio::spawn(async move {
+        let mut black_holes = conn.stats().path.black_holes_detected;
+        let mut probes = ProbeState::new(Instant::now());
+        let mut acks = plane.probe_acks();
+        let mut nonce = 0;
+        let mut next_probe = Instant::now() + PROBE_INTERVAL;
+        let mut tick = tokio::time::interval(POLL_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = tx.closed() => break,
+                _ = conn.closed() => break,
+            }
+            let path = conn.stats().path;
+            if path.black_holes_detected > black_holes {
+                black_holes = path.black_holes_detected;
+                warn!(
+                    "path to {} dropped packets of the discovered MTU, probing again",
+                    conn.remote_address()
+                );
+            }
+            let fits = inner_mtu(&conn, cap);
+            let now = Instant::now();
+            if plane.probes_enabled() && now >= next_probe {
+                next_probe = now + PROBE_INTERVAL;
+                if let Some(size) = probes.target(fits, now) {
+                    nonce += 1;
+                    match plane.send_probe(nonce, size) {
+                        Ok(true) => {}
+                        // quinn's estimate shrank since the sample; try again next time
+                        Ok(false) => continue,
+                        Err(_) => break,
+                    }
+                    let wait = (conn.rtt() * 3).max(MIN_PROBE_TIMEOUT);
+                    let acked = tokio::time::timeout(wait, acks.wait_for(|&acked| acked >= nonce))
+                        .await
+                        .is_ok_and(|acked| acked.is_ok());
+                    if acked {
+                        if let Some(raised) = probes.on_ack(size, fits) {
+                            info!(
+                                "probe of {size} bytes to {} answered, tunnel MTU back to {raised}",
+                                conn.remote_address()
+                            );
+                        }
+                    } else {
+                        next_probe = Instant::now();
+                        if let Some(lowered) = probes.on_loss(size, fits, Instant::now()) {
+                            warn!(
+                                "{MAX_PROBE_LOSSES} probes of {size} bytes to {} lost, lowering tunnel MTU to {lowered}",
+                                conn.remote_address()
+                            );
+                        }
+                    }
+                }
+            }
+            let mtu = probes.mtu(fits);
+            tx.send_if_modified(|current| {
+                if *current == mtu {
+                    return false;
+                }
+                debug!(
+                    "tunnel MTU for {}: {current} -> {mtu} ({} probes sent, {} lost)",
+                    conn.remote_address(),
+                    path.sent_plpmtud_probes,
+                    path.lost_plpmtud_probes
+                );
+                *current = mtu;
+                true
+            });
+        }
+    });
+    rx
+}
+
+/// Our own black hole detection on top of quinn's estimate. Sizes are
+/// tunnel MTUs; the probe for one is as large as a data frame carrying a
+/// packet of that size.
+#[derive(Debug)]
+struct ProbeState {
+    /// Limit on the tunnel MTU since a black hole, if any.
+    ceiling: Option<u16>,
+    /// Smallest size above `ceiling` known to get lost.
+    too_big: Option<u16>,
+    /// The size last lost and how many times in a row.
+    losses: (u16, u32),
+    /// No probing above `ceiling` before then.
+    next_raise: Instant,
+}
+
+impl ProbeState {
+    fn new(now: Instant) -> Self {
+        Self {
+            ceiling: None,
+            too_big: None,
+            losses: (0, 0),
+            next_raise: now,
+        }
+    }
+
+    /// The tunnel MTU, given that a datagram fits `fits` bytes of packet.
+    fn mtu(&self, fits: u16) -> u16 {
+        self.ceiling.map_or(fits, |ceiling| ceiling.min(fits))
+    }
+
+    /// The size to probe next, if any: a step up while looking for a larger
+    /// tunnel MTU, the tunnel MTU otherwise.
+    fn target(&mut self, fits: u16, now: Instant) -> Option<u16> {
+        let mtu = self.mtu(fits);
+        if mtu < fits && now >= self.next_raise {
+            let candidate = match self.too_big {
+                Some(too_big) if too_big <= fits => mtu + (too_big.saturating_sub(mtu)) / 2,
+                _ => fits,
+            };
+            if candidate > mtu {
+                return Some(candidate);
+            }
+            // Nothing left between what works and what does not
+            self.too_big = None;
+            self.next_raise = now + RAISE_INTERVAL;
+        }
+        (mtu > MIN_INNER_MTU).then_some(mtu)
+    }
+
+    /// Record an answered probe. Returns the new tunnel MTU if it raised it.
+    fn on_ack(&mut self, size: u16, fits: u16) -> Option<u16> {
+        self.losses = (0, 0);
+        if size <= self.mtu(fits) {
+            return None;
+        }
+        if size >= fits {
+            self.ceiling = None;
+            self.too_big = None;
+        } else {
+            self.ceiling = Some(size);
+        }
+        Some(self.mtu(fits))
+    }
+
+    /// Record a probe that went unanswered. Returns the new tunnel MTU if
+    /// its size turned out to be a black hole.
+    fn on_loss(&mut self, size: u16, fits: u16, now: Instant) -> Option<u16> {
+        let (lost, count) = self.losses;
+        let count = if lost == size { count + 1 } else { 1 };
+        if count < MAX_PROBE_LOSSES {
+            self.losses = (size, count);
+            return None;
+        }
+        self.losses = (0, 0);
+        self.too_big = Some(size);
+        if size > self.mtu(fits) {
+            // A step up that did not work; the next one is smaller
+            return None;
+        }
+        let lowered = MIN_INNER_MTU + size.saturating_sub(MIN_INNER_MTU) / 2;
+        self.ceiling = Some(lowered);
+        self.next_raise = now + RAISE_INTERVAL;
+        Some(lowered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lose(probes: &mut ProbeState, size: u16, fits: u16, now: Instant) -> Option<u16> {
+        (0..MAX_PROBE_LOSSES)
+            .map(|_| probes.on_loss(size, fits, now))
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn probes_the_tunnel_mtu_while_it_works() {
+        let now = Instant::now();
+        let mut probes = ProbeState::new(now);
+        assert_eq!(probes.target(1406, now), Some(1406));
+        assert_eq!(probes.on_ack(1406, 1406), None);
+        assert_eq!(probes.mtu(1406), 1406);
+        // Nothing to check at the floor
+        assert_eq!(probes.target(MIN_INNER_MTU, now), None);
+    }
+
+    #[test]
+    fn repeated_losses_lower_the_mtu() {
+        let now = Instant::now();
+        let mut probes = ProbeState::new(now);
+        for _ in 1..MAX_PROBE_LOSSES {
+            assert_eq!(probes.on_loss(1406, 1406, now), None);
+            assert_eq!(probes.mtu(1406), 1406);
+        }
+        assert_eq!(probes.on_loss(1406, 1406, now), Some(1343));
+        assert_eq!(probes.mtu(1406), 1343);
+        assert_eq!(probes.target(1406, now), Some(1343));
+        // Still lost: down again, never below the floor
+        assert_eq!(lose(&mut probes, 1343, 1406, now), Some(1311));
+        assert_eq!(lose(&mut probes, 1311, 1406, now), Some(1295));
+        probes.ceiling = Some(MIN_INNER_MTU + 1);
+        assert_eq!(
+            lose(&mut probes, MIN_INNER_MTU + 1, 1406, now),
+            Some(MIN_INNER_MTU)
+        );
+        assert_eq!(probes.target(1406, now), None);
+        // quinn's own estimate still applies below the ceiling
+        probes.ceiling = Some(1343);
+        assert_eq!(probes.mtu(1300), 1300);
+    }
+
+    #[test]
+    fn an_answer_resets_the_loss_count() {
+        let now = Instant::now();
+        let mut probes = ProbeState::new(now);
+        for _ in 0..4 {
+            for _ in 1..MAX_PROBE_LOSSES {
+                assert_eq!(probes.on_loss(1406, 1406, now), None);
+            }
+            probes.on_ack(1406, 1406);
+        }
+        assert_eq!(probes.mtu(1406), 1406);
+    }
+
+    #[test]
+    fn searches_back_up_after_a_black_hole() {
+        let start = Instant::now();
+        let mut probes = ProbeState::new(start);
+        assert_eq!(lose(&mut probes, 1406, 1406, start), Some(1343));
+        // Not before the raise interval
+        assert_eq!(probes.target(1406, start), Some(1343));
+        let later = start + RAISE_INTERVAL;
+        // Bisect between what works and what was lost
+        assert_eq!(probes.target(1406, later), Some(1374));
+        assert_eq!(probes.on_ack(1374, 1406), Some(1374));
+        assert_eq!(probes.target(1406, later), Some(1390));
+        assert_eq!(lose(&mut probes, 1390, 1406, later), None);
+        assert_eq!(probes.mtu(1406), 1374);
+        assert_eq!(probes.target(1406, later), Some(1382));
+        assert_eq!(probes.on_ack(1382, 1406), Some(1382));
+        for (size, mtu) in [(1386, 1386), (1388, 1388), (1389, 1389)] {
+            assert_eq!(probes.target(1406, later), Some(size));
+            assert_eq!(probes.on_ack(size, 1406), Some(mtu));
+        }
+        // Converged: back to checking the tunnel MTU until the next raise
+        assert_eq!(probes.target(1406, later), Some(1389));
+        assert_eq!(probes.target(1406, later + RAISE_INTERVAL), Some(1406));
+        assert_eq!(probes.on_ack(1406, 1406), Some(1406));
+        assert_eq!(probes.ceiling, None);
+    }
+
+    #[test]
+    fn a_larger_estimate_stays_capped_after_a_black_hole() {
+        let now = Instant::now();
+        let mut probes = ProbeState::new(now);
+        assert_eq!(lose(&mut probes, 1350, 1350, now), Some(1315));
+        // quinn raising its estimate does not lift the ceiling
+        assert_eq!(probes.mtu(1406), 1315);
+        let later = now + RAISE_INTERVAL;
+        assert_eq!(probes.target(1406, later), Some(1332));
+        assert_eq!(probes.on_ack(1332, 1406), Some(1332));
+        // Past the size that was lost, steps go up to the new estimate
+        probes.too_big = None;
+        assert_eq!(probes.target(1406, later), Some(1406));
+        assert_eq!(probes.on_ack(1406, 1406), Some(1406));
+        assert_eq!(probes.mtu(1406), 1406);
+    }
+}
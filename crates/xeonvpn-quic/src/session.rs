@@ -0,0 +1,221 @@
+//! Server session table: maps each client's inner tunnel address to the
+//! queue feeding that client's downlink. Also the writer that takes each
+//! session's packets into the server TUN, enforcing its source addresses,
+//! routes and ACL.
+//! Both enforce the session's rate limit, if it has one.
+
+#[cfg(target_os = "linux")]
+use crate::acl::SessionAcl;
+#[cfg(target_os = "linux")]
+use ipnet::IpNet;
+#[cfg(target_os = "linux")]
+use std::io;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+#[cfg(target_os = "linux")]
+use tracing::debug;
+use xeonvpn_core::buffer::PacketBuf;
+use xeonvpn_core::ratelimit::RateLimiter;
+use xeonvpn_net::vnet::VNET_HDR_LEN;
+#[cfg(target_os = "linux")]
+use xeonvpn_net::FlowWriter;
+
+/// Packets queued per session before the dispatcher starts dropping.
+pub const DOWNLINK_QUEUE: usize = 256;
+
+/// A [`RateLimiter`] shared by the tasks forwarding one direction of a
+/// session.
+pub type SharedLimiter = Arc<Mutex<RateLimiter>>;
+
+struct SessionEntry {
+    session_id: u64,
+    tx: mpsc::Sender<PacketBuf>,
+    limit: Option<SharedLimiter>,
+}
+
+/// Outcome of handing one TUN packet to [`SessionTable::dispatch`].
+#[derive(Debug)]
+pub enum Dispatch {
+    /// Queued for the owning session.
+    Delivered,
+    /// The owning session's queue is full; the packet was dropped.
+    Congested,
+    /// The owning session is over its rate limit; the packet was dropped.
+    Limited,
+    /// No session owns the destination address; the packet is handed back
+    /// so the caller can answer it with an ICMP error.
+    NoRoute(PacketBuf),
+    /// Not a parseable IP packet.
+    Invalid,
+}
+
+/// Shared, cheaply clonable table of live sessions.
+#[derive(Clone, Default)]
+pub struct SessionTable {
+    inner: Arc<RwLock<HashMap<IpAddr, SessionEntry>>>,
+}
+
+impl SessionTable {
+    /// Register `session_id` as the owner of `addr` and return the receiver
+    /// of packets destined to it. Packets over `limit` are dropped.
+    pub fn register(
+        &self,
+        addr: IpAddr,
+        session_id: u64,
+        limit: Option<SharedLimiter>,
+    ) -> mpsc::Receiver<PacketBuf> {
+        let (tx, rx) = mpsc::channel(DOWNLINK_QUEUE);
+        let entry = SessionEntry {
+            session_id,
+            tx,
+            limit,
+        };
+        self.inner.write().unwrap().insert(addr, entry);
+        rx
+    }
+
+    /// Remove `addr` if it is still owned by `session_id`.
+    pub fn unregister(&self, addr: IpAddr, session_id: u64) {
+        let mut map = self.inner.write().unwrap();
+        if map.get(&addr).is_some_and(|e| e.session_id == session_id) {
+            map.remove(&addr);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Route one packet read from the server TUN to the session owning its
+    /// destination address; `vnet` says the packet starts with a virtio-net
+    /// header. Never blocks: a slow client loses packets rather than stalling
+    /// every other session.
+    pub fn dispatch(&self, pkt: PacketBuf, vnet: bool) -> Dispatch {
+        let ip = if vnet {
+            pkt.get(VNET_HDR_LEN..)
+        } else {
+            Some(&pkt[..])
+        };
+        let Some(ip) = ip else {
+            return Dispatch::Invalid;
+        };
+        let Some(dst) = xeonvpn_net::packet::dst_addr(ip) else {
+            return Dispatch::Invalid;
+        };
+        let len = ip.len();
+        let map = self.inner.read().unwrap();
+        let Some(entry) = map.get(&dst) else {
+            return Dispatch::NoRoute(pkt);
+        };
+        if let Some(limit) = &entry.limit {
+            if !limit.lock().unwrap().admit(len) {
+                return Dispatch::Limited;
+            }
+        }
+        match entry.tx.try_send(pkt) {
+            Ok(()) => Dispatch::Delivered,
+            Err(TrySendError::Full(_)) => Dispatch::Congested,
+            // The session is shutting down and about to unregister
+            Err(TrySendError::Closed(pkt)) => Dispatch::NoRoute(pkt),
+        }
+    }
+}
+
+/// What a session's uplink packets are checked against.
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+pub struct UplinkPolicy {
+    /// Destinations the session may reach; `None` allows all.
+    pub allowed: Option<Vec<IpNet>>,
+    pub acl: Option<Arc<SessionAcl>>,
+    pub limit: Option<SharedLimiter>,
+}
+
+/// Writes the packets a session sends into the server TUN, dropping those
+/// not from its own addresses and those its [`UplinkPolicy`] does not let
+/// through.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+pub struct UplinkWriter {
+    dev: FlowWriter,
+    /// Tunnel addresses assigned to the session.
+    sources: Arc<[IpAddr]>,
+    allowed: Option<Arc<[IpNet]>>,
+    acl: Option<Arc<SessionAcl>>,
+    limit: Option<SharedLimiter>,
+}
+
+#[cfg(target_os = "linux")]
+impl UplinkWriter {
+    /// A writer for a session assigned `addresses`.
+    pub fn new(dev: FlowWriter, addresses: &[IpNet], policy: UplinkPolicy) -> Self {
+        Self {
+            dev,
+            sources: addresses.iter().map(IpNet::addr).collect(),
+            allowed: policy.allowed.map(Arc::from),
+            acl: policy.acl,
+            limit: policy.limit,
+        }
+    }
+
+    /// The TUN itself, for packets the server makes up, such as ICMP
+    /// errors.
+    pub fn tun(&self) -> &FlowWriter {
+        &self.dev
+    }
+
+    pub async fn send(&self, pkt: &[u8]) -> io::Result<()> {
+        if !self.permits(pkt) {
+            return Ok(());
+        }
+        self.dev.send(pkt).await
+    }
+
+    /// Write a packet starting with a virtio-net header.
+    pub async fn send_offload(&self, pkt: &[u8]) -> io::Result<()> {
+        if !self.permits(pkt.get(VNET_HDR_LEN..).unwrap_or_default()) {
+            return Ok(());
+        }
+        self.dev.send_offload(pkt).await
+    }
+
+    fn permits(&self, pkt: &[u8]) -> bool {
+        // Another client's address as source would have replies and ACL
+        // logs attributed to that client
+        match xeonvpn_net::packet::src_addr(pkt) {
+            Some(src) if self.sources.contains(&src) => {}
+            Some(src) => {
+                debug!("dropping packet from {src}: not an address of the session");
+                return false;
+            }
+            None => return false,
+        }
+        if let Some(allowed) = &self.allowed {
+            match xeonvpn_net::packet::dst_addr(pkt) {
+                Some(dst) if allowed.iter().any(|net| net.contains(&dst)) => {}
+                Some(dst) => {
+                    debug!("dropping packet to {dst}: outside the routes of the session");
+                    return false;
+                }
+                None => return false,
+            }
+        }
+        if let Some(acl) = &self.acl {
+            if !acl.check(pkt) {
+                return false;
+            }
+        }
+        match &self.limit {
+            Some(limit) => limit.lock().unwrap().admit(pkt.len()),
+            None => true,
+        }
+    }
+}
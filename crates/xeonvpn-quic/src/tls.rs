@@ -0,0 +1,486 @@
+//! TLS material loading, the server identity, and client-certificate (mutual
+//! TLS) authentication.
+//!
+//! The server presents a certificate chain loaded from the configured files,
+//! so its identity survives restarts. A throwaway self-signed certificate is
+//! only generated when the configuration explicitly asks for one.
+//!
+//! With a client CA configured the server only completes TLS handshakes with
+//! clients presenting a certificate issued by that CA and not listed in the
+//! configured CRL. The certificate subject becomes the session's user
+//! identity. A SHA-256 fingerprint deny-list allows revoking a single device
+//! without issuing a new CRL. Both files are re-read when they change, without
+//! restarting the server, and live sessions whose certificate is no longer
+//! accepted are then closed with [`CloseCode::AuthFailed`].
+//!
+//! [`CloseCode::AuthFailed`]: xeonvpn_core::close::CloseCode::AuthFailed
+
+use crate::auth::AuthError;
+use crate::close::close_connection;
+use crate::config::{AuthSettings, TlsSettings};
+use quinn::Connection;
+use ring::digest;
+use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerifier, UnparsedCertRevocationList};
+use rustls::{Certificate, CertificateError, PrivateKey, RootCertStore};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+use tracing::{error, info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use xeonvpn_core::close::{CloseCode, SessionClose};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(11).any(|w| w == b"-----BEGIN ")
+}
+
+/// Read a certificate chain from a PEM file (leaf first) or a single DER
+/// certificate.
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, BoxError> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if !is_pem(&data) {
+        return Ok(vec![Certificate(data)]);
+    }
+    let certs = rustls_pemfile::certs(&mut BufReader::new(data.as_slice()))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Read a private key from a PEM file (PKCS#8, PKCS#1 or SEC1) or a DER
+/// PKCS#8 key.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, BoxError> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if !is_pem(&data) {
+        return Ok(PrivateKey(data));
+    }
+    for item in rustls_pemfile::read_all(&mut BufReader::new(data.as_slice()))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("{}: no private key found", path.display()).into())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, data);
+    let mut out = String::with_capacity(64);
+    for b in hash.as_ref() {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex.
+pub fn fingerprint(cert: &Certificate) -> String {
+    sha256_hex(&cert.0)
+}
+
+/// SHA-256 hash of a certificate's SubjectPublicKeyInfo, as lowercase hex.
+/// Unlike the certificate fingerprint it survives re-issuing a certificate
+/// for the same key, which makes it the value to pin.
+pub fn spki_fingerprint(cert: &Certificate) -> Result<String, BoxError> {
+    let (_, parsed) = X509Certificate::from_der(&cert.0)?;
+    Ok(sha256_hex(parsed.public_key().raw))
+}
+
+/// Certificate chain and key the server presents to clients.
+pub struct ServerIdentity {
+    pub cert_chain: Vec<Certificate>,
+    pub key: PrivateKey,
+}
+
+impl ServerIdentity {
+    /// Load the configured certificate and key, or generate an ephemeral
+    /// self-signed identity if the settings ask for one.
+    pub fn from_settings(settings: &TlsSettings) -> Result<Self, BoxError> {
+        match (&settings.cert_file, &settings.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert_chain = load_certs(cert_file)?;
+                let key = load_private_key(key_file)?;
+                info!(
+                    "loaded server certificate {} (sha256 {})",
+                    cert_file.display(),
+                    fingerprint(&cert_chain[0])
+                );
+                Ok(Self { cert_chain, key })
+            }
+            _ if settings.ephemeral => Self::ephemeral(),
+            _ => Err(
+                "no server certificate configured: set tls.cert_file and tls.key_file \
+                 (see `xeonvpn-server cert --help`), or opt into an ephemeral \
+                 certificate with tls.ephemeral or --ephemeral-cert"
+                    .into(),
+            ),
+        }
+    }
+
+    /// Generate a self-signed identity for local testing. The certificate is
+    /// written to `server_cert.der` so a local client can trust it.
+    pub fn ephemeral() -> Result<Self, BoxError> {
+        warn!("using an ephemeral self-signed certificate; clients must re-trust it after every restart");
+        let issued = crate::pki::self_signed(&["localhost".into()])?;
+        if let Err(e) = std::fs::write("server_cert.der", &issued.cert_der) {
+            error!("failed to write server_cert.der: {e}");
+        } else {
+            let len = issued.cert_der.len();
+            info!("wrote server_cert.der ({len} bytes)");
+        }
+        let key = rustls_pemfile::pkcs8_private_keys(&mut issued.key_pem.as_bytes())?
+            .pop()
+            .ok_or("rcgen produced no private key")?;
+        Ok(Self {
+            cert_chain: vec![Certificate(issued.cert_der)],
+            key: PrivateKey(key),
+        })
+    }
+}
+
+/// User identity taken from a verified client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    /// Common name of the subject, or the full subject DN if it has none.
+    pub name: String,
+    pub subject: String,
+    pub fingerprint: String,
+}
+
+fn mtime(path: &Option<PathBuf>) -> Option<SystemTime> {
+    std::fs::metadata(path.as_ref()?).ok()?.modified().ok()
+}
+
+/// Client-certificate authentication state shared by the accept path and
+/// the revocation reload task.
+pub struct ClientAuth {
+    ca_file: PathBuf,
+    crl_file: Option<PathBuf>,
+    deny_list_file: Option<PathBuf>,
+    denied: RwLock<HashSet<String>>,
+    stamps: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+    /// Connections of live sessions by session id.
+    live: Mutex<HashMap<u64, Connection>>,
+}
+
+impl ClientAuth {
+    /// Set up client-certificate authentication if a client CA is configured.
+    pub fn from_settings(settings: &AuthSettings) -> Result<Option<Arc<Self>>, BoxError> {
+        let Some(ca_file) = &settings.client_ca_file else {
+            return Ok(None);
+        };
+        let auth = Self {
+            ca_file: ca_file.clone(),
+            crl_file: settings.crl_file.clone(),
+            deny_list_file: settings.deny_list_file.clone(),
+            denied: RwLock::new(HashSet::new()),
+            stamps: Mutex::new((None, None)),
+            live: Mutex::new(HashMap::new()),
+        };
+        auth.reload()?;
+        Ok(Some(Arc::new(auth)))
+    }
+
+    /// Build a verifier accepting certificates issued by the client CA and
+    /// not revoked by the current CRL.
+    pub fn verifier(&self) -> Result<Arc<dyn ClientCertVerifier>, BoxError> {
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(&self.ca_file)? {
+            roots.add(&ca)?;
+        }
+        let mut verifier = AllowAnyAuthenticatedClient::new(roots);
+        if let Some(path) = &self.crl_file {
+            let data = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            let crls = if is_pem(&data) {
+                rustls_pemfile::crls(&mut BufReader::new(data.as_slice()))?
+            } else {
+                vec![data]
+            };
+            let count = crls.len();
+            verifier = verifier
+                .with_crls(crls.into_iter().map(UnparsedCertRevocationList))
+                .map_err(|e| format!("{}: invalid CRL: {e:?}", path.display()))?;
+            info!("loaded {count} CRL(s) from {}", path.display());
+        }
+        Ok(verifier.boxed())
+    }
+
+    /// Re-read the deny-list. The CRL is read by [`ClientAuth::verifier`].
+    pub fn reload(&self) -> Result<(), BoxError> {
+        let mut denied = HashSet::new();
+        if let Some(path) = &self.deny_list_file {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            for line in text.lines() {
+                let entry = line.split('#').next().unwrap_or("").trim();
+                if !entry.is_empty() {
+                    denied.insert(entry.replace(':', "").to_ascii_lowercase());
+                }
+            }
+            info!(
+                "loaded {} denied certificates from {}",
+                denied.len(),
+                path.display()
+            );
+        }
+        *self.denied.write().unwrap() = denied;
+        *self.stamps.lock().unwrap() = (mtime(&self.crl_file), mtime(&self.deny_list_file));
+        Ok(())
+    }
+
+    /// Whether the CRL or deny-list changed on disk since the last reload.
+    pub fn changed(&self) -> bool {
+        *self.stamps.lock().unwrap() != (mtime(&self.crl_file), mtime(&self.deny_list_file))
+    }
+
+    /// Identify the client of a connection whose certificate TLS has already
+    /// verified, rejecting denied certificates.
+    pub fn identify(&self, conn: &Connection) -> Result<ClientIdentity, AuthError> {
+        let chain = peer_chain(conn).ok_or(AuthError::MissingCredentials)?;
+        let leaf = chain.first().ok_or(AuthError::MissingCredentials)?;
+        let fingerprint = fingerprint(leaf);
+        if self.denied.read().unwrap().contains(&fingerprint) {
+            return Err(AuthError::Revoked);
+        }
+        let (_, cert) =
+            X509Certificate::from_der(&leaf.0).map_err(|_| AuthError::InvalidCredentials)?;
+        let subject = cert.subject().to_string();
+        let name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| subject.clone());
+        Ok(ClientIdentity {
+            name,
+            subject,
+            fingerprint,
+        })
+    }
+    /// Track a live session authenticated with a certificate, so it can be
+    /// closed if the certificate is revoked.
+    pub fn register(&self, session_id: u64, conn: Connection) {
+        self.live.lock().unwrap().insert(session_id, conn);
+    }
+
+    pub fn unregister(&self, session_id: u64) {
+        self.live.lock().unwrap().remove(&session_id);
+    }
+
+    /// Close the live sessions whose certificate `verifier`, built from the
+    /// reloaded CRL, no longer accepts or that the deny-list now names.
+    pub fn disconnect_revoked(&self, verifier: &dyn ClientCertVerifier) {
+        let now = SystemTime::now();
+        let live = self.live.lock().unwrap();
+        for (session_id, conn) in live.iter() {
+            let Some(chain) = peer_chain(conn) else {
+                continue;
+            };
+            if let Err(e) = self.recheck(&chain, verifier, now) {
+                info!("closing session {session_id}: {e}");
+                close_connection(
+                    conn,
+                    &SessionClose::new(CloseCode::AuthFailed, e.to_string()),
+                );
+            }
+        }
+    }
+
+    /// Whether a certificate chain accepted earlier would still be accepted
+    /// at `now`.
+    fn recheck(
+        &self,
+        chain: &[Certificate],
+        verifier: &dyn ClientCertVerifier,
+        now: SystemTime,
+    ) -> Result<(), AuthError> {
+        let (leaf, intermediates) = chain.split_first().ok_or(AuthError::MissingCredentials)?;
+        if self.denied.read().unwrap().contains(&fingerprint(leaf)) {
+            return Err(AuthError::Revoked);
+        }
+        match verifier.verify_client_cert(leaf, intermediates, now) {
+            Ok(_) => Ok(()),
+            Err(rustls::Error::InvalidCertificate(CertificateError::Revoked)) => {
+                Err(AuthError::Revoked)
+            }
+            Err(e) => {
+                warn!(
+                    "client certificate {} no longer verifies: {e}",
+                    fingerprint(leaf)
+                );
+                Err(AuthError::InvalidCredentials)
+            }
+        }
+    }
+}
+
+/// The certificate chain the peer of `conn` presented, leaf first.
+fn peer_chain(conn: &Connection) -> Option<Vec<Certificate>> {
+    let chain = conn.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+    Some(*chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pki::{self, Issued, LeafKind};
+    use rcgen::{
+        CertificateParams, CertificateRevocationList, CertificateRevocationListParams, KeyIdMethod,
+        KeyPair, RevokedCertParams, SerialNumber, PKCS_ECDSA_P256_SHA256,
+    };
+    use std::time::Duration;
+
+    /// A client CA with its CRL and deny-list in a temporary directory.
+    struct TempPki {
+        dir: PathBuf,
+        ca: Issued,
+    }
+
+    impl TempPki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("xeonvpn-tls-test-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let ca = pki::generate_ca("Test Client CA", 30).unwrap();
+            std::fs::write(dir.join("ca.pem"), &ca.cert_pem).unwrap();
+            let pki = Self { dir, ca };
+            pki.revoke(&[]);
+            pki.deny("");
+            pki
+        }
+
+        fn settings(&self) -> AuthSettings {
+            AuthSettings {
+                client_ca_file: Some(self.dir.join("ca.pem")),
+                crl_file: Some(self.dir.join("ca.crl")),
+                deny_list_file: Some(self.dir.join("denied.txt")),
+                ..AuthSettings::default()
+            }
+        }
+
+        fn client(&self, name: &str) -> Vec<Certificate> {
+            let issued = pki::issue(
+                &self.ca.cert_pem,
+                &self.ca.key_pem,
+                name,
+                &LeafKind::Client,
+                30,
+            )
+            .unwrap();
+            vec![Certificate(issued.cert_der)]
+        }
+
+        /// Write a CRL revoking `certs`.
+        fn revoke(&self, certs: &[&Certificate]) {
+            let key = KeyPair::from_pem(&self.ca.key_pem).unwrap();
+            let params = CertificateParams::from_ca_cert_pem(&self.ca.cert_pem, key).unwrap();
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            let now = time::OffsetDateTime::now_utc();
+            let revoked_certs = certs
+                .iter()
+                .map(|cert| {
+                    let (_, parsed) = X509Certificate::from_der(&cert.0).unwrap();
+                    RevokedCertParams {
+                        serial_number: SerialNumber::from_slice(parsed.raw_serial()),
+                        revocation_time: now,
+                        reason_code: None,
+                        invalidity_date: None,
+                    }
+                })
+                .collect();
+            let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+                this_update: now - time::Duration::hours(1),
+                next_update: now + time::Duration::days(7),
+                crl_number: SerialNumber::from(certs.len() as u64 + 1),
+                issuing_distribution_point: None,
+                revoked_certs,
+                alg: &PKCS_ECDSA_P256_SHA256,
+                key_identifier_method: KeyIdMethod::Sha256,
+            })
+            .unwrap();
+            let der = crl.serialize_der_with_signer(&ca).unwrap();
+            std::fs::write(self.dir.join("ca.crl"), der).unwrap();
+        }
+
+        fn deny(&self, text: &str) {
+            std::fs::write(self.dir.join("denied.txt"), text).unwrap();
+        }
+    }
+
+    impl Drop for TempPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn reloads_revoke_accepted_certificates() {
+        let pki = TempPki::new("revoke");
+        let auth = ClientAuth::from_settings(&pki.settings()).unwrap().unwrap();
+        let alice = pki.client("alice");
+        let bob = pki.client("bob");
+        let now = SystemTime::now();
+        let verifier = auth.verifier().unwrap();
+        assert!(auth.recheck(&alice, verifier.as_ref(), now).is_ok());
+        assert!(auth.recheck(&bob, verifier.as_ref(), now).is_ok());
+
+        // The deny-list takes fingerprints with colons in any case
+        let colons = fingerprint(&alice[0])
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap().to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        pki.deny(&format!("# lost laptop\n{colons}\n"));
+        auth.reload().unwrap();
+        assert!(matches!(
+            auth.recheck(&alice, verifier.as_ref(), now),
+            Err(AuthError::Revoked)
+        ));
+        assert!(auth.recheck(&bob, verifier.as_ref(), now).is_ok());
+
+        // A verifier built from the new CRL refuses what it revokes
+        pki.revoke(&[&bob[0]]);
+        auth.reload().unwrap();
+        let verifier = auth.verifier().unwrap();
+        assert!(matches!(
+            auth.recheck(&bob, verifier.as_ref(), now),
+            Err(AuthError::Revoked)
+        ));
+        pki.deny("");
+        auth.reload().unwrap();
+        assert!(auth.recheck(&alice, verifier.as_ref(), now).is_ok());
+    }
+
+    #[test]
+    fn rechecks_refuse_what_no_longer_verifies() {
+        let pki = TempPki::new("verify");
+        let auth = ClientAuth::from_settings(&pki.settings()).unwrap().unwrap();
+        let verifier = auth.verifier().unwrap();
+        let now = SystemTime::now();
+        let alice = pki.client("alice");
+        let later = now + Duration::from_secs(60 * 86_400);
+        assert!(matches!(
+            auth.recheck(&alice, verifier.as_ref(), later),
+            Err(AuthError::InvalidCredentials)
+        ));
+        let stranger = TempPki::new("stranger").client("alice");
+        assert!(matches!(
+            auth.recheck(&stranger, verifier.as_ref(), now),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.recheck(&[], verifier.as_ref(), now),
+            Err(AuthError::MissingCredentials)
+        ));
+    }
+}
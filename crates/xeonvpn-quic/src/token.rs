@@ -0,0 +1,477 @@
+//! Signed, expiring session tokens, an alternative to the pre-shared key.
+//!
+//! A provisioning backend hands clients short-lived tokens without touching
+//! the server config. A token is two unpadded base64url parts joined by a
+//! dot: the JSON-encoded [`TokenClaims`], and a signature over the first
+//! part (as ASCII) made with the key the claims name:
+//!
+//! ```text
+//! eyJrZXlfaWQiOiIyMDI2LTEwIiwidXNlciI6ImFsaWNlIiwiZXhwaXJlcyI6MTc5MjI0MDAwMH0.kP3x...
+//! ```
+//!
+//! The claims are JSON like
+//!
+//! ```json
+//! {"key_id": "2026-10", "user": "alice", "expires": 1792240000,
+//!  "regions": ["sg", "id"], "bandwidth_class": "standard"}
+//! ```
+//!
+//! Servers verify tokens offline against their key set (see
+//! [`TokenKeySet`]). Keys are HMAC-SHA256 secrets shared with the backend,
+//! or Ed25519 public keys whose private half only the backend holds. Several
+//! keys may be active at once, so a new key can be rolled out before the
+//! tokens signed with the old one expire.
+//!
+//! Tokens are bearer credentials: they travel inside the TLS session to a
+//! server the client has verified, and are only as safe as their lifetime is
+//! short.
+
+use crate::config::{AuthSettings, TokenKeySettings};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo; the raw key follows.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// How tokens signed with a key are verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenAlgorithm {
+    /// HMAC-SHA256 with a secret shared by the backend and every server.
+    HmacSha256,
+    /// Ed25519; servers only hold the public key.
+    Ed25519,
+}
+
+impl std::str::FromStr for TokenAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hmac-sha256" => Ok(TokenAlgorithm::HmacSha256),
+            "ed25519" => Ok(TokenAlgorithm::Ed25519),
+            other => Err(format!(
+                "unknown token algorithm {other:?}, expected hmac-sha256 or ed25519"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TokenAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TokenAlgorithm::HmacSha256 => "hmac-sha256",
+            TokenAlgorithm::Ed25519 => "ed25519",
+        })
+    }
+}
+
+/// What a token grants.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// Key the token is signed with.
+    pub key_id: String,
+    /// User the session belongs to; also used as the client identity.
+    pub user: String,
+    /// Unix time in seconds from which the token is no longer accepted.
+    pub expires: u64,
+    /// Regions whose servers accept the token; empty allows all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<String>,
+    /// Bandwidth class of the user, if the backend assigns one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_class: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// Not two base64url parts holding claims, or oversized.
+    Malformed,
+    /// The claims name a key this server does not have.
+    UnknownKey(String),
+    /// The signature does not match the claims.
+    BadSignature,
+    /// The token expired at this Unix time.
+    Expired(u64),
+    /// The token is limited to regions this server is not in.
+    WrongRegion,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::UnknownKey(id) => write!(f, "token signed with unknown key {id:?}"),
+            TokenError::BadSignature => write!(f, "invalid token signature"),
+            TokenError::Expired(at) => write!(f, "token expired at {at}"),
+            TokenError::WrongRegion => write!(f, "token not valid in this region"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// Longest token accepted, in bytes.
+const MAX_TOKEN_LEN: usize = 4096;
+
+enum VerifyKey {
+    Hmac(hmac::Key),
+    Ed25519(UnparsedPublicKey<Vec<u8>>),
+}
+
+/// The keys a server accepts tokens from, and the checks beyond the
+/// signature.
+pub struct TokenKeySet {
+    keys: HashMap<String, VerifyKey>,
+    /// Seconds a token is still accepted after it expired, for clock skew.
+    leeway_secs: u64,
+    /// Region of this server.
+    region: Option<String>,
+}
+
+impl TokenKeySet {
+    /// Load the configured token keys; `None` when there are none.
+    pub fn from_settings(
+        settings: &AuthSettings,
+        region: Option<&str>,
+    ) -> Result<Option<Self>, BoxError> {
+        if settings.token_keys.is_empty() {
+            return Ok(None);
+        }
+        let mut keys = HashMap::new();
+        for key in &settings.token_keys {
+            let loaded = load_verify_key(key)
+                .map_err(|e| format!("token key {:?} ({}): {e}", key.id, key.key_file.display()))?;
+            if keys.insert(key.id.clone(), loaded).is_some() {
+                return Err(format!("token key {:?} is configured twice", key.id).into());
+            }
+        }
+        Ok(Some(Self {
+            keys,
+            leeway_secs: settings.token_leeway_secs,
+            region: region.map(str::to_string),
+        }))
+    }
+
+    pub fn key_ids(&self) -> impl Iterator<Item = &str> {
+        self.keys.keys().map(String::as_str)
+    }
+
+    /// Check a token's signature, expiry and regions, and return its claims.
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, TokenError> {
+        if token.len() > MAX_TOKEN_LEN {
+            return Err(TokenError::Malformed);
+        }
+        let (body, sig) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let claims = URL_SAFE_NO_PAD
+            .decode(body)
+            .map_err(|_| TokenError::Malformed)?;
+        let sig = URL_SAFE_NO_PAD
+            .decode(sig)
+            .map_err(|_| TokenError::Malformed)?;
+        let claims: TokenClaims =
+            serde_json::from_slice(&claims).map_err(|_| TokenError::Malformed)?;
+        // Nothing in the claims is trusted until the signature checks out
+        let verified = match self.keys.get(&claims.key_id) {
+            Some(VerifyKey::Hmac(key)) => hmac::verify(key, body.as_bytes(), &sig).is_ok(),
+            Some(VerifyKey::Ed25519(key)) => key.verify(body.as_bytes(), &sig).is_ok(),
+            None => return Err(TokenError::UnknownKey(claims.key_id)),
+        };
+        if !verified {
+            return Err(TokenError::BadSignature);
+        }
+        if unix_now() >= claims.expires.saturating_add(self.leeway_secs) {
+            return Err(TokenError::Expired(claims.expires));
+        }
+        if !claims.regions.is_empty() {
+            match &self.region {
+                Some(region) if claims.regions.contains(region) => {}
+                _ => return Err(TokenError::WrongRegion),
+            }
+        }
+        Ok(claims)
+    }
+}
+
+fn load_verify_key(settings: &TokenKeySettings) -> Result<VerifyKey, BoxError> {
+    let raw = std::fs::read_to_string(&settings.key_file)?;
+    Ok(match settings.algorithm {
+        TokenAlgorithm::HmacSha256 => VerifyKey::Hmac(hmac_key(&raw)?),
+        TokenAlgorithm::Ed25519 => VerifyKey::Ed25519(UnparsedPublicKey::new(
+            &signature::ED25519,
+            ed25519_public_key(&raw)?,
+        )),
+    })
+}
+
+/// An HMAC key from a secret file, held to the same rules as a PSK.
+fn hmac_key(raw: &str) -> Result<hmac::Key, BoxError> {
+    let secret = crate::auth::parse_psk(raw)?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+}
+
+/// The raw Ed25519 key from a `PUBLIC KEY` PEM, as written by
+/// `openssl pkey -pubout` or [`generate_key`].
+fn ed25519_public_key(raw: &str) -> Result<Vec<u8>, BoxError> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(raw.as_bytes())
+        .map_err(|_| "expected a PEM public key")?;
+    if pem.label != "PUBLIC KEY" {
+        return Err(format!("expected a PUBLIC KEY, found {}", pem.label).into());
+    }
+    match pem.contents.strip_prefix(&ED25519_SPKI_PREFIX) {
+        Some(key) if key.len() == 32 => Ok(key.to_vec()),
+        _ => Err("not an Ed25519 public key".into()),
+    }
+}
+
+/// Signs tokens; what the provisioning backend does, also available as
+/// `xeonvpn-server token issue` for testing and small deployments.
+pub enum TokenSigner {
+    Hmac(hmac::Key),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl TokenSigner {
+    /// Load the HMAC secret or the Ed25519 private key (PKCS#8 PEM) in
+    /// `path`.
+    pub fn load(algorithm: TokenAlgorithm, path: &Path) -> Result<Self, BoxError> {
+        let raw = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        match algorithm {
+            TokenAlgorithm::HmacSha256 => Ok(TokenSigner::Hmac(hmac_key(&raw)?)),
+            TokenAlgorithm::Ed25519 => {
+                let der = rustls_pemfile::pkcs8_private_keys(&mut raw.as_bytes())?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format!("{}: no PKCS#8 private key", path.display()))?;
+                let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+                    .map_err(|e| format!("{}: not an Ed25519 key: {e}", path.display()))?;
+                Ok(TokenSigner::Ed25519(pair))
+            }
+        }
+    }
+
+    /// Encode and sign `claims`.
+    pub fn sign(&self, claims: &TokenClaims) -> String {
+        // Serializing these plain data types cannot fail.
+        let json = serde_json::to_vec(claims).expect("token claims serialize");
+        let body = URL_SAFE_NO_PAD.encode(json);
+        let sig = match self {
+            TokenSigner::Hmac(key) => URL_SAFE_NO_PAD.encode(hmac::sign(key, body.as_bytes())),
+            TokenSigner::Ed25519(pair) => URL_SAFE_NO_PAD.encode(pair.sign(body.as_bytes())),
+        };
+        format!("{body}.{sig}")
+    }
+}
+
+/// A new token key.
+pub struct GeneratedKey {
+    /// The HMAC secret, or the Ed25519 private key as PKCS#8 PEM.
+    pub secret: String,
+    /// The Ed25519 public key as PEM, for the servers' key sets.
+    pub public: Option<String>,
+}
+
+/// Generate a key for signing tokens with `algorithm`.
+pub fn generate_key(algorithm: TokenAlgorithm) -> Result<GeneratedKey, BoxError> {
+    match algorithm {
+        TokenAlgorithm::HmacSha256 => {
+            let mut secret = [0u8; 32];
+            SystemRandom::new()
+                .fill(&mut secret)
+                .map_err(|_| "random number generator failed")?;
+            let hex: String = secret.iter().map(|b| format!("{b:02x}")).collect();
+            Ok(GeneratedKey {
+                secret: hex + "\n",
+                public: None,
+            })
+        }
+        TokenAlgorithm::Ed25519 => {
+            let pair = rcgen::KeyPair::generate(&rcgen::PKCS_ED25519)?;
+            Ok(GeneratedKey {
+                secret: pair.serialize_pem(),
+                public: Some(pair.public_key_pem()),
+            })
+        }
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A signer for a new key of `algorithm`, and the key servers verify
+    /// its tokens with.
+    fn new_key(algorithm: TokenAlgorithm) -> (TokenSigner, VerifyKey) {
+        let key = generate_key(algorithm).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "xeonvpn-token-test-{}-{}",
+            std::process::id(),
+            unix_now_nanos()
+        ));
+        std::fs::write(&path, &key.secret).unwrap();
+        let signer = TokenSigner::load(algorithm, &path);
+        std::fs::remove_file(&path).unwrap();
+        let verify = match &key.public {
+            None => VerifyKey::Hmac(hmac_key(&key.secret).unwrap()),
+            Some(pem) => VerifyKey::Ed25519(UnparsedPublicKey::new(
+                &signature::ED25519,
+                ed25519_public_key(pem).unwrap(),
+            )),
+        };
+        (signer.unwrap(), verify)
+    }
+
+    fn unix_now_nanos() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    }
+
+    fn key_set(keys: Vec<(&str, VerifyKey)>, leeway_secs: u64) -> TokenKeySet {
+        TokenKeySet {
+            keys: keys
+                .into_iter()
+                .map(|(id, key)| (id.to_string(), key))
+                .collect(),
+            leeway_secs,
+            region: Some("sg".into()),
+        }
+    }
+
+    fn claims(key_id: &str, expires: u64) -> TokenClaims {
+        TokenClaims {
+            key_id: key_id.into(),
+            user: "alice".into(),
+            expires,
+            regions: Vec::new(),
+            bandwidth_class: None,
+        }
+    }
+
+    #[test]
+    fn verifies_tokens_of_both_algorithms() {
+        for algorithm in [TokenAlgorithm::HmacSha256, TokenAlgorithm::Ed25519] {
+            let (signer, key) = new_key(algorithm);
+            let keys = key_set(vec![("k1", key)], 0);
+            let mut claims = claims("k1", unix_now() + 60);
+            claims.regions = vec!["id".into(), "sg".into()];
+            claims.bandwidth_class = Some("gold".into());
+            assert_eq!(keys.verify(&signer.sign(&claims)), Ok(claims));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        for algorithm in [TokenAlgorithm::HmacSha256, TokenAlgorithm::Ed25519] {
+            let (signer, key) = new_key(algorithm);
+            let keys = key_set(vec![("k1", key)], 0);
+            let token = signer.sign(&claims("k1", unix_now() + 60));
+            let (body, sig) = token.split_once('.').unwrap();
+
+            // Claims swapped under an existing signature
+            let mut forged = claims("k1", unix_now() + 60);
+            forged.user = "mallory".into();
+            let forged_body = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+            let forged = format!("{forged_body}.{sig}");
+            assert_eq!(keys.verify(&forged), Err(TokenError::BadSignature));
+
+            let mut sig = URL_SAFE_NO_PAD.decode(sig).unwrap();
+            sig[0] ^= 1;
+            let flipped = format!("{body}.{}", URL_SAFE_NO_PAD.encode(&sig));
+            assert_eq!(keys.verify(&flipped), Err(TokenError::BadSignature));
+
+            let unsigned = format!("{body}.");
+            assert_eq!(keys.verify(&unsigned), Err(TokenError::BadSignature));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let (signer, key) = new_key(TokenAlgorithm::HmacSha256);
+        let keys = key_set(vec![("k1", key)], 0);
+        let token = signer.sign(&claims("k1", unix_now() + 60));
+        let oversized = format!("{token}{}", "A".repeat(MAX_TOKEN_LEN));
+        for token in ["", "no-dot", "!!.!!", "e30.", oversized.as_str()] {
+            assert_eq!(keys.verify(token), Err(TokenError::Malformed), "{token:?}");
+        }
+    }
+
+    #[test]
+    fn expiry_allows_leeway() {
+        let (signer, key) = new_key(TokenAlgorithm::HmacSha256);
+        let expires = unix_now() - 30;
+        let token = signer.sign(&claims("k1", expires));
+        let strict = key_set(vec![("k1", key)], 0);
+        assert_eq!(strict.verify(&token), Err(TokenError::Expired(expires)));
+        let lenient = TokenKeySet {
+            leeway_secs: 60,
+            ..strict
+        };
+        assert!(lenient.verify(&token).is_ok());
+        let token = signer.sign(&claims("k1", unix_now() - 120));
+        assert!(matches!(
+            lenient.verify(&token),
+            Err(TokenError::Expired(_))
+        ));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let (old_signer, old_key) = new_key(TokenAlgorithm::Ed25519);
+        let (current_signer, current_key) = new_key(TokenAlgorithm::HmacSha256);
+        let (retired_signer, _) = new_key(TokenAlgorithm::HmacSha256);
+        let keys = key_set(vec![("2026-09", old_key), ("2026-10", current_key)], 0);
+        let expires = unix_now() + 60;
+
+        // Tokens of both active keys are accepted during the rollover
+        assert!(keys
+            .verify(&old_signer.sign(&claims("2026-09", expires)))
+            .is_ok());
+        assert!(keys
+            .verify(&current_signer.sign(&claims("2026-10", expires)))
+            .is_ok());
+
+        // A key dropped from the set no longer verifies anything
+        let token = retired_signer.sign(&claims("2026-08", expires));
+        assert_eq!(
+            keys.verify(&token),
+            Err(TokenError::UnknownKey("2026-08".into()))
+        );
+
+        // Naming an active key does not help a token signed with another
+        let token = old_signer.sign(&claims("2026-10", expires));
+        assert_eq!(keys.verify(&token), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn regions_are_enforced() {
+        let (signer, key) = new_key(TokenAlgorithm::HmacSha256);
+        let keys = key_set(vec![("k1", key)], 0);
+        let mut claims = claims("k1", unix_now() + 60);
+        claims.regions = vec!["id".into()];
+        assert_eq!(
+            keys.verify(&signer.sign(&claims)),
+            Err(TokenError::WrongRegion)
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! QUIC transport tuning shared by client and server, read from the
+//! `[transport]` table of either config file.
+//!
+//! ```toml
+//! [transport]
+//! congestion = "bbr"          # "cubic" (default), "new_reno" or "bbr"
+//! initial_rtt_ms = 150
+//! stream_receive_window = 4194304
+//! receive_window = 16777216
+//! send_window = 16777216
+//! max_udp_payload = 1452
+//! gso = true
+//! idle_timeout_secs = 30
+//! keep_alive_secs = 5
+//! mtu_discovery = true
+//! initial_mtu = 1200
+//! mtu_upper_bound = 1452
+//! mtu_probe_interval_secs = 600
+//! auto_mtu = true
+//! clamp_mss = true
+//! batching = true
+//! batch_max_packets = 32
+//! batch_delay_us = 100
+//! compression = ["lz4", "zstd"]
+//! compression_level = 1
+//! compress_min_size = 128
+//! fragmentation = true
+//! reassembly_timeout_ms = 1000
+//! reassembly_max_bytes = 1048576
+//! ```
+//!
+//! The defaults suit a LAN. Links with a large bandwidth-delay product, such
+//! as ID or IN to SG, want windows of at least bandwidth × RTT and an initial
+//! RTT close to the real one.
+//!
+//! Path MTU discovery (DPLPMTUD, RFC 8899) probes the path with padded QUIC
+//! packets between `initial_mtu` and `mtu_upper_bound` bytes of UDP payload.
+//! With `auto_mtu` the tunnel MTU follows what it finds; see [`crate::pmtu`].
+//!
+//! `compression` lists the algorithms this side accepts, preferred first.
+//! The server picks the first algorithm on the client's list that is also
+//! on its own; an empty list on either side turns compression off.
+//!
+//! With `fragmentation`, datagram sessions cut packets too big for one
+//! datagram into fragments instead of sending them over the stream. The
+//! receiving side gives up on a packet whose fragments have not all arrived
+//! within `reassembly_timeout_ms`, and holds at most `reassembly_max_bytes`
+//! of incomplete packets per session.
+
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{EndpointConfig, IdleTimeout, MtuDiscoveryConfig, TransportConfig, VarInt};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use xeonvpn_core::compress::Compression;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Smallest UDP payload every QUIC path must carry.
+const QUIC_MIN_MTU: u16 = 1200;
+
+/// Congestion controller for the connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Congestion {
+    #[default]
+    Cubic,
+    NewReno,
+    /// Model-based; keeps throughput up on lossy long-haul paths where
+    /// loss-based controllers back off.
+    Bbr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+    pub congestion: Congestion,
+    /// RTT assumed before the first measurement, in milliseconds.
+    pub initial_rtt_ms: u64,
+    /// Bytes a peer may send on one stream before it is acknowledged.
+    /// Unset keeps quinn's default (1.25 MB).
+    pub stream_receive_window: Option<u64>,
+    /// Bytes a peer may send over all streams of the connection. Unset means
+    /// unlimited.
+    pub receive_window: Option<u64>,
+    /// Bytes this side keeps in flight over all streams. Unset keeps quinn's
+    /// default (10 MB).
+    pub send_window: Option<u64>,
+    /// Largest UDP payload this endpoint accepts, at least 1200. Unset
+    /// accepts anything.
+    pub max_udp_payload: Option<u16>,
+    /// Hand batches of packets to the kernel in one call (UDP GSO) where
+    /// the platform supports it.
+    pub gso: bool,
+    /// Close the connection after this many seconds without traffic. `0`
+    /// disables the timeout; the effective timeout is the smaller of both
+    /// peers'.
+    pub idle_timeout_secs: u64,
+    /// Send a keep-alive after this many idle seconds; `0` disables.
+    pub keep_alive_secs: u64,
+    /// Probe the path for a larger UDP payload than `initial_mtu`.
+    pub mtu_discovery: bool,
+    /// UDP payload size assumed to work from the start, at least 1200.
+    pub initial_mtu: u16,
+    /// Largest UDP payload size path MTU discovery probes for.
+    pub mtu_upper_bound: u16,
+    /// Seconds between discovery runs once one has finished. A detected
+    /// black hole restarts discovery after a minute regardless.
+    pub mtu_probe_interval_secs: u64,
+    /// Lower the tunnel MTU to what fits in one datagram on the discovered
+    /// path, and follow it as it changes.
+    pub auto_mtu: bool,
+    /// Lower the MSS of TCP SYNs entering the tunnel to fit its MTU, so
+    /// TCP never sends packets too big for the tunnel.
+    pub clamp_mss: bool,
+    /// Pack packets queued for the stream into batch frames, for peers that
+    /// unpack them.
+    pub batching: bool,
+    /// Most packets packed into one batch and written at once.
+    pub batch_max_packets: usize,
+    /// Microseconds the first packet of a batch waits for more before the
+    /// batch is written. `0` only packs packets that are already queued.
+    pub batch_delay_us: u64,
+    /// Compression algorithms accepted for tunnel packets, preferred first.
+    pub compression: Vec<Compression>,
+    /// zstd compression level; higher compresses better and slower.
+    pub compression_level: i32,
+    /// Packets smaller than this many bytes are sent uncompressed.
+    pub compress_min_size: usize,
+    /// Send packets too big for one datagram as fragments, to peers that
+    /// reassemble them.
+    pub fragmentation: bool,
+    /// Milliseconds a packet may take to arrive in full before its
+    /// fragments are dropped.
+    pub reassembly_timeout_ms: u64,
+    /// Most bytes of incomplete packets held per session.
+    pub reassembly_max_bytes: usize,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            congestion: Congestion::Cubic,
+            initial_rtt_ms: 333,
+            stream_receive_window: None,
+            receive_window: None,
+            send_window: None,
+            max_udp_payload: None,
+            gso: true,
+            idle_timeout_secs: 10,
+            keep_alive_secs: 5,
+            mtu_discovery: true,
+            initial_mtu: QUIC_MIN_MTU,
+            mtu_upper_bound: 1452,
+            mtu_probe_interval_secs: 600,
+            auto_mtu: true,
+            clamp_mss: true,
+            batching: false,
+            batch_max_packets: 32,
+            batch_delay_us: 100,
+            compression: Vec::new(),
+            compression_level: 1,
+            compress_min_size: 128,
+            fragmentation: true,
+            reassembly_timeout_ms: 1000,
+            reassembly_max_bytes: 1 << 20,
+        }
+    }
+}
+
+impl TransportSettings {
+    /// Build the quinn transport config for these settings.
+    pub fn transport_config(&self) -> Result<TransportConfig, BoxError> {
+        let mut transport = TransportConfig::default();
+        match self.congestion {
+            Congestion::Cubic => {
+                transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Congestion::NewReno => {
+                transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Congestion::Bbr => {
+                transport.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+        transport.initial_rtt(Duration::from_millis(self.initial_rtt_ms));
+        if let Some(window) = self.stream_receive_window {
+            transport.stream_receive_window(varint("stream_receive_window", window)?);
+        }
+        if let Some(window) = self.receive_window {
+            transport.receive_window(varint("receive_window", window)?);
+        }
+        if let Some(window) = self.send_window {
+            transport.send_window(window);
+        }
+        transport.enable_segmentation_offload(self.gso);
+        let idle = match self.idle_timeout_secs {
+            0 => None,
+            secs => Some(
+                IdleTimeout::try_from(Duration::from_secs(secs))
+                    .map_err(|_| format!("idle_timeout_secs {secs} is too large"))?,
+            ),
+        };
+        transport.max_idle_timeout(idle);
+        let keep_alive =
+            (self.keep_alive_secs > 0).then(|| Duration::from_secs(self.keep_alive_secs));
+        transport.keep_alive_interval(keep_alive);
+        if self.initial_mtu < QUIC_MIN_MTU {
+            return Err(format!(
+                "initial_mtu {} is below the QUIC minimum of {QUIC_MIN_MTU}",
+                self.initial_mtu
+            )
+            .into());
+        }
+        transport.initial_mtu(self.initial_mtu);
+        let discovery = self.mtu_discovery.then(|| {
+            let mut discovery = MtuDiscoveryConfig::default();
+            discovery
+                .upper_bound(self.mtu_upper_bound)
+                .interval(Duration::from_secs(self.mtu_probe_interval_secs));
+            discovery
+        });
+        transport.mtu_discovery_config(discovery);
+        Ok(transport)
+    }
+
+    /// Build the endpoint config for these settings.
+    pub fn endpoint_config(&self) -> Result<EndpointConfig, BoxError> {
+        let mut config = EndpointConfig::default();
+        if let Some(max) = self.max_udp_payload {
+            config
+                .max_udp_payload_size(max)
+                .map_err(|_| format!("max_udp_payload {max} is below the QUIC minimum of 1200"))?;
+        }
+        Ok(config)
+    }
+}
+
+fn varint(name: &str, value: u64) -> Result<VarInt, BoxError> {
+    VarInt::from_u64(value).map_err(|_| format!("{name} {value} is too large").into())
+}
@@ -0,0 +1,724 @@
+//! Tunnel data plane: carries IP packets either in unreliable QUIC DATAGRAM
+//! frames or, as a fallback, on the session's reliable bidi stream.
+//!
+//! Datagram mode is negotiated per session through the `datagrams`
+//! capability in the session handshake (see [`crate::handshake`]). Until it
+//! is enabled, and whenever a packet cannot be sent as a datagram, packets
+//! keep flowing on the stream.
+//!
+//! Packets from a TUN with offloads start with a virtio-net header. Peers
+//! that negotiated the `offload` capability get them as they are, in
+//! [`Frame::Offload`] frames; for anyone else they are finished and
+//! segmented into plain packets first.
+//!
+//! Packets from the TUN larger than the session's tunnel MTU (see
+//! [`crate::pmtu`]) that must not be fragmented are not sent: the sender
+//! gets an ICMP "fragmentation needed" / "packet too big" instead, and TCP
+//! SYNs can have their MSS clamped so such packets are rare to begin with.
+//!
+//! Frames for the stream are gathered by a [`StreamBatcher`], which packs
+//! queued packets into batch frames for peers that unpack them and writes
+//! them at once.
+//!
+//! Packets too big for one datagram are cut into fragments for peers that
+//! reassemble them (see [`xeonvpn_core::fragment`]), and go over the stream
+//! otherwise. Offload frames are never fragmented: losing one fragment would
+//! lose a whole super-segment.
+//!
+//! Datagram sessions whose peer negotiated probes also carry path MTU
+//! probes (see [`crate::pmtu`]): a [`Frame::Probe`] is answered with a
+//! [`Frame::Pong`] datagram, and the pongs that arrive are handed to the
+//! prober rather than to the caller.
+//!
+//! Sessions that negotiated compression (see [`xeonvpn_core::compress`])
+//! send plain packets that shrink as compressed frames, on either path.
+//! Small packets and packets that are most likely encrypted already are not
+//! tried; neither are offload frames, and compressed frames are not batched.
+
+use crate::transport::TransportSettings;
+use bytes::Bytes;
+use quinn::{Connection, ConnectionError, SendDatagramError};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::{fmt, io, time::Duration};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use xeonvpn_core::batch;
+use xeonvpn_core::buffer::PacketBuf;
+use xeonvpn_core::compress::{Compression, Compressor, Decompressor};
+use xeonvpn_core::fragment::{self, Reassembler, ReassemblyStats};
+use xeonvpn_core::frame::{Frame, ProtocolError, HEADER_LEN, MAX_PAYLOAD};
+use xeonvpn_net::packet::{self, IcmpError};
+use xeonvpn_net::vnet::{finish_checksum, segment, VnetHdr, VNET_HDR_LEN};
+
+/// Per-session packet sender shared by the tasks of one connection.
+#[derive(Clone)]
+pub struct DataPlane {
+    conn: Connection,
+    datagrams: Arc<AtomicBool>,
+    offload: Arc<AtomicBool>,
+    mtu: Arc<AtomicU16>,
+    clamp_mss: Arc<AtomicBool>,
+    compression: Arc<Mutex<Option<PacketCompressor>>>,
+    compression_counters: Arc<CompressionCounters>,
+    fragments: Arc<AtomicBool>,
+    fragment_ids: Arc<AtomicU32>,
+    fragment_counters: Arc<FragmentCounters>,
+    reassembly: Arc<Mutex<Option<PacketReassembler>>>,
+    probes: Arc<AtomicBool>,
+    probe_acks: Arc<watch::Sender<u64>>,
+}
+
+/// The sending side of a session's compression.
+struct PacketCompressor {
+    compressor: Compressor,
+    min_size: usize,
+    reported: Instant,
+}
+
+/// The receiving side of a session's fragmentation.
+struct PacketReassembler {
+    reassembler: Reassembler,
+    reported: Instant,
+}
+
+/// How often a [`DataPlane`] compressing or reassembling packets logs its
+/// stats at debug level.
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl DataPlane {
+    /// Create a data plane that starts out on the stream path.
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            datagrams: Arc::new(AtomicBool::new(false)),
+            offload: Arc::new(AtomicBool::new(false)),
+            mtu: Arc::new(AtomicU16::new(u16::MAX)),
+            clamp_mss: Arc::new(AtomicBool::new(false)),
+            compression: Arc::new(Mutex::new(None)),
+            compression_counters: Arc::new(CompressionCounters::default()),
+            fragments: Arc::new(AtomicBool::new(false)),
+            fragment_ids: Arc::new(AtomicU32::new(0)),
+            fragment_counters: Arc::new(FragmentCounters::default()),
+            reassembly: Arc::new(Mutex::new(None)),
+            probes: Arc::new(AtomicBool::new(false)),
+            probe_acks: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    /// Whether this side of the connection can carry datagrams at all.
+    pub fn datagrams_available(&self) -> bool {
+        self.conn.max_datagram_size().is_some()
+    }
+
+    /// Switch to datagram mode if the connection allows it; returns whether
+    /// datagram mode is now active.
+    pub fn enable_datagrams(&self) -> bool {
+        let ok = self.datagrams_available();
+        self.datagrams.store(ok, Ordering::Relaxed);
+        ok
+    }
+
+    pub fn datagrams_enabled(&self) -> bool {
+        self.datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Send packets with virtio-net headers to the peer as they are, once
+    /// both sides negotiated offloads.
+    pub fn enable_offload(&self) {
+        self.offload.store(true, Ordering::Relaxed);
+    }
+
+    /// Set the largest inner packet the tunnel carries. Until set, any size
+    /// is sent.
+    pub fn set_mtu(&self, mtu: u16) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Rewrite the MSS of TCP SYNs from the TUN to fit the tunnel MTU.
+    pub fn enable_mss_clamp(&self) {
+        self.clamp_mss.store(true, Ordering::Relaxed);
+    }
+
+    /// Reassemble fragments the peer sends within the limits of `settings`,
+    /// and cut packets too big for a datagram into fragments when
+    /// `settings` allow it and the peer reassembles them.
+    pub fn enable_fragments(&self, settings: &TransportSettings, peer_reassembles: bool) {
+        *self.reassembly.lock().unwrap() = Some(PacketReassembler {
+            reassembler: Reassembler::new(
+                Duration::from_millis(settings.reassembly_timeout_ms),
+                settings.reassembly_max_bytes,
+            ),
+            reported: Instant::now(),
+        });
+        self.fragments.store(
+            settings.fragmentation && peer_reassembles,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn fragments_enabled(&self) -> bool {
+        self.fragments.load(Ordering::Relaxed)
+    }
+
+    /// Let the path MTU prober send probes, once the peer negotiated them.
+    /// They only go out while datagram mode is active.
+    pub fn enable_probes(&self) {
+        self.probes.store(true, Ordering::Relaxed);
+    }
+
+    pub fn probes_enabled(&self) -> bool {
+        self.probes.load(Ordering::Relaxed) && self.datagrams_enabled()
+    }
+
+    pub(crate) fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Send a probe datagram the size of a data frame carrying an `mtu`-byte
+    /// packet. Returns whether it was sent.
+    pub(crate) fn send_probe(&self, nonce: u64, mtu: u16) -> Result<bool, ConnectionError> {
+        let probe = Frame::Probe {
+            nonce,
+            padding: usize::from(mtu).saturating_sub(8),
+        };
+        match probe.to_bytes() {
+            Ok(datagram) => self.send_datagram(datagram),
+            Err(e) => {
+                warn!("cannot encode probe: {e}");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Follow the highest probe nonce the peer answered.
+    pub(crate) fn probe_acks(&self) -> watch::Receiver<u64> {
+        self.probe_acks.subscribe()
+    }
+
+    /// Fragmentation counters of this session so far, for both directions.
+    pub fn fragment_stats(&self) -> FragmentStats {
+        let reassembly = match &*self.reassembly.lock().unwrap() {
+            Some(state) => state.reassembler.stats(),
+            None => ReassemblyStats::default(),
+        };
+        FragmentStats {
+            fragmented: self.fragment_counters.fragmented.load(Ordering::Relaxed),
+            sent: self.fragment_counters.sent.load(Ordering::Relaxed),
+            reassembly,
+        }
+    }
+
+    /// Compress plain packets of at least `min_size` bytes with `algorithm`
+    /// before sending them; `level` only applies to zstd.
+    pub fn enable_compression(
+        &self,
+        algorithm: Compression,
+        level: i32,
+        min_size: usize,
+    ) -> io::Result<()> {
+        *self.compression.lock().unwrap() = Some(PacketCompressor {
+            compressor: Compressor::new(algorithm, level)?,
+            min_size,
+            reported: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Compression counters of this session so far, for both directions.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression_counters.snapshot()
+    }
+
+    /// Restore a packet from a [`Frame::Compressed`] payload and count it.
+    pub fn decompress(
+        &self,
+        decompressor: &mut Decompressor,
+        payload: &[u8],
+    ) -> Result<Bytes, ProtocolError> {
+        let pkt = decompressor.decompress(payload)?;
+        let counters = &self.compression_counters;
+        counters.received.fetch_add(1, Ordering::Relaxed);
+        let saved = pkt.len().saturating_sub(payload.len()) as u64;
+        counters.received_saved.fetch_add(saved, Ordering::Relaxed);
+        Ok(pkt)
+    }
+
+    /// Send one packet read from a TUN. With `vnet` set the packet starts
+    /// with a virtio-net header: it is forwarded as is to a peer with
+    /// offloads, and finished or segmented into plain packets otherwise.
+    ///
+    /// Encoded frames that have to go over the stream are appended to
+    /// `stream`, in order. Returns the ICMP error to write back into the TUN
+    /// when the packet is too big for the tunnel.
+    pub fn send_tun_packet(
+        &self,
+        mut pkt: PacketBuf,
+        vnet: bool,
+        stream: &mut Vec<Bytes>,
+    ) -> Result<Option<Vec<u8>>, ConnectionError> {
+        if !vnet {
+            return self.send_fitted(pkt, stream);
+        }
+        let Some(hdr) = VnetHdr::parse(&pkt) else {
+            debug!("dropping TUN packet without virtio-net header");
+            return Ok(None);
+        };
+        if !hdr.is_gso() {
+            // A single packet: finish its checksum in place and strip the header
+            if finish_checksum(&hdr, &mut pkt[VNET_HDR_LEN..]).is_none() {
+                debug!("dropping TUN packet with bad checksum offsets");
+                return Ok(None);
+            }
+            pkt.advance(VNET_HDR_LEN);
+            return self.send_fitted(pkt, stream);
+        }
+        // Segments are cut to the MSS, which the kernel took from the TUN MTU
+        if self.offload.load(Ordering::Relaxed) && pkt.len() <= MAX_PAYLOAD {
+            stream.extend(self.send_frame(pkt.into_offload_frame(), false)?);
+            return Ok(None);
+        }
+        let mut result = Ok(());
+        let segmented = segment(&hdr, &pkt[VNET_HDR_LEN..], |seg| {
+            if result.is_err() {
+                return;
+            }
+            let frame = match self.compress(seg) {
+                Some(frame) => Ok(frame),
+                None => Frame::Data(Bytes::copy_from_slice(seg)).to_bytes(),
+            };
+            match frame {
+                Ok(frame) => result = self.send_frame(frame, true).map(|f| stream.extend(f)),
+                Err(e) => debug!("dropping segment: {e}"),
+            }
+        });
+        if segmented.is_none() {
+            debug!("dropping malformed GSO packet from TUN");
+        }
+        result.map(|()| None)
+    }
+
+    /// Clamp and send one plain packet, unless it exceeds the tunnel MTU and
+    /// must not be fragmented.
+    ///
+    /// The ICMP error appears to come from the packet's destination: the
+    /// kernel drops packets from the TUN claiming one of its own addresses,
+    /// and reverse path filtering accepts the destination on the tunnel.
+    fn send_fitted(
+        &self,
+        mut pkt: PacketBuf,
+        stream: &mut Vec<Bytes>,
+    ) -> Result<Option<Vec<u8>>, ConnectionError> {
+        let mtu = self.mtu.load(Ordering::Relaxed);
+        if self.clamp_mss.load(Ordering::Relaxed) {
+            packet::clamp_mss(&mut pkt, mtu);
+        }
+        if pkt.len() > usize::from(mtu) && packet::dont_fragment(&pkt) {
+            debug!("{}-byte packet exceeds tunnel MTU {mtu}", pkt.len());
+            let from = packet::dst_addr(&pkt);
+            return Ok(
+                from.and_then(|from| packet::icmp_error(&pkt, from, IcmpError::TooBig { mtu }))
+            );
+        }
+        stream.extend(self.send_packet(pkt)?);
+        Ok(None)
+    }
+
+    /// Send one IP packet as a datagram when datagram mode is active. The
+    /// frame header is written into the packet's headroom, so the packet is
+    /// never copied.
+    ///
+    /// Returns `Ok(Some(frame))` with the encoded frame when it has to go
+    /// over the stream instead, either because datagram mode is off or
+    /// because this packet does not fit. Peers that turn out not to support
+    /// datagrams switch the session back to the stream path for good.
+    ///
+    /// With compression enabled the packet goes out compressed when that
+    /// makes it smaller, and is then copied once.
+    pub fn send_packet(&self, pkt: PacketBuf) -> Result<Option<Bytes>, ConnectionError> {
+        match self.compress(&pkt) {
+            Some(frame) => self.send_frame(frame, true),
+            None => self.send_frame(pkt.into_data_frame(), true),
+        }
+    }
+
+    /// Encode `pkt` as a compressed frame if compression is enabled, the
+    /// packet is worth trying and it shrinks.
+    fn compress(&self, pkt: &[u8]) -> Option<Bytes> {
+        let mut compression = self.compression.lock().unwrap();
+        let state = compression.as_mut()?;
+        let counters = &self.compression_counters;
+        let frame = if pkt.len() < state.min_size || packet::likely_encrypted(pkt) {
+            None
+        } else {
+            state.compressor.compress(pkt)
+        };
+        match &frame {
+            Some(frame) => {
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+                let saved = (pkt.len() + HEADER_LEN).saturating_sub(frame.len()) as u64;
+                counters.sent_saved.fetch_add(saved, Ordering::Relaxed);
+            }
+            None => {
+                counters.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if state.reported.elapsed() >= STATS_REPORT_INTERVAL {
+            state.reported = Instant::now();
+            debug!(
+                "{} compression: {}",
+                state.compressor.algorithm(),
+                counters.snapshot()
+            );
+        }
+        frame
+    }
+
+    /// Send an encoded frame as a datagram, or as fragments if it is too
+    /// big, `may_fragment` and fragments are enabled. Returns the frame
+    /// when it has to go over the stream instead.
+    fn send_frame(
+        &self,
+        frame: Bytes,
+        may_fragment: bool,
+    ) -> Result<Option<Bytes>, ConnectionError> {
+        if !self.datagrams_enabled() {
+            return Ok(Some(frame));
+        }
+        let max = match self.conn.max_datagram_size() {
+            Some(max) => max,
+            None => {
+                warn!("path can no longer carry datagrams, falling back to stream");
+                self.datagrams.store(false, Ordering::Relaxed);
+                return Ok(Some(frame));
+            }
+        };
+        if frame.len() <= max {
+            // Cloning only bumps a reference count
+            return Ok((!self.send_datagram(frame.clone())?).then_some(frame));
+        }
+        if !may_fragment || !self.fragments_enabled() {
+            return Ok(Some(frame));
+        }
+        let id = self.fragment_ids.fetch_add(1, Ordering::Relaxed);
+        let Some(pieces) = fragment::split(&frame, id, max) else {
+            return Ok(Some(frame));
+        };
+        let counters = &self.fragment_counters;
+        counters.fragmented.fetch_add(1, Ordering::Relaxed);
+        for piece in pieces {
+            if !self.send_datagram(piece)? {
+                // The peer drops the pieces already sent once they time out
+                return Ok(Some(frame));
+            }
+            counters.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(None)
+    }
+
+    /// Send one datagram. Returns whether it was sent; if not, the caller
+    /// falls back to the stream.
+    fn send_datagram(&self, datagram: Bytes) -> Result<bool, ConnectionError> {
+        match self.conn.send_datagram(datagram) {
+            Ok(()) => Ok(true),
+            Err(SendDatagramError::TooLarge) => Ok(false),
+            Err(e @ (SendDatagramError::UnsupportedByPeer | SendDatagramError::Disabled)) => {
+                warn!("{e}, falling back to stream");
+                self.datagrams.store(false, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(SendDatagramError::ConnectionLost(e)) => Err(e),
+        }
+    }
+
+    /// Wait for the next datagram and decode the frame it carries. Fragments
+    /// are collected until the frame they make up is complete, and probes
+    /// and their answers are dealt with here.
+    pub async fn recv_frame(&self) -> Result<Frame, DatagramRecvError> {
+        loop {
+            let data = self
+                .conn
+                .read_datagram()
+                .await
+                .map_err(DatagramRecvError::Connection)?;
+            let frame = match decode_one(&data).map_err(DatagramRecvError::Protocol)? {
+                Frame::Fragment(payload) => match self
+                    .reassemble(payload)
+                    .map_err(DatagramRecvError::Protocol)?
+                {
+                    Some(whole) => match decode_one(&whole).map_err(DatagramRecvError::Protocol)? {
+                        Frame::Fragment(_) => {
+                            return Err(DatagramRecvError::Protocol(ProtocolError::Malformed(
+                                "fragment inside a fragment",
+                            )))
+                        }
+                        frame => frame,
+                    },
+                    None => continue,
+                },
+                frame => frame,
+            };
+            match frame {
+                Frame::Probe { nonce, .. } => {
+                    let pong = Frame::Pong(nonce)
+                        .to_bytes()
+                        .map_err(DatagramRecvError::Protocol)?;
+                    // A pong that cannot be sent is a lost probe to the peer
+                    self.send_datagram(pong)
+                        .map_err(DatagramRecvError::Connection)?;
+                }
+                Frame::Pong(nonce) => {
+                    self.probe_acks.send_if_modified(|acked| {
+                        let newer = nonce > *acked;
+                        if newer {
+                            *acked = nonce;
+                        }
+                        newer
+                    });
+                }
+                frame => return Ok(frame),
+            }
+        }
+    }
+}
+
+impl DataPlane {
+    /// Add a fragment to its frame. Returns the encoded frame once it is
+    /// complete.
+    fn reassemble(&self, payload: Bytes) -> Result<Option<Bytes>, ProtocolError> {
+        let mut reassembly = self.reassembly.lock().unwrap();
+        let Some(state) = reassembly.as_mut() else {
+            return Err(ProtocolError::Malformed("fragment before negotiation"));
+        };
+        let whole = state.reassembler.push(payload);
+        if state.reported.elapsed() >= STATS_REPORT_INTERVAL {
+            state.reported = Instant::now();
+            drop(reassembly);
+            debug!("fragmentation: {}", self.fragment_stats());
+        }
+        whole
+    }
+}
+
+/// Decode a buffer holding exactly one frame.
+fn decode_one(data: &Bytes) -> Result<Frame, ProtocolError> {
+    match Frame::decode_bytes(data)? {
+        Some((frame, used)) if used == data.len() => Ok(frame),
+        _ => Err(ProtocolError::Malformed(
+            "datagram does not hold exactly one frame",
+        )),
+    }
+}
+
+/// Fragmentation counters of the sending side, shared by the clones of a
+/// [`DataPlane`].
+#[derive(Default)]
+struct FragmentCounters {
+    fragmented: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// Fragmentation counters of a [`DataPlane`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FragmentStats {
+    /// Frames sent as fragments.
+    pub fragmented: u64,
+    /// Fragments sent.
+    pub sent: u64,
+    /// Fragments received and what became of them.
+    pub reassembly: ReassemblyStats,
+}
+
+impl fmt::Display for FragmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} frames in {} fragments; {}",
+            self.fragmented, self.sent, self.reassembly
+        )
+    }
+}
+
+/// Holds the frames queued for a session's stream until enough have
+/// gathered or the first has waited long enough, then packs them into batch
+/// frames for a single write.
+///
+/// The writer task queues frames, asks [`StreamBatcher::hold`] whether to
+/// wait for more, and selects on [`StreamBatcher::deadline`] while it does.
+pub struct StreamBatcher {
+    max_packets: usize,
+    delay: Duration,
+    flush_at: Option<Instant>,
+    stats: BatchStats,
+    reported: Instant,
+}
+
+/// How often a [`StreamBatcher`] logs its stats at debug level.
+const BATCH_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+impl StreamBatcher {
+    /// A batcher following `settings`, which writes every frame right away
+    /// unless batching is configured and the peer unpacks batches.
+    pub fn new(settings: &TransportSettings, peer_unpacks: bool) -> Self {
+        let enabled = settings.batching && peer_unpacks;
+        Self {
+            max_packets: if enabled {
+                settings.batch_max_packets
+            } else {
+                1
+            },
+            delay: Duration::from_micros(settings.batch_delay_us),
+            flush_at: None,
+            stats: BatchStats::default(),
+            reported: Instant::now(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_packets > 1
+    }
+
+    /// Whether to hold back `pending` for more frames. The first call after
+    /// a flush starts the deadline.
+    pub fn hold(&mut self, pending: &[Bytes]) -> bool {
+        if !self.enabled() || pending.len() >= self.max_packets {
+            return false;
+        }
+        let now = Instant::now();
+        match self.flush_at {
+            None => {
+                self.flush_at = Some(now + self.delay);
+                true
+            }
+            Some(at) => at > now,
+        }
+    }
+
+    /// Completes when held frames are due; never while none are held.
+    /// Select on it after the frame sources, with `biased;`, so frames that
+    /// are already queued still join the batch at a zero delay.
+    pub async fn deadline(&self) {
+        match self.flush_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Pack `pending` for one write and count it.
+    pub fn flush(&mut self, pending: &mut Vec<Bytes>) {
+        self.flush_at = None;
+        self.stats.writes += 1;
+        self.stats.queued += pending.len() as u64;
+        batch::coalesce(pending, self.max_packets);
+        self.stats.frames += pending.len() as u64;
+        if self.enabled() && self.reported.elapsed() >= BATCH_REPORT_INTERVAL {
+            self.reported = Instant::now();
+            debug!("stream batching: {}", self.stats);
+        }
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        self.stats
+    }
+}
+
+/// Counters of a [`StreamBatcher`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BatchStats {
+    /// Writes to the stream.
+    pub writes: u64,
+    /// Frames queued: packets, plus the odd pong or close.
+    pub queued: u64,
+    /// Frames written, a batch counting once.
+    pub frames: u64,
+}
+
+impl BatchStats {
+    /// Queued frames per write.
+    pub fn batch_factor(&self) -> f64 {
+        self.queued as f64 / self.writes.max(1) as f64
+    }
+}
+
+impl fmt::Display for BatchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames queued, {} written in {} writes (batch factor {:.1})",
+            self.queued,
+            self.frames,
+            self.writes,
+            self.batch_factor()
+        )
+    }
+}
+
+/// Live compression counters shared by the clones of a [`DataPlane`].
+#[derive(Default)]
+struct CompressionCounters {
+    sent: AtomicU64,
+    skipped: AtomicU64,
+    sent_saved: AtomicU64,
+    received: AtomicU64,
+    received_saved: AtomicU64,
+}
+
+impl CompressionCounters {
+    fn snapshot(&self) -> CompressionStats {
+        CompressionStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            sent_saved: self.sent_saved.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            received_saved: self.received_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Compression counters of a [`DataPlane`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompressionStats {
+    /// Packets sent compressed.
+    pub sent: u64,
+    /// Packets sent as they are: too small, likely encrypted, or not
+    /// shrinking enough.
+    pub skipped: u64,
+    /// Bytes compression kept off the wire for packets sent.
+    pub sent_saved: u64,
+    /// Compressed packets received.
+    pub received: u64,
+    /// Bytes compression kept off the wire for packets received.
+    pub received_saved: u64,
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} packets compressed and {} as they are, saving {} bytes; \
+             received {} compressed, saving {} bytes",
+            self.sent, self.skipped, self.sent_saved, self.received, self.received_saved
+        )
+    }
+}
+
+/// Errors returned by [`DataPlane::recv_frame`].
+#[derive(Debug)]
+pub enum DatagramRecvError {
+    Connection(ConnectionError),
+    Protocol(ProtocolError),
+}
+
+impl std::fmt::Display for DatagramRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatagramRecvError::Connection(e) => write!(f, "datagram receive failed: {e}"),
+            DatagramRecvError::Protocol(e) => write!(f, "bad datagram: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DatagramRecvError {}
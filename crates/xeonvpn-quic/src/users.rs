@@ -0,0 +1,446 @@
+//! Server-side user database, read from a TOML file and reloaded when it
+//! changes.
+//!
+//! ```toml
+//! [users.alice]
+//! psk = "alice-0123456789abcdef"         # her own pre-shared key
+//! address = "10.123.0.10"                # static tunnel address
+//! routes = ["192.168.50.0/24"]           # pushed to her and all she may reach
+//! bandwidth_class = "gold"
+//! groups = ["contractors"]               # ACL groups, see crate::acl
+//! expires = 2026-12-31                   # or 2026-12-31T18:00:00+07:00
+//!
+//! [users."bob-laptop"]
+//! enabled = false
+//! ```
+//!
+//! With a user database configured, every session needs an entry for its
+//! identity that is enabled and has not expired. The identity must be
+//! proven: a certificate or token naming the user, or a client id whose
+//! entry has a `psk` the client proved. Users without a `psk` can therefore
+//! only connect with a certificate or token. Live sessions of a user that
+//! gets disabled, removed or expires are closed with
+//! [`CloseCode::AuthFailed`].
+
+use crate::close::close_connection;
+use ipnet::IpNet;
+use quinn::Connection;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+use toml::value::{Datetime, Offset};
+use tracing::info;
+use xeonvpn_core::close::{CloseCode, SessionClose};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFile {
+    #[serde(default)]
+    users: BTreeMap<String, UserEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    #[serde(default = "enabled")]
+    enabled: bool,
+    psk: Option<String>,
+    address: Option<Ipv4Addr>,
+    #[serde(default)]
+    routes: Vec<IpNet>,
+    bandwidth_class: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    expires: Option<Datetime>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// One user of the database.
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    /// Pre-shared key the user proves instead of the server-wide one.
+    pub psk: Option<Vec<u8>>,
+    /// Tunnel address the user always gets.
+    pub address: Option<Ipv4Addr>,
+    /// Routes pushed to the user instead of the server's, and the only
+    /// destinations outside the tunnel subnet the user may reach. Empty
+    /// keeps the server's routes and allows everything.
+    pub routes: Vec<IpNet>,
+    pub bandwidth_class: Option<String>,
+    /// ACL groups the user is in.
+    pub groups: Vec<String>,
+    /// Unix time from which the user is refused.
+    pub expires: Option<u64>,
+}
+
+impl User {
+    /// Whether the user may have sessions at Unix time `now`.
+    pub fn check(&self, now: u64) -> Result<(), UserError> {
+        if !self.enabled {
+            return Err(UserError::Disabled);
+        }
+        match self.expires {
+            Some(expires) if now >= expires => Err(UserError::Expired),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a user may not have sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    Unknown,
+    /// The session claims the user without proving it.
+    Unauthenticated,
+    Disabled,
+    Expired,
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::Unknown => write!(f, "unknown user"),
+            UserError::Unauthenticated => write!(f, "user not authenticated"),
+            UserError::Disabled => write!(f, "user disabled"),
+            UserError::Expired => write!(f, "user expired"),
+        }
+    }
+}
+
+impl std::error::Error for UserError {}
+
+impl From<UserError> for SessionClose {
+    fn from(e: UserError) -> Self {
+        SessionClose::new(CloseCode::AuthFailed, e.to_string())
+    }
+}
+
+/// The user database and the live sessions of its users.
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<HashMap<String, Arc<User>>>,
+    stamp: Mutex<Option<SystemTime>>,
+    /// Connections of live sessions by user and session id.
+    live: Mutex<HashMap<String, HashMap<u64, Connection>>>,
+}
+
+impl UserStore {
+    /// Load the user database in `path`.
+    pub fn open(path: &Path) -> Result<Self, BoxError> {
+        let store = Self {
+            path: path.to_path_buf(),
+            users: RwLock::new(HashMap::new()),
+            stamp: Mutex::new(None),
+            live: Mutex::new(HashMap::new()),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Re-read the database. On error the previous users stay in effect.
+    pub fn reload(&self) -> Result<(), BoxError> {
+        let stamp = mtime(&self.path);
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("{}: {e}", self.path.display()))?;
+        let users = parse(&text).map_err(|e| format!("{}: {e}", self.path.display()))?;
+        info!("loaded {} users from {}", users.len(), self.path.display());
+        *self.users.write().unwrap() = users;
+        *self.stamp.lock().unwrap() = stamp;
+        Ok(())
+    }
+
+    /// Whether the database changed on disk since the last reload.
+    pub fn changed(&self) -> bool {
+        *self.stamp.lock().unwrap() != mtime(&self.path)
+    }
+
+    /// Users without a pre-shared key of their own, by name.
+    pub fn without_psk(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        let mut names: Vec<_> = users
+            .values()
+            .filter(|user| user.psk.is_none())
+            .map(|user| user.name.clone())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<User>> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    /// The user `name` if it may have sessions now. `verified` says the
+    /// session proved it is `name`; sessions that did not are refused
+    /// before the database is consulted, so they cannot tell which users
+    /// exist.
+    pub fn admit(&self, name: &str, verified: bool) -> Result<Arc<User>, UserError> {
+        if !verified {
+            return Err(UserError::Unauthenticated);
+        }
+        let user = self.get(name).ok_or(UserError::Unknown)?;
+        user.check(crate::token::unix_now())?;
+        Ok(user)
+    }
+
+    /// Static tunnel addresses by user.
+    pub fn reservations(&self) -> HashMap<String, Ipv4Addr> {
+        let users = self.users.read().unwrap();
+        users
+            .values()
+            .filter_map(|user| Some((user.name.clone(), user.address?)))
+            .collect()
+    }
+
+    /// Track a live session of `name`, so it can be closed if the user is
+    /// disabled.
+    pub fn register(&self, name: &str, session_id: u64, conn: Connection) {
+        let mut live = self.live.lock().unwrap();
+        live.entry(name.to_string())
+            .or_default()
+            .insert(session_id, conn);
+    }
+
+    pub fn unregister(&self, name: &str, session_id: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(sessions) = live.get_mut(name) {
+            sessions.remove(&session_id);
+            if sessions.is_empty() {
+                live.remove(name);
+            }
+        }
+    }
+
+    /// Close the live sessions of users that may no longer have any.
+    pub fn disconnect_refused(&self) {
+        let now = crate::token::unix_now();
+        let live = self.live.lock().unwrap();
+        for (name, sessions) in live.iter() {
+            let refused = match self.get(name) {
+                Some(user) => user.check(now),
+                None => Err(UserError::Unknown),
+            };
+            let Err(e) = refused else {
+                continue;
+            };
+            info!("closing {} session(s) of {name}: {e}", sessions.len());
+            for conn in sessions.values() {
+                close_connection(conn, &e.into());
+            }
+        }
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+fn parse(text: &str) -> Result<HashMap<String, Arc<User>>, BoxError> {
+    let file: UserFile = toml::from_str(text)?;
+    let mut addresses = HashMap::new();
+    let mut users = HashMap::new();
+    for (name, entry) in file.users {
+        if let Some(addr) = entry.address {
+            if let Some(other) = addresses.insert(addr, name.clone()) {
+                return Err(format!("{other} and {name} both have address {addr}").into());
+            }
+        }
+        let psk = match &entry.psk {
+            Some(psk) => Some(crate::auth::parse_psk(psk).map_err(|e| format!("{name}: {e}"))?),
+            None => None,
+        };
+        let expires = match &entry.expires {
+            Some(at) => Some(unix_time(at).map_err(|e| format!("{name}: expires: {e}"))?),
+            None => None,
+        };
+        let user = User {
+            name: name.clone(),
+            enabled: entry.enabled,
+            psk,
+            address: entry.address,
+            routes: entry.routes,
+            bandwidth_class: entry.bandwidth_class,
+            groups: entry.groups,
+            expires,
+        };
+        users.insert(name, Arc::new(user));
+    }
+    Ok(users)
+}
+
+/// Unix time of a TOML date or date-time. A date stands for its start and
+/// a date-time without offset for UTC.
+fn unix_time(at: &Datetime) -> Result<u64, BoxError> {
+    let date = at.date.ok_or("a date is required")?;
+    let month = time::Month::try_from(date.month)?;
+    let mut when = time::Date::from_calendar_date(i32::from(date.year), month, date.day)?
+        .midnight()
+        .assume_utc();
+    if let Some(t) = at.time {
+        when = when.replace_time(time::Time::from_hms(t.hour, t.minute, t.second)?);
+    }
+    if let Some(Offset::Custom { minutes }) = at.offset {
+        when -= time::Duration::minutes(i64::from(minutes));
+    }
+    u64::try_from(when.unix_timestamp()).map_err(|_| "before 1970".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERS: &str = r#"
+        [users.alice]
+        psk = "alice-0123456789abcdef"
+        address = "10.123.0.10"
+        routes = ["192.168.50.0/24"]
+        bandwidth_class = "gold"
+        groups = ["contractors"]
+        expires = 2999-12-31
+
+        [users.bob]
+        enabled = false
+
+        [users.carol]
+        expires = 2020-01-01T00:00:00Z
+    "#;
+
+    /// A store over a temporary copy of `text`.
+    struct TempStore {
+        store: UserStore,
+        path: PathBuf,
+    }
+
+    impl TempStore {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "xeonvpn-users-test-{}-{name}.toml",
+                std::process::id()
+            ));
+            std::fs::write(&path, text).unwrap();
+            let store = UserStore::open(&path).unwrap();
+            Self { store, path }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn parses_entries() {
+        let users = parse(USERS).unwrap();
+        let alice = &users["alice"];
+        assert!(alice.enabled);
+        assert_eq!(alice.psk.as_deref(), Some(&b"alice-0123456789abcdef"[..]));
+        assert_eq!(alice.address, Some(Ipv4Addr::new(10, 123, 0, 10)));
+        assert_eq!(alice.routes, ["192.168.50.0/24".parse::<IpNet>().unwrap()]);
+        assert_eq!(alice.bandwidth_class.as_deref(), Some("gold"));
+        assert_eq!(alice.groups, ["contractors"]);
+        let bob = &users["bob"];
+        assert!(!bob.enabled);
+        assert_eq!(bob.psk, None);
+        assert_eq!(bob.expires, None);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for text in [
+            "[users.a]\npsk = \"short\"",
+            "[users.a]\nshell = \"/bin/sh\"",
+            "[users.a]\nexpires = 12:00:00",
+            "[users.a]\naddress = \"10.0.0.1\"\n[users.b]\naddress = \"10.0.0.1\"",
+        ] {
+            assert!(parse(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn converts_expiry_times() {
+        let at = |text: &str| {
+            let value: toml::Value = toml::from_str(&format!("at = {text}")).unwrap();
+            unix_time(value["at"].as_datetime().unwrap()).unwrap()
+        };
+        assert_eq!(at("2026-10-17"), 1_792_195_200);
+        assert_eq!(at("2026-10-17T12:30:00"), 1_792_195_200 + 45_000);
+        assert_eq!(at("2026-10-17T12:30:00Z"), 1_792_195_200 + 45_000);
+        assert_eq!(at("2026-10-17T19:30:00+07:00"), 1_792_195_200 + 45_000);
+    }
+
+    #[test]
+    fn checks_disabled_and_expired_users() {
+        let users = parse(USERS).unwrap();
+        let now = crate::token::unix_now();
+        assert_eq!(users["alice"].check(now), Ok(()));
+        assert_eq!(users["bob"].check(now), Err(UserError::Disabled));
+        assert_eq!(users["carol"].check(now), Err(UserError::Expired));
+        let carol_expires = users["carol"].expires.unwrap();
+        assert_eq!(users["carol"].check(carol_expires - 1), Ok(()));
+        assert_eq!(users["carol"].check(carol_expires), Err(UserError::Expired));
+    }
+
+    #[test]
+    fn admits_only_proven_allowed_users() {
+        let store = TempStore::new("admit", USERS);
+        let store = &store.store;
+        assert_eq!(store.admit("alice", true).unwrap().name, "alice");
+        assert_eq!(store.admit("bob", true).unwrap_err(), UserError::Disabled);
+        assert_eq!(store.admit("carol", true).unwrap_err(), UserError::Expired);
+        assert_eq!(
+            store.admit("mallory", true).unwrap_err(),
+            UserError::Unknown
+        );
+    }
+
+    #[test]
+    fn unproven_sessions_cannot_tell_users_apart() {
+        let store = TempStore::new("unproven", USERS);
+        let reasons: Vec<_> = ["alice", "bob", "carol", "mallory"]
+            .into_iter()
+            .map(|name| {
+                let e = store.store.admit(name, false).unwrap_err();
+                SessionClose::from(e).reason
+            })
+            .collect();
+        assert!(
+            reasons.iter().all(|r| *r == "user not authenticated"),
+            "{reasons:?}"
+        );
+    }
+
+    #[test]
+    fn reload_keeps_users_on_error() {
+        let temp = TempStore::new("reload", USERS);
+        let store = &temp.store;
+        assert_eq!(store.without_psk(), ["bob", "carol"]);
+        assert_eq!(
+            store.reservations(),
+            HashMap::from([("alice".to_string(), Ipv4Addr::new(10, 123, 0, 10))])
+        );
+
+        std::fs::write(&temp.path, "[users.dave]\npsk = \"dave-0123456789abcdef\"").unwrap();
+        store.reload().unwrap();
+        assert!(store.get("alice").is_none());
+        assert!(store.get("dave").is_some());
+
+        std::fs::write(&temp.path, "[users.erin\n").unwrap();
+        assert!(store.reload().is_err());
+        assert!(store.get("dave").is_some());
+    }
+}